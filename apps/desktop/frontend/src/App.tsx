@@ -2,8 +2,7 @@
 import Workspace from "./components/Workspace";
 import Sidebar from "./components/Sidebar";
 import "./app.css";
-
-const API = "http://127.0.0.1:3030";
+import { apiFetch } from "./api";
 
 export type Note = {
   id: string;
@@ -14,6 +13,8 @@ export type Note = {
   plaintext?: string;
   tags: string[];
   preview_path?: string | null;
+  // Bumped by every save; /update is refused with 409 when it's not the one the editor started from.
+  version: number;
 };
 
 export default function App() {
@@ -26,21 +27,38 @@ export default function App() {
     fetchNotes();
   }, []);
 
+  // Records the open for `sort=opened`; GET /note can't, since every note is fetched above.
+  useEffect(() => {
+    if (!activeNoteId) return;
+    apiFetch(`/open/${activeNoteId}`, { method: "POST" })
+      .catch((e) => console.error("Failed to mark note opened:", e));
+  }, [activeNoteId]);
+
   const fetchNotes = async () => {
     try {
-      const res = await fetch(`${API}/notes`);
-      const data = await res.json();
+      // /notes pages by cursor (200 at most per page); follow it to the end so no note is left out.
+      const data: any[] = [];
+      let cursor: string | null = null;
+      do {
+        const query = new URLSearchParams({ limit: "200" });
+        if (cursor) query.set("cursor", cursor);
+        const res = await apiFetch(`/notes?${query}`);
+        const page = await res.json();
+        data.push(...page.items);
+        cursor = page.next_cursor ?? null;
+      } while (cursor);
       
       // Cargar detalles completos de cada nota incluyendo HTML
       const notesWithContent = await Promise.all(
         data.map(async (note: any) => {
-          const detailRes = await fetch(`${API}/note/${note.id}`);
+          const detailRes = await apiFetch(`/note/${note.id}`);
           const detail = await detailRes.json();
           return {
             ...note,
             content: detail.html || "",
             html: detail.html,
-            plaintext: detail.plaintext
+            plaintext: detail.plaintext,
+            version: detail.version
           };
         })
       );
@@ -67,7 +85,7 @@ export default function App() {
         ops: { tags: ["study"] }
       };
       
-      const res = await fetch(`${API}/clip`, {
+      const res = await apiFetch(`/clip`, {
         method: "POST",
         headers: { "Content-Type": "application/json" },
         body: JSON.stringify(payload)
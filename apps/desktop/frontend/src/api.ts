@@ -0,0 +1,43 @@
+import { useEffect, useState } from "react";
+
+// Base URL and token for the local LevelNotes API. The desktop shell injects both: the port it
+// actually bound (it falls back to another one when 3030 is busy) and the per-install token.
+// A plain browser dev session can set `levelnotes.token` in localStorage instead.
+declare global {
+  interface Window { __LEVELNOTES_API__?: string; __LEVELNOTES_TOKEN__?: string }
+}
+
+export const API: string = window.__LEVELNOTES_API__ ?? "http://127.0.0.1:3030";
+const TOKEN: string = window.__LEVELNOTES_TOKEN__ ?? localStorage.getItem("levelnotes.token") ?? "";
+
+export function apiFetch(path: string, init: RequestInit = {}): Promise<Response> {
+  const headers = new Headers(init.headers);
+  if (TOKEN) headers.set("Authorization", `Bearer ${TOKEN}`);
+  return fetch(`${API}${path}`, { ...init, headers });
+}
+
+// An object URL for a stored file, for <img src> and other places that can't send the token
+// themselves; the file is fetched with the header, so the token never ends up in a URL. With
+// `size`, an image comes as a thumbnail at least that many pixels on its longer side, where there
+// is one. `null` until it has loaded, and if it can't be.
+export function useFileUrl(relPath: string | null | undefined, size?: number): string | null {
+  const [url, setUrl] = useState<string | null>(null);
+  useEffect(() => {
+    if (!relPath) { setUrl(null); return; }
+    let objectUrl: string | null = null;
+    let cancelled = false;
+    apiFetch(`/file/${relPath}${size ? `?size=${size}` : ""}`)
+      .then((res) => { if (!res.ok) throw new Error(`HTTP ${res.status}`); return res.blob(); })
+      .then((blob) => {
+        if (cancelled) return;
+        objectUrl = URL.createObjectURL(blob);
+        setUrl(objectUrl);
+      })
+      .catch((e) => { if (!cancelled) { console.error(`Failed to load ${relPath}:`, e); setUrl(null); } });
+    return () => {
+      cancelled = true;
+      if (objectUrl) URL.revokeObjectURL(objectUrl);
+    };
+  }, [relPath, size]);
+  return url;
+}
@@ -1,4 +1,5 @@
 ﻿import React from "react";
+import { useFileUrl } from "../api";
 
 export type NoteListItem = {
   id: string;
@@ -17,6 +18,8 @@ type Props = {
 };
 
 export function NoteCard({ item, onOpen, isSelected }: Props) {
+  const previewUrl = useFileUrl(item.preview_path, 320);
+
   const formatDate = (dateStr: string) => {
     const date = new Date(dateStr);
     const now = new Date();
@@ -37,10 +40,10 @@ export function NoteCard({ item, onOpen, isSelected }: Props) {
       className={`note-card ${isSelected ? "note-card-selected" : ""}`}
       onClick={() => onOpen(item.id)}
     >
-      {item.preview_path ? (
+      {previewUrl ? (
         <img 
           className="note-thumbnail" 
-          src={`http://127.0.0.1:3030/file/${item.preview_path}`} 
+          src={previewUrl} 
           alt="" 
         />
       ) : (
@@ -1,6 +1,6 @@
 ﻿import React, { useEffect, useState, useRef, useMemo, useCallback } from "react";
 
-import { useEditor, EditorContent } from "@tiptap/react";
+import { useEditor, EditorContent, Editor } from "@tiptap/react";
 
 import StarterKit from "@tiptap/starter-kit";
 
@@ -29,7 +29,7 @@ import { EditorView } from "prosemirror-view";
 
 import { Note } from "../App";
 
-const API = "http://127.0.0.1:3030";
+import { apiFetch } from "../api";
 
 const PAPER_STYLES = {
 
@@ -415,43 +415,115 @@ export default function NoteEditor({ note, onUpdate }: Props) {
 
   const lastSyncedHtmlRef = useRef<string | null>(null);
 
-  const saveNote = useCallback(async (htmlContent: string, plainContent: string) => {
+  // The note's version and HTML on the server as of the last read or save. /update refuses a save
+
+  // made against an older version with 409, e.g. after a clip was appended from the extension.
+
+  const versionRef = useRef<number>(note.version);
+
+  const serverHtmlRef = useRef<string>(note.content);
+
+  useEffect(() => {
+
+    versionRef.current = note.version;
+
+    serverHtmlRef.current = note.content;
+
+  }, [note.id, note.version]);
+
+  const saveNote = useCallback(async (editor: Editor, htmlContent: string, plainContent: string) => {
+
+    const post = (html: string, plaintext: string) => apiFetch(`/update/${note.id}`, {
+
+      method: "POST",
+
+      headers: { "Content-Type": "application/json" },
+
+      body: JSON.stringify({
+
+        title: note.title,
+
+        tags: note.tags,
+
+        html,
+
+        plaintext,
+
+        expectedVersion: versionRef.current
+
+      })
+
+    });
 
     try {
 
-      const response = await fetch(`${API}/update/${note.id}`, {
+      let html = htmlContent;
+
+      let response = await post(html, plainContent);
+
+      if (response.status === 409) {
+
+        const { current } = await response.json();
+
+        const base = serverHtmlRef.current;
+
+        const serverHtml: string = current.html || "";
+
+        versionRef.current = current.version;
+
+        serverHtmlRef.current = serverHtml;
+
+        if (!base || !serverHtml.startsWith(base)) {
+
+          // Edited elsewhere too: show that version rather than overwrite it.
 
-        method: "POST",
+          const reloaded = ensurePagedContent(serverHtml);
 
-        headers: { "Content-Type": "application/json" },
+          editor.commands.setContent(reloaded, { emitUpdate: false });
 
-        body: JSON.stringify({
+          lastSyncedHtmlRef.current = reloaded;
 
-          title: note.title,
+          onUpdate();
 
-          tags: note.tags,
+          return;
 
-          html: htmlContent,
+        }
+
+        // Only added to since (an appended clip): keep these edits and add what came in after them.
+
+        const merged = ensurePagedContent(htmlContent + serverHtml.slice(base.length));
+
+        editor.commands.setContent(merged, { emitUpdate: false });
+
+        html = editor.getHTML();
 
-          plaintext: plainContent
+        lastSyncedHtmlRef.current = html;
 
-        })
+        response = await post(html, editor.getText());
 
-      });
+      }
 
       if (!response.ok) {
 
         console.error("Failed to save note:", response.status);
 
+        return;
+
       }
 
+      const saved = await response.json();
+
+      versionRef.current = saved.version;
+
+      serverHtmlRef.current = html;
+
     } catch (e) {
 
       console.error("Failed to save:", e);
 
     }
 
-  }, [note.id, note.tags, note.title]);
+  }, [note.id, note.tags, note.title, onUpdate]);
 
   const initialContent = useMemo(() => ensurePagedContent(note.content), [note.id]);
 
@@ -548,7 +620,7 @@ PageLayout,
 
       saveTimeoutRef.current = setTimeout(() => {
 
-        saveNote(html, plainText);
+        saveNote(editor, html, plainText);
 
       }, 1000);
 
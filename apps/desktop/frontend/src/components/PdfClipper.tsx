@@ -1,12 +1,11 @@
 ﻿import React, { useEffect, useRef, useState, useCallback } from "react";
 import * as pdfjsLib from "pdfjs-dist";
 import { TextLayerBuilder } from "pdfjs-dist/web/pdf_viewer";
+import { apiFetch } from "../api";
 
 // Configurar worker
 pdfjsLib.GlobalWorkerOptions.workerSrc = `//cdnjs.cloudflare.com/ajax/libs/pdf.js/2.16.105/pdf.worker.min.js`;
 
-const API = "http://127.0.0.1:3030";
-
 type Props = {
   onClose: () => void;
   onClipped: () => void;
@@ -21,6 +20,8 @@ export default function PdfClipper({ onClose, onClipped, targetNoteId = null, ta
   const [page, setPage] = useState<number>(1);
   const [scale, setScale] = useState<number>(1.5);
   const [fileName, setFileName] = useState<string>("");
+  // The id POST /documents gave the open PDF, so notes can reopen it at the clipped page.
+  const [documentId, setDocumentId] = useState<string | null>(null);
   const [loading, setLoading] = useState<boolean>(false);
 
   const canvasRef = useRef<HTMLCanvasElement | null>(null);
@@ -33,6 +34,7 @@ export default function PdfClipper({ onClose, onClipped, targetNoteId = null, ta
     
     setLoading(true);
     setFileName(f.name);
+    setDocumentId(null);
     
     try {
       const buf = await f.arrayBuffer();
@@ -40,6 +42,16 @@ export default function PdfClipper({ onClose, onClipped, targetNoteId = null, ta
       setPdfDoc(doc);
       setNumPages(doc.numPages || 0);
       setPage(1);
+      // Clips still work without it; they just can't point back into the file.
+      try {
+        const form = new FormData();
+        form.append("file", f, f.name);
+        const res = await apiFetch(`/documents`, { method: "POST", body: form });
+        if (!res.ok) throw new Error(`HTTP ${res.status}`);
+        setDocumentId((await res.json()).document.id);
+      } catch (err) {
+        console.error("Error storing PDF:", err);
+      }
     } catch (err) {
       console.error("Error loading PDF:", err);
       alert("Error loading PDF file");
@@ -106,6 +118,17 @@ export default function PdfClipper({ onClose, onClipped, targetNoteId = null, ta
     return selectedText;
   };
 
+  // The selection's rectangles on the page, in PDF units (scale 1) from its top-left corner.
+  const getHighlights = () => {
+    const selection = window.getSelection();
+    const canvas = canvasRef.current;
+    if (!selection || selection.rangeCount === 0 || !canvas) return [];
+    const origin = canvas.getBoundingClientRect();
+    return Array.from(selection.getRangeAt(0).getClientRects())
+      .filter((r) => r.width > 0 && r.height > 0)
+      .map((r) => ({ x: (r.left - origin.left) / scale, y: (r.top - origin.top) / scale, w: r.width / scale, h: r.height / scale }));
+  };
+
   const addSelection = async () => {
     const selectedText = getSelectedText();
     
@@ -115,30 +138,33 @@ export default function PdfClipper({ onClose, onClipped, targetNoteId = null, ta
     }
     
     const finalHtml = `<p>${selectedText.replace(/\n\n/g, "</p><p>").replace(/\n/g, "<br>")}</p>`;
+    const source = { kind: "pdf", url: fileName, ...(documentId ? { documentId } : {}) };
+    const ops = { tags: ["pdf"], page, highlights: getHighlights() };
+    // The page as rendered, kept with the note whether the clip starts one or is appended to it.
+    const screenshotDataUrl = canvasRef.current?.toDataURL("image/png") || null;
     
     try {
       if (mode === "append" && targetNoteId) {
-        const payload = { 
-          plaintext: selectedText,
-          html: finalHtml, 
-          tags: ["pdf"],
-          page
+        const payload = {
+          source,
+          selection: { text: selectedText, html: finalHtml },
+          media: { screenshotDataUrl },
+          ops
         };
-        const res = await fetch(`${API}/append/${targetNoteId}`, {
+        const res = await apiFetch(`/append/${targetNoteId}`, {
           method: "POST",
           headers: { "Content-Type": "application/json" },
           body: JSON.stringify(payload),
         });
         if (!res.ok) throw new Error(`HTTP ${res.status}`);
       } else {
-        const screenshotDataUrl = canvasRef.current?.toDataURL("image/png") || null;
         const payload = {
-          source: { kind: "pdf", url: fileName },
+          source,
           selection: { text: selectedText, html: finalHtml },
           media: { screenshotDataUrl },
-          ops: { tags: ["pdf"], page }
+          ops
         };
-        const res = await fetch(`${API}/clip`, {
+        const res = await apiFetch(`/clip`, {
           method: "POST",
           headers: { "Content-Type": "application/json" },
           body: JSON.stringify(payload),
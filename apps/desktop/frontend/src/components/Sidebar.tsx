@@ -1,5 +1,6 @@
 ﻿import React, { useState } from "react";
 import { Note } from "../App";
+import { apiFetch } from "../api";
 
 type Props = {
   notes: Note[];
@@ -25,6 +26,17 @@ export default function Sidebar({
     note.tags.some(tag => tag.toLowerCase().includes(searchQuery.toLowerCase()))
   );
 
+  const pairExtension = async () => {
+    try {
+      const res = await apiFetch(`/pair/start`, { method: "POST" });
+      if (!res.ok) throw new Error(`HTTP ${res.status}`);
+      const data = await res.json();
+      alert(`Enter this code in the LevelNotes Clipper extension options within ${Math.round(data.expires_in / 60)} minutes:\n\n${data.code}`);
+    } catch (e) {
+      console.error("Failed to start pairing:", e);
+    }
+  };
+
   const formatDate = (dateStr: string) => {
     const date = new Date(dateStr);
     const now = new Date();
@@ -77,6 +89,13 @@ export default function Sidebar({
               <span>➕</span>
               <span>New Note</span>
             </button>
+            <button
+              className="btn-pair-extension"
+              onClick={pairExtension}
+              title="Connect the browser extension to this app"
+            >
+              🔗 Pair browser extension
+            </button>
           </div>
         </>
       )}
@@ -0,0 +1,55 @@
+// Shared by the service worker and the options page. The token comes from pairing with the
+// desktop app and is kept in extension-local storage, never exposed to web pages.
+
+// The desktop API listens on 3030, or on one of the next 10 ports when that one is busy.
+const DEFAULT_PORT = 3030;
+const PORT_FALLBACK_TRIES = 10;
+
+async function isLevelNotes(base: string): Promise<boolean> {
+  try {
+    const r = await fetch(`${base}/health`, { signal: AbortSignal.timeout(1000) });
+    return r.ok && (await r.json()).service === "levelnotes";
+  } catch {
+    return false;
+  }
+}
+
+// Where the API is: the address it was last found at if it still answers, else the first port of
+// its range that does. Remembered in extension-local storage.
+export async function apiBase(): Promise<string> {
+  const { api } = await chrome.storage.local.get("api");
+  if (api && await isLevelNotes(api)) return api;
+  const bases = Array.from({ length: PORT_FALLBACK_TRIES + 1 }, (_, i) => `http://127.0.0.1:${DEFAULT_PORT + i}`);
+  const found = bases[(await Promise.all(bases.map(isLevelNotes))).indexOf(true)];
+  if (!found) throw new Error(`LevelNotes is not running (looked on ports ${DEFAULT_PORT}-${DEFAULT_PORT + PORT_FALLBACK_TRIES}).`);
+  await chrome.storage.local.set({ api: found });
+  return found;
+}
+
+export async function getToken(): Promise<string | undefined> {
+  const { token } = await chrome.storage.local.get("token");
+  return token;
+}
+
+export async function pair(code: string): Promise<void> {
+  const r = await fetch(`${await apiBase()}/pair/complete`, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ code, name: "LevelNotes Clipper" })
+  });
+  const body = await r.json().catch(() => ({}));
+  if (!r.ok) throw new Error(body.message || "HTTP " + r.status);
+  await chrome.storage.local.set({ token: body.token });
+}
+
+export async function postClip(payload: unknown): Promise<Response> {
+  const token = await getToken();
+  if (!token) throw new Error("Not paired: open the extension options and enter the code from LevelNotes.");
+  const r = await fetch(`${await apiBase()}/clip`, {
+    method: "POST",
+    headers: { "Content-Type": "application/json", "Authorization": `Bearer ${token}` },
+    body: JSON.stringify(payload)
+  });
+  if (!r.ok) throw new Error("HTTP " + r.status);
+  return r;
+}
@@ -1,42 +1,21 @@
-﻿const API = "http://127.0.0.1:3030/clip";
-
-function getSelectionScript() {
-  return () => {
-    function selectionHtml() {
-      const sel = window.getSelection();
-      if (!sel || sel.rangeCount === 0) return "";
-      const range = sel.getRangeAt(0).cloneContents();
-      const div = document.createElement("div");
-      div.appendChild(range);
-      return div.innerHTML;
-    }
-    const sel = window.getSelection();
-    const text = sel ? sel.toString() : "";
-    const html = selectionHtml();
-    return { text, html, url: location.href, title: document.title };
-  };
-}
+﻿import { postClip } from "./api";
+import { readSelection } from "./selection";
 
 async function clipFromActiveTab(tabId?: number) {
   const [tab] = await chrome.tabs.query({ active: true, lastFocusedWindow: true });
   if (!tab?.id) return;
   const [res] = await chrome.scripting.executeScript({
     target: { tabId: tab.id },
-    func: getSelectionScript()
+    func: readSelection
   });
-  const data = res?.result || { text: "", html: "", url: tab.url || "", title: tab.title || "" };
+  const data = res?.result || { text: "", html: "", url: tab.url || "", title: tab.title || "", anchors: undefined };
   const payload = {
     source: { kind: "web", url: data.url },
-    selection: { text: data.text || data.title || data.url, html: data.html || "" },
+    selection: { text: data.text || data.title || data.url, html: data.html || "", anchors: data.anchors },
     ops: { summarize: false, tags: [] }
   };
   try {
-    const r = await fetch(API, {
-      method: "POST",
-      headers: { "Content-Type": "application/json" },
-      body: JSON.stringify(payload)
-    });
-    if (!r.ok) throw new Error("HTTP " + r.status);
+    await postClip(payload);
     // feedback simple en badge
     if (tab.id) {
       await chrome.action.setBadgeText({ tabId: tab.id, text: "✓" });
@@ -68,3 +47,12 @@ chrome.contextMenus.onClicked.addListener((info, tab) => {
 chrome.commands.onCommand.addListener((cmd) => {
   if (cmd === "send-selection") clipFromActiveTab();
 });
+
+// Content scripts run with the page's origin, so they hand clips to the worker, which holds the token.
+chrome.runtime.onMessage.addListener((msg, _sender, sendResponse) => {
+  if (msg?.type !== "levelnotes-clip") return;
+  postClip(msg.payload)
+    .then(() => sendResponse({ ok: true }))
+    .catch((e) => sendResponse({ ok: false, error: String(e) }));
+  return true;
+});
@@ -1,28 +1,15 @@
-﻿const API = "http://127.0.0.1:3030/clip";
-
-function selectionHtml(): string {
-  const sel = window.getSelection();
-  if (!sel || sel.rangeCount === 0) return "";
-  const range = sel.getRangeAt(0).cloneContents();
-  const div = document.createElement("div");
-  div.appendChild(range);
-  return div.innerHTML;
-}
+﻿import { readSelection } from "./selection";
 
 async function send() {
-  const text = window.getSelection()?.toString() || document.title || location.href;
-  const html = selectionHtml();
+  const { text, html, anchors } = readSelection();
   const payload = {
     source: { kind: "web", url: location.href },
-    selection: { text, html },
+    selection: { text: text || document.title || location.href, html, anchors },
     ops: { summarize: false, tags: [] }
   };
-  try {
-    await fetch(API, { method: "POST", headers: { "Content-Type": "application/json" }, body: JSON.stringify(payload) });
-    console.log("LevelNotes: clip sent.");
-  } catch (e) {
-    console.warn("LevelNotes: backend not reachable.", e);
-  }
+  const res = await chrome.runtime.sendMessage({ type: "levelnotes-clip", payload });
+  if (res?.ok) console.log("LevelNotes: clip sent.");
+  else console.warn("LevelNotes: clip failed.", res?.error);
 }
 
 document.addEventListener("keydown", (ev) => {
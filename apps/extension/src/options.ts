@@ -0,0 +1,21 @@
+import { getToken, pair } from "./api";
+
+const status = document.getElementById("status")!;
+const input = document.getElementById("code") as HTMLInputElement;
+
+async function refresh() {
+  status.textContent = (await getToken()) ? "Paired with LevelNotes." : "Not paired yet.";
+}
+
+document.getElementById("pair")!.addEventListener("click", async () => {
+  try {
+    await pair(input.value);
+    input.value = "";
+  } catch (e) {
+    status.textContent = `Pairing failed: ${(e as Error).message}`;
+    return;
+  }
+  refresh();
+});
+
+refresh();
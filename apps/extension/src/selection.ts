@@ -0,0 +1,49 @@
+// What the page's selection is and where it sits, as the clip payload's `selection` wants it.
+// Self-contained, because the service worker runs it in the tab through chrome.scripting, which
+// sends the function's source alone.
+export function readSelection() {
+  // Characters of context kept on either side of a quote.
+  const QUOTE_CONTEXT = 32;
+
+  // `/html/body/div[2]/p[1]`: each step's index counts the same-named siblings before it.
+  function xpathOf(el: Element): string {
+    const steps: string[] = [];
+    for (let node: Element | null = el; node; node = node.parentElement) {
+      let index = 1;
+      for (let sib = node.previousElementSibling; sib; sib = sib.previousElementSibling) {
+        if (sib.localName === node.localName) index++;
+      }
+      steps.unshift(`${node.localName}[${index}]`);
+    }
+    return "/" + steps.join("/");
+  }
+
+  // The text from the start of `container` up to a boundary point, however deep it is.
+  function textBefore(container: Node, node: Node, offset: number): string {
+    const r = document.createRange();
+    r.selectNodeContents(container);
+    r.setEnd(node, offset);
+    return r.toString();
+  }
+
+  const sel = window.getSelection();
+  const page = { url: location.href, title: document.title };
+  if (!sel || sel.rangeCount === 0 || sel.isCollapsed) return { ...page, text: "", html: "" };
+  const range = sel.getRangeAt(0);
+  const div = document.createElement("div");
+  div.appendChild(range.cloneContents());
+
+  // Offsets are into the text of the element holding the whole selection, which the XPath names.
+  const common = range.commonAncestorContainer;
+  const el = common instanceof Element ? common : common.parentElement;
+  const exact = range.toString();
+  const before = textBefore(document.body, range.startContainer, range.startOffset);
+  const after = (document.body.textContent || "").slice(before.length + exact.length);
+  const anchors = {
+    xpath: el ? xpathOf(el) : undefined,
+    startOffset: el ? textBefore(el, range.startContainer, range.startOffset).length : undefined,
+    endOffset: el ? textBefore(el, range.endContainer, range.endOffset).length : undefined,
+    textQuote: { exact, prefix: before.slice(-QUOTE_CONTEXT), suffix: after.slice(0, QUOTE_CONTEXT) },
+  };
+  return { ...page, text: sel.toString(), html: div.innerHTML, anchors };
+}
@@ -0,0 +1,370 @@
+//! Files stored next to the database. New files are content-addressed: named by the SHA-256 of their
+//! bytes under `assets/ab/cd/`, so the same screenshot clipped twice is stored once. The `assets`
+//! table records each file's type and size, and `note_assets` which notes use it.
+
+use std::{fs, io::Write, path::{Component, Path as FsPath, PathBuf}};
+use chrono::{Duration, Utc};
+use base64::{engine::general_purpose, Engine as _};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use crate::{error::{ApiError, ApiResult}, model::{Asset, NoteAsset}, store::NoteStore};
+
+// How long an asset uploaded to `/assets` may wait for a clip to use it before a purge removes it.
+const UPLOAD_GRACE: Duration = Duration::days(1);
+
+/// Lowercase hex SHA-256 of `bytes`; the id of an asset.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+  Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The MIME type `bytes` start like, for the formats clips carry; `None` for anything else.
+pub fn detect_mime(bytes: &[u8]) -> Option<&'static str> {
+  let starts = |magic: &[u8]| bytes.starts_with(magic);
+  if starts(b"\x89PNG\r\n\x1a\n") { Some("image/png") }
+  else if starts(b"\xff\xd8\xff") { Some("image/jpeg") }
+  else if bytes.len() >= 12 && starts(b"RIFF") && &bytes[8..12] == b"WEBP" { Some("image/webp") }
+  else if starts(b"GIF87a") || starts(b"GIF89a") { Some("image/gif") }
+  else if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" && matches!(&bytes[8..12], b"avif" | b"avis") { Some("image/avif") }
+  else if starts(b"%PDF-") { Some("application/pdf") }
+  else { None }
+}
+
+/// The types `/file` serves as what they are. Anything else goes out as an `application/octet-stream`
+/// download, so an uploaded page or script never runs on the API's origin.
+pub const SERVABLE: &[&str] = &["image/png", "image/jpeg", "image/webp", "image/gif", "image/avif", "application/pdf"];
+
+// The directories under `data_dir` that hold clip files. `/file` serves nothing outside them, so
+// the database and `auth_token` next to them can't be read through it.
+const SERVED_DIRS: &[&str] = &["assets", "previews"];
+
+/// Whether `/file` may serve `rel`: a plain relative path to a file inside one of `SERVED_DIRS`.
+pub fn is_served(rel: &str) -> bool {
+  let mut components = FsPath::new(rel).components();
+  let top = match components.next() { Some(Component::Normal(top)) => top, _ => return false };
+  let rest: Vec<Component> = components.collect();
+  SERVED_DIRS.iter().any(|d| top == *d) && !rest.is_empty() && rest.iter().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// `mime` if it is one of `SERVABLE`, else `application/octet-stream`.
+pub fn servable(mime: &str) -> &str { SERVABLE.iter().find(|&&m| m == mime).copied().unwrap_or("application/octet-stream") }
+
+fn extension(mime: &str) -> &'static str {
+  match mime {
+    "image/png" => "png", "image/jpeg" => "jpg", "image/webp" => "webp", "image/gif" => "gif", "image/avif" => "avif",
+    "application/pdf" => "pdf",
+    _ => "bin",
+  }
+}
+
+/// Where a file named `stem` that belongs with asset `hash` is stored: `assets/ab/cd/{stem}.{ext}`.
+pub(crate) fn stored_path(hash: &str, stem: &str, mime: &str) -> String {
+  format!("assets/{}/{}/{}.{}", &hash[..2], &hash[2..4], stem, extension(mime))
+}
+
+/// The bytes of a base64 `data:` URL. `field` names it in errors. The type it declares is ignored:
+/// stored files get the type their bytes have.
+pub fn decode_data_url(field: &str, data_url: &str) -> ApiResult<Vec<u8>> {
+  let (header, b64) = data_url.strip_prefix("data:").and_then(|rest| rest.split_once(','))
+    .ok_or_else(|| ApiError::Validation(format!("{} is not a data URL", field)))?;
+  let encoding = header.rsplit_once(';').map_or("", |(_, e)| e);
+  if encoding != "base64" { return Err(ApiError::Validation(format!("{} must be base64-encoded", field))); }
+  let bytes = general_purpose::STANDARD.decode(b64.trim()).map_err(|e| ApiError::Validation(format!("{}: {}", field, e)))?;
+  if bytes.is_empty() { return Err(ApiError::Validation(format!("{} is empty", field))); }
+  Ok(bytes)
+}
+
+/// Files under `data_dir`, addressed by a path relative to it. Writes and deletes of stored assets
+/// happen with the database locked, so a purge can't remove a file a new clip is about to use.
+#[derive(Clone)]
+pub struct AssetStore { data_dir: PathBuf }
+
+impl AssetStore {
+  pub fn new(data_dir: impl Into<PathBuf>) -> Self { AssetStore { data_dir: data_dir.into() } }
+
+  pub fn data_dir(&self) -> &FsPath { &self.data_dir }
+
+  /// Stores `bytes` under their hash unless a file with that hash is already there, typed by `stored_mime`.
+  pub fn put(&self, bytes: &[u8]) -> ApiResult<Asset> {
+    let hash = sha256_hex(bytes);
+    let mime = stored_mime(bytes);
+    let rel = stored_path(&hash, &hash, &mime);
+    if !self.data_dir.join(&rel).is_file() { self.write(&rel, bytes)?; }
+    Ok(Asset { hash, mime, size: bytes.len() as i64, path: rel })
+  }
+
+  /// Writes `bytes` to `rel`, replacing any file there. Written aside and renamed into place, so a
+  /// crash never leaves a truncated file behind.
+  pub(crate) fn write(&self, rel: &str, bytes: &[u8]) -> ApiResult<()> {
+    let abs = self.resolve(rel)?;
+    let dir = abs.parent().ok_or_else(|| ApiError::Validation(format!("{} is not a file path", rel)))?;
+    fs::create_dir_all(dir)?;
+    let name = abs.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let tmp = dir.join(format!(".{}.{}.tmp", name, std::process::id()));
+    let written = fs::File::create(&tmp).and_then(|mut f| { f.write_all(bytes)?; f.sync_all() }).and_then(|_| fs::rename(&tmp, &abs));
+    if let Err(e) = written { let _ = fs::remove_file(&tmp); return Err(e.into()); }
+    Ok(())
+  }
+
+  /// Starts streaming a file of at most `limit` bytes into the store; see `Staged`.
+  pub fn stage(&self, limit: u64) -> ApiResult<Staged> {
+    let dir = self.data_dir.join("uploads");
+    fs::create_dir_all(&dir)?;
+    let tmp = dir.join(format!("{}.part", uuid::Uuid::new_v4()));
+    let file = fs::File::create(&tmp)?;
+    Ok(Staged { file, tmp: Some(tmp), hasher: Sha256::new(), size: 0, head: Vec::new(), limit })
+  }
+
+  /// Resolves `rel` under `data_dir`, refusing anything that could escape it.
+  pub fn resolve(&self, rel: &str) -> ApiResult<PathBuf> {
+    let rel = PathBuf::from(rel);
+    for c in rel.components() {
+      match c { Component::ParentDir|Component::RootDir|Component::Prefix(_) =>
+        return Err(ApiError::Validation("file path must stay inside the data directory".into())),
+        _=>{}
+      }
+    }
+    Ok(self.data_dir.join(rel))
+  }
+
+  /// The file's bytes and the MIME type they look like.
+  pub fn read(&self, rel: &str) -> ApiResult<(Vec<u8>, &'static str)> {
+    let abs = self.resolve(rel)?;
+    let bytes = fs::read(&abs).map_err(|e| match e.kind() {
+      std::io::ErrorKind::NotFound => ApiError::NotFound(format!("file {} not found", rel)),
+      _ => ApiError::from(e),
+    })?;
+    let ct = detect_mime(&bytes).unwrap_or("application/octet-stream");
+    Ok((bytes, ct))
+  }
+
+  /// Deletes the file at `rel`; one that is already gone counts as removed.
+  pub fn remove(&self, rel: &str) -> ApiResult<()> {
+    match fs::remove_file(self.resolve(rel)?) {
+      Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// A file being streamed into the store: hashed and counted as it is written to `uploads/`, so it
+/// is never held in memory whole. `NoteStore::add_staged` moves it under its hash; dropped before
+/// that, it is deleted.
+pub struct Staged { file: fs::File, tmp: Option<PathBuf>, hasher: Sha256, size: u64, head: Vec<u8>, limit: u64 }
+
+impl Staged {
+  pub fn write(&mut self, chunk: &[u8]) -> ApiResult<()> {
+    self.size += chunk.len() as u64;
+    if self.size > self.limit {
+      return Err(ApiError::PayloadTooLarge(format!("uploads are limited to {} MiB", self.limit / (1024 * 1024))));
+    }
+    // Enough of the start to sniff the type from.
+    if self.head.len() < 16 { self.head.extend_from_slice(&chunk[..chunk.len().min(16 - self.head.len())]); }
+    self.hasher.update(chunk);
+    Ok(self.file.write_all(chunk)?)
+  }
+
+  // Moves the file to its place under `data_dir`, unless one with that hash is there already.
+  fn finish(mut self, data_dir: &FsPath) -> ApiResult<Asset> {
+    if self.size == 0 { return Err(ApiError::Validation("the uploaded file is empty".into())); }
+    self.file.sync_all()?;
+    let hash: String = std::mem::take(&mut self.hasher).finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    let mime = stored_mime(&self.head);
+    let rel = stored_path(&hash, &hash, &mime);
+    let abs = data_dir.join(&rel);
+    let tmp = self.tmp.take().expect("a staged file is finished once");
+    if abs.is_file() {
+      fs::remove_file(&tmp)?;
+    } else {
+      fs::create_dir_all(abs.parent().expect("asset paths have a parent"))?;
+      if let Err(e) = fs::rename(&tmp, &abs) { let _ = fs::remove_file(&tmp); return Err(e.into()); }
+    }
+    Ok(Asset { hash, mime, size: self.size as i64, path: rel })
+  }
+}
+
+impl Drop for Staged {
+  fn drop(&mut self) { if let Some(tmp) = self.tmp.take() { let _ = fs::remove_file(tmp); } }
+}
+
+// The type a file starting with `head` is stored as: one of `SERVABLE` if the bytes are one, else
+// `application/octet-stream`. What the client said it was is never taken on trust.
+fn stored_mime(head: &[u8]) -> String {
+  servable(detect_mime(head).unwrap_or("application/octet-stream")).to_string()
+}
+
+/// The asset with hash `hash`, if it is stored.
+pub(crate) fn find(db: &Connection, hash: &str) -> rusqlite::Result<Option<Asset>> {
+  db.query_row("SELECT hash, mime, size, path FROM assets WHERE hash=?1", params![hash],
+    |r| Ok(Asset { hash: r.get(0)?, mime: r.get(1)?, size: r.get(2)?, path: r.get(3)? })).optional()
+}
+
+/// Records `asset` unless its hash is known already. Returns the row as stored, whose `path` may
+/// differ from `asset.path` for files kept where they were before the store was content-addressed.
+pub(crate) fn register(db: &Connection, asset: &Asset, now: &str) -> rusqlite::Result<Asset> {
+  db.execute("INSERT INTO assets (hash, mime, size, path, created_at) VALUES (?1,?2,?3,?4,?5) ON CONFLICT(hash) DO NOTHING",
+    params![asset.hash, asset.mime, asset.size, asset.path, now])?;
+  find(db, &asset.hash)?.ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+/// Attaches a registered asset to note `id`, after the ones it already has. A file the note
+/// already has keeps its place.
+pub(crate) fn link(db: &Connection, id: &str, hash: &str, role: &str, name: Option<&str>, now: &str) -> rusqlite::Result<()> {
+  db.execute(
+    "INSERT INTO note_assets (note_id, asset_hash, role, name, position, created_at)
+     VALUES (?1, ?2, ?3, ?4, (SELECT COALESCE(MAX(position) + 1, 0) FROM note_assets WHERE note_id=?1), ?5)
+     ON CONFLICT(note_id, asset_hash) DO NOTHING",
+    params![id, hash, role, name, now])?;
+  Ok(())
+}
+
+/// The assets of note `id`, in order.
+pub(crate) fn for_note(db: &Connection, id: &str) -> rusqlite::Result<Vec<NoteAsset>> {
+  let mut stmt = db.prepare(
+    "SELECT a.hash, a.mime, a.size, a.path, na.role, na.name, na.position, na.created_at
+     FROM note_assets na JOIN assets a ON a.hash = na.asset_hash WHERE na.note_id=?1 ORDER BY na.position")?;
+  let rows = stmt.query_map(params![id], |r| Ok(NoteAsset {
+    asset: Asset { hash: r.get(0)?, mime: r.get(1)?, size: r.get(2)?, path: r.get(3)? },
+    role: r.get(4)?, name: r.get(5)?, position: r.get(6)?, created_at: r.get(7)?,
+  }))?.collect();
+  rows
+}
+
+/// Forgets assets no note or document uses any more, with their thumbnails: those in `released` (just unlinked
+/// by the caller), and uploads no clip took up within `UPLOAD_GRACE`. Returns the paths of their
+/// files, for the caller to delete once committed.
+pub(crate) fn drop_orphans(db: &Connection, released: &[String]) -> rusqlite::Result<Vec<String>> {
+  let orphans = format!("SELECT hash FROM assets WHERE hash NOT IN (SELECT asset_hash FROM note_assets) AND hash NOT IN (SELECT hash FROM documents) AND (created_at <= ? OR hash IN ({}))",
+    vec!["?"; released.len()].join(","));
+  let args: Vec<String> = std::iter::once((Utc::now() - UPLOAD_GRACE).to_rfc3339()).chain(released.iter().cloned()).collect();
+  let paths = {
+    let mut stmt = db.prepare(&format!(
+      "SELECT path FROM assets WHERE hash IN ({orphans}) UNION ALL SELECT path FROM asset_thumbnails WHERE asset_hash IN ({orphans})"))?;
+    let rows = stmt.query_map(params_from_iter(args.iter().chain(&args)), |r| r.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+    rows
+  };
+  db.execute(&format!("DELETE FROM asset_thumbnails WHERE asset_hash IN ({orphans})"), params_from_iter(&args))?;
+  db.execute(&format!("DELETE FROM assets WHERE hash IN ({orphans})"), params_from_iter(&args))?;
+  Ok(paths)
+}
+
+impl NoteStore {
+  /// Stores a file streamed with `AssetStore::stage`, for clips to refer to by its hash.
+  pub fn add_staged(&self, staged: Staged) -> ApiResult<Asset> {
+    let db = self.conn()?;
+    let asset = staged.finish(self.assets().data_dir())?;
+    let stored = register(&db, &asset, &Utc::now().to_rfc3339())?;
+    if stored.path != asset.path { self.assets().remove(&asset.path)?; }
+    Ok(stored)
+  }
+
+  /// The asset stored at `path`, if it is one.
+  pub fn asset_at(&self, path: &str) -> ApiResult<Option<Asset>> {
+    Ok(self.conn()?.query_row("SELECT hash, mime, size, path FROM assets WHERE path=?1", params![path],
+      |r| Ok(Asset { hash: r.get(0)?, mime: r.get(1)?, size: r.get(2)?, path: r.get(3)? })).optional()?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::tests::{clip, png_data_url, temp_store};
+
+  const PNG: &[u8] = b"\x89PNG\r\n\x1a\nnot really an image";
+
+  #[test]
+  fn serves_only_clip_files() {
+    assert!(is_served("assets/ab/cd/abcd.png"));
+    assert!(is_served("previews/note.png"));
+    for rel in ["auth_token", "levelnotes.db", "server.json", "uploads/x.part", "assets", "assets/../auth_token", "/assets/a.png", "./assets/a.png", ""] {
+      assert!(!is_served(rel), "{}", rel);
+    }
+  }
+
+  #[test]
+  fn stores_the_same_bytes_once() {
+    let (dir, store) = temp_store();
+    assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    let first = store.assets().put(PNG).unwrap();
+    let again = store.assets().put(PNG).unwrap();
+    assert_eq!((first.hash.as_str(), first.path.as_str()), (again.hash.as_str(), again.path.as_str()));
+    assert_eq!(first.hash, sha256_hex(PNG));
+    assert_eq!(first.path, format!("assets/{}/{}/{}.png", &first.hash[..2], &first.hash[2..4], first.hash));
+    assert_eq!(std::fs::read(dir.join(&first.path)).unwrap(), PNG);
+    assert_eq!(std::fs::read_dir(dir.join(&first.path).parent().unwrap()).unwrap().count(), 1);
+    let other = store.assets().put(b"\x89PNG\r\n\x1a\nanother").unwrap();
+    assert_ne!(other.path, first.path);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn types_files_by_their_bytes_and_serves_only_safe_types() {
+    assert_eq!(stored_mime(PNG), "image/png");
+    assert_eq!(stored_mime(b"%PDF-1.7"), "application/pdf");
+    assert_eq!(stored_mime(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+    for page in [b"<!doctype html><script>alert(1)</script>".as_slice(), b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>", b""] {
+      assert_eq!(stored_mime(page), "application/octet-stream");
+    }
+    assert_eq!(servable("image/jpeg"), "image/jpeg");
+    for mime in ["text/html", "image/svg+xml", "application/javascript", "IMAGE/PNG"] { assert_eq!(servable(mime), "application/octet-stream"); }
+  }
+
+  #[test]
+  fn drops_only_unused_assets_past_the_grace_period() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(serde_json::json!({
+      "selection": { "text": "shot" }, "media": { "screenshotDataUrl": png_data_url("used") },
+    }))).unwrap();
+    let used = store.get(&id).unwrap().assets[0].asset.hash.clone();
+    let db = store.conn().unwrap();
+    let old = (Utc::now() - UPLOAD_GRACE - Duration::minutes(1)).to_rfc3339();
+    let now = Utc::now().to_rfc3339();
+    let upload = |bytes: &[u8], at: &str| register(&db, &store.assets().put(bytes).unwrap(), at).unwrap();
+    let fresh = upload(b"\x89PNG\r\n\x1a\nfresh", &now);
+    let stale = upload(b"\x89PNG\r\n\x1a\nstale", &old);
+    let released = upload(b"\x89PNG\r\n\x1a\nreleased", &now);
+    db.execute("UPDATE assets SET created_at=?1 WHERE hash=?2", params![old, used]).unwrap();
+
+    let mut dropped = drop_orphans(&db, std::slice::from_ref(&released.hash)).unwrap();
+    dropped.sort();
+    let mut expected = vec![stale.path.clone(), released.path.clone()];
+    expected.sort();
+    assert_eq!(dropped, expected);
+    for (asset, kept) in [(&used, true), (&fresh.hash, true), (&stale.hash, false), (&released.hash, false)] {
+      assert_eq!(find(&db, asset).unwrap().is_some(), kept, "{}", asset);
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn streams_uploads_into_the_store_by_hash() {
+    let (dir, store) = temp_store();
+    let bytes: Vec<u8> = [b"%PDF-1.7\n".as_slice(), &[7u8; 5000]].concat();
+    let mut staged = store.assets().stage(1 << 20).unwrap();
+    for chunk in bytes.chunks(3) { staged.write(chunk).unwrap(); }
+    let asset = store.add_staged(staged).unwrap();
+    assert_eq!((asset.hash.clone(), asset.mime.as_str(), asset.size), (sha256_hex(&bytes), "application/pdf", bytes.len() as i64));
+    assert_eq!(std::fs::read(dir.join(&asset.path)).unwrap(), bytes);
+    // The same file again is the same asset, and leaves nothing behind in `uploads/`.
+    let mut staged = store.assets().stage(1 << 20).unwrap();
+    staged.write(&bytes).unwrap();
+    assert_eq!(store.add_staged(staged).unwrap().path, asset.path);
+    assert_eq!(std::fs::read_dir(dir.join("uploads")).unwrap().count(), 0);
+    assert!(matches!(store.add_staged(store.assets().stage(1 << 20).unwrap()), Err(ApiError::Validation(_))));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn refuses_uploads_over_the_limit() {
+    let (dir, store) = temp_store();
+    let mut staged = store.assets().stage(3 << 20).unwrap();
+    staged.write(&vec![0u8; 2 << 20]).unwrap();
+    staged.write(&vec![0u8; 1 << 20]).unwrap();
+    match staged.write(b"x") {
+      Err(ApiError::PayloadTooLarge(m)) => assert_eq!(m, "uploads are limited to 3 MiB"),
+      other => panic!("expected 413, got {:?}", other.err()),
+    }
+    drop(staged);
+    assert_eq!(std::fs::read_dir(dir.join("uploads")).unwrap().count(), 0);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,209 @@
+use std::{collections::BTreeSet, fs, io, path::{Path as FsPath, PathBuf}, sync::{Arc, Mutex, RwLock}, time::{Duration, Instant}};
+use axum::{extract::{Request, State}, http::{header, HeaderMap, HeaderValue, Method}, middleware::Next, response::Response};
+use uuid::Uuid;
+use crate::{error::ApiError, router::AppState};
+
+const TOKEN_FILE: &str = "auth_token";
+const TOKEN_HEADER: &str = "x-levelnotes-token";
+pub const PAIRING_TTL: Duration = Duration::from_secs(300);
+const PAIRING_MAX_ATTEMPTS: u32 = 5;
+
+// Origins the desktop webview loads from: Tauri on macOS/Linux, and on Windows.
+const APP_ORIGINS: &[&str] = &["tauri://localhost", "http://tauri.localhost", "https://tauri.localhost"];
+
+// The Vite dev server, trusted in debug builds only; in a release any local page could sit on that port.
+const DEV_ORIGINS: &[&str] = &["http://localhost:5173"];
+
+// Routes reachable without the token: liveness, and completing a pairing (the code is the credential).
+const PUBLIC_PATHS: &[&str] = &["/health", "/pair/complete"];
+
+struct Pairing { code: String, expires: Instant, attempts: u32 }
+
+/// The per-install API secret plus the browser origins allowed to call the API.
+#[derive(Clone)]
+pub struct Auth {
+  token: Arc<String>,
+  origins: Arc<RwLock<BTreeSet<String>>>,
+  pairing: Arc<Mutex<Option<Pairing>>>,
+}
+
+fn random_hex(bytes: usize) -> String {
+  let mut out = String::with_capacity(bytes * 2);
+  while out.len() < bytes * 2 { out.push_str(&Uuid::new_v4().simple().to_string()); }
+  out.truncate(bytes * 2); out
+}
+
+fn write_private(path: &FsPath, contents: &str) -> io::Result<()> {
+  #[cfg(unix)]
+  {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    let mut f = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    f.write_all(contents.as_bytes())
+  }
+  #[cfg(not(unix))]
+  { fs::write(path, contents) }
+}
+
+// Compares without short-circuiting so response timing doesn't leak how much of a guess matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl Auth {
+  /// Reads `auth_token` from `data_dir`, creating it on first run.
+  pub fn load_or_create(data_dir: &FsPath, extra_origins: impl IntoIterator<Item = String>) -> io::Result<Self> {
+    let path: PathBuf = data_dir.join(TOKEN_FILE);
+    let token = match fs::read_to_string(&path) {
+      Ok(t) if !t.trim().is_empty() => t.trim().to_string(),
+      Ok(_) => { let t = random_hex(32); write_private(&path, &t)?; t }
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        fs::create_dir_all(data_dir)?;
+        let t = random_hex(32); write_private(&path, &t)?; t
+      }
+      Err(e) => return Err(e),
+    };
+    let dev = if cfg!(debug_assertions) { DEV_ORIGINS } else { &[] };
+    let origins = APP_ORIGINS.iter().chain(dev).map(|o| o.to_string()).chain(extra_origins).collect();
+    Ok(Auth { token: Arc::new(token), origins: Arc::new(RwLock::new(origins)), pairing: Arc::new(Mutex::new(None)) })
+  }
+
+  pub fn token(&self) -> &str { &self.token }
+
+  pub fn allows_origin(&self, origin: &str) -> bool {
+    self.origins.read().map(|o| o.contains(origin)).unwrap_or(false)
+  }
+
+  pub fn allow_origin(&self, origin: &str) {
+    if let Ok(mut o) = self.origins.write() { o.insert(origin.to_string()); }
+  }
+
+  // Headers only: a token in the query string would end up in history, logs and `Referer`.
+  fn presented_token(headers: &HeaderMap) -> Option<String> {
+    if let Some(v) = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+      if let Some(t) = v.strip_prefix("Bearer ") { return Some(t.trim().to_string()); }
+    }
+    headers.get(TOKEN_HEADER).and_then(|v| v.to_str().ok()).map(|v| v.trim().to_string())
+  }
+
+  pub fn check(&self, headers: &HeaderMap) -> Result<(), ApiError> {
+    match Self::presented_token(headers) {
+      Some(t) if constant_time_eq(t.as_bytes(), self.token.as_bytes()) => Ok(()),
+      Some(_) => Err(ApiError::Unauthorized("invalid API token".into())),
+      None => Err(ApiError::Unauthorized("missing API token; send `Authorization: Bearer <token>`".into())),
+    }
+  }
+
+  /// Starts a pairing window and returns the one-time code to show the user.
+  pub fn start_pairing(&self) -> Result<String, ApiError> {
+    let n = u32::from_le_bytes(Uuid::new_v4().as_bytes()[..4].try_into().unwrap_or_default()) % 1_000_000;
+    let code = format!("{:06}", n);
+    *self.pairing.lock()? = Some(Pairing { code: code.clone(), expires: Instant::now() + PAIRING_TTL, attempts: 0 });
+    Ok(code)
+  }
+
+  /// Redeems a pairing code. The code is single-use and dies after a few wrong guesses.
+  pub fn complete_pairing(&self, code: &str) -> Result<String, ApiError> {
+    let mut slot = self.pairing.lock()?;
+    let pairing = match slot.as_mut() {
+      Some(p) if p.expires > Instant::now() => p,
+      _ => { *slot = None; return Err(ApiError::Unauthorized("no pairing in progress; start one from the desktop app".into())); }
+    };
+    if !constant_time_eq(pairing.code.as_bytes(), code.trim().as_bytes()) {
+      pairing.attempts += 1;
+      if pairing.attempts >= PAIRING_MAX_ATTEMPTS { *slot = None; }
+      return Err(ApiError::Unauthorized("wrong pairing code".into()));
+    }
+    *slot = None;
+    Ok(self.token.to_string())
+  }
+}
+
+/// Middleware: every route except `PUBLIC_PATHS` needs the token. Preflights are answered by the CORS layer.
+pub async fn require_token(State(state): State<AppState>, req: Request, next: Next) -> Result<Response, ApiError> {
+  if req.method() != Method::OPTIONS && !PUBLIC_PATHS.contains(&req.uri().path()) {
+    state.auth.check(req.headers())?;
+  }
+  Ok(next.run(req).await)
+}
+
+pub(crate) fn is_extension_origin(origin: &str) -> bool {
+  ["chrome-extension://", "moz-extension://", "safari-web-extension://"].iter().any(|s| origin.starts_with(s))
+}
+
+/// CORS predicate: the app and paired origins everywhere; unpaired extensions only on `/pair/complete`.
+pub(crate) fn origin_allowed(auth: &Auth, origin: &HeaderValue, path: &str) -> bool {
+  let Ok(origin) = origin.to_str() else { return false };
+  auth.allows_origin(origin) || (path == "/pair/complete" && is_extension_origin(origin))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn temp_auth() -> (PathBuf, Auth) {
+    let dir = std::env::temp_dir().join(format!("levelnotes-test-{}", Uuid::new_v4()));
+    let auth = Auth::load_or_create(&dir, ["chrome-extension://paired".to_string()]).unwrap();
+    (dir, auth)
+  }
+
+  fn headers(name: &str, value: &str) -> HeaderMap {
+    let mut h = HeaderMap::new();
+    h.insert(axum::http::HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+    h
+  }
+
+  #[test]
+  fn keeps_the_token_across_loads() {
+    let (dir, auth) = temp_auth();
+    assert_eq!(auth.token().len(), 64);
+    assert_eq!(Auth::load_or_create(&dir, []).unwrap().token(), auth.token());
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn accepts_the_token_only_in_headers() {
+    let (dir, auth) = temp_auth();
+    assert!(auth.check(&headers("authorization", &format!("Bearer {}", auth.token()))).is_ok());
+    assert!(auth.check(&headers(TOKEN_HEADER, auth.token())).is_ok());
+    assert!(matches!(auth.check(&headers("authorization", "Bearer nope")), Err(ApiError::Unauthorized(_))));
+    assert!(matches!(auth.check(&HeaderMap::new()), Err(ApiError::Unauthorized(_))));
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn admits_the_app_and_paired_origins() {
+    let (dir, auth) = temp_auth();
+    let allowed = |origin: &str, path: &str| origin_allowed(&auth, &HeaderValue::from_str(origin).unwrap(), path);
+    assert!(allowed("tauri://localhost", "/notes"));
+    assert!(allowed("chrome-extension://paired", "/clip"));
+    assert!(!allowed("https://evil.example", "/notes"));
+    assert!(!allowed("https://evil.example", "/pair/complete"));
+    // An unpaired extension may only complete a pairing.
+    assert!(!allowed("chrome-extension://new", "/clip"));
+    assert!(allowed("chrome-extension://new", "/pair/complete"));
+    auth.allow_origin("chrome-extension://new");
+    assert!(allowed("chrome-extension://new", "/clip"));
+    assert_eq!(allowed("http://localhost:5173", "/notes"), cfg!(debug_assertions));
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn pairing_codes_are_single_use() {
+    let (dir, auth) = temp_auth();
+    assert!(auth.complete_pairing("000000").is_err());
+    let code = auth.start_pairing().unwrap();
+    assert_eq!(auth.complete_pairing(&code).unwrap(), auth.token());
+    assert!(matches!(auth.complete_pairing(&code), Err(ApiError::Unauthorized(_))));
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn pairing_ends_after_too_many_wrong_codes() {
+    let (dir, auth) = temp_auth();
+    let code = auth.start_pairing().unwrap();
+    let wrong = if code == "000000" { "000001" } else { "000000" };
+    for _ in 0..PAIRING_MAX_ATTEMPTS { assert!(auth.complete_pairing(wrong).is_err()); }
+    assert!(auth.complete_pairing(&code).is_err());
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,114 @@
+//! HTTP validators for notes. A note's `ETag` and `Last-Modified` both derive from its `updated_at`,
+//! and writes sent with `If-Match` or `If-Unmodified-Since` are refused once another window has
+//! changed the note, instead of overwriting that change.
+
+use axum::http::{header, HeaderMap, HeaderValue};
+use chrono::{DateTime, Utc};
+use crate::error::{ApiError, ApiResult};
+
+fn parse(updated_at: &str) -> Option<DateTime<Utc>> { DateTime::parse_from_rfc3339(updated_at).ok().map(|t| t.with_timezone(&Utc)) }
+
+/// Strong entity tag for a note last written at `updated_at`.
+pub fn etag(updated_at: &str) -> String {
+  match parse(updated_at).and_then(|t| t.timestamp_nanos_opt()) {
+    Some(nanos) => format!("\"{:x}\"", nanos),
+    None => format!("\"{}\"", updated_at.replace(|c: char| !c.is_ascii_alphanumeric(), "")),
+  }
+}
+
+/// `ETag` and `Last-Modified` headers for a note last written at `updated_at`.
+pub fn validators(updated_at: &str) -> HeaderMap {
+  let mut headers = HeaderMap::new();
+  if let Ok(v) = HeaderValue::from_str(&etag(updated_at)) { headers.insert(header::ETAG, v); }
+  if let Some(t) = parse(updated_at) {
+    if let Ok(v) = HeaderValue::from_str(&t.format("%a, %d %b %Y %H:%M:%S GMT").to_string()) { headers.insert(header::LAST_MODIFIED, v); }
+  }
+  headers
+}
+
+/// The `If-Match` and `If-Unmodified-Since` a write came with. Checked by the store inside the
+/// write's transaction, so nothing can change the note between the check and the write.
+#[derive(Default, Debug, Clone)]
+pub struct Precondition { pub if_match: Option<String>, pub if_unmodified_since: Option<DateTime<Utc>> }
+
+impl Precondition {
+  pub fn from_headers(headers: &HeaderMap) -> ApiResult<Precondition> {
+    let text = |name: header::HeaderName| -> ApiResult<Option<String>> {
+      headers.get(&name).map(|v| v.to_str().map(|s| s.trim().to_string()).map_err(|_| ApiError::Validation(format!("{} is not valid text", name)))).transpose()
+    };
+    let if_unmodified_since = match text(header::IF_UNMODIFIED_SINCE)? {
+      // The weekday is redundant, and a wrong one shouldn't fail the request.
+      Some(s) => Some(DateTime::parse_from_rfc2822(s.split_once(", ").map_or(s.as_str(), |(_, date)| date)).map_err(|_| ApiError::Validation(format!("If-Unmodified-Since {:?} is not an HTTP date", s)))?.with_timezone(&Utc)),
+      None => None,
+    };
+    Ok(Precondition { if_match: text(header::IF_MATCH)?, if_unmodified_since })
+  }
+
+  /// Ok if the client's view of a note last written at `updated_at` is still current.
+  /// `If-Match` wins when both are sent, as RFC 9110 asks.
+  pub fn check(&self, id: &str, updated_at: &str) -> ApiResult<()> {
+    let fresh = if let Some(tags) = &self.if_match {
+      let current = etag(updated_at);
+      // Strong comparison (RFC 9110 13.1.1): a weak tag never matches.
+      tags.split(',').map(str::trim).any(|t| t == "*" || t == current)
+    } else if let Some(since) = self.if_unmodified_since {
+      // HTTP dates have whole seconds; an edit within the same second as `since` passes, which `If-Match` catches.
+      parse(updated_at).map(|t| t.timestamp() <= since.timestamp()).unwrap_or(false)
+    } else {
+      true
+    };
+    if fresh { Ok(()) } else { Err(ApiError::PreconditionFailed(format!("note {} was changed since you loaded it (now {})", id, etag(updated_at)))) }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const AT: &str = "2024-05-06T07:08:09.123456789+00:00";
+
+  fn precondition(headers: &[(header::HeaderName, &str)]) -> ApiResult<Precondition> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers { map.insert(name.clone(), HeaderValue::from_str(value).unwrap()); }
+    Precondition::from_headers(&map)
+  }
+
+  fn passes(headers: &[(header::HeaderName, &str)]) -> bool {
+    match precondition(headers).unwrap().check("n1", AT) {
+      Ok(()) => true,
+      Err(ApiError::PreconditionFailed(m)) => { assert!(m.starts_with("note n1 was changed")); false }
+      Err(e) => panic!("{}", e),
+    }
+  }
+
+  #[test]
+  fn sends_validators_for_the_last_write() {
+    let headers = validators(AT);
+    assert_eq!(headers[header::ETAG], etag(AT).as_str());
+    assert_eq!(headers[header::LAST_MODIFIED], "Mon, 06 May 2024 07:08:09 GMT");
+    assert_ne!(etag(AT), etag("2024-05-06T07:08:09.123456790+00:00"));
+  }
+
+  #[test]
+  fn if_match_needs_the_current_strong_tag() {
+    let current = etag(AT);
+    assert!(passes(&[]));
+    assert!(passes(&[(header::IF_MATCH, &current)]));
+    assert!(passes(&[(header::IF_MATCH, &format!("\"old\", {}", current))]));
+    assert!(passes(&[(header::IF_MATCH, "*")]));
+    assert!(!passes(&[(header::IF_MATCH, "\"old\"")]));
+    assert!(!passes(&[(header::IF_MATCH, &format!("W/{}", current))]));
+    // If-Match is what counts when both are sent.
+    assert!(!passes(&[(header::IF_MATCH, "\"old\""), (header::IF_UNMODIFIED_SINCE, "Tue, 07 May 2024 00:00:00 GMT")]));
+  }
+
+  #[test]
+  fn if_unmodified_since_compares_whole_seconds() {
+    assert!(passes(&[(header::IF_UNMODIFIED_SINCE, "Mon, 06 May 2024 07:08:09 GMT")]));
+    assert!(passes(&[(header::IF_UNMODIFIED_SINCE, "Tue, 07 May 2024 00:00:00 GMT")]));
+    assert!(!passes(&[(header::IF_UNMODIFIED_SINCE, "Mon, 06 May 2024 07:08:08 GMT")]));
+    // A wrong weekday is ignored; a date that isn't one is a 400.
+    assert!(passes(&[(header::IF_UNMODIFIED_SINCE, "Fri, 06 May 2024 07:08:09 GMT")]));
+    assert!(matches!(precondition(&[(header::IF_UNMODIFIED_SINCE, "yesterday")]), Err(ApiError::Validation(_))));
+  }
+}
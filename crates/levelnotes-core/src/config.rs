@@ -0,0 +1,316 @@
+use std::{collections::BTreeMap, fmt, fs, io, net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener}, path::{Path as FsPath, PathBuf}};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PORT: u16 = 3030;
+pub const DEFAULT_TRASH_RETENTION_DAYS: u32 = 30;
+pub const DEFAULT_MAX_UPLOAD_MB: u64 = 100;
+const CONFIG_FILE: &str = "levelnotes.toml";
+/// How many ports above the requested one are tried when it is busy. The extension and SDK look for
+/// the API on the same range, so it never moves anywhere they wouldn't find it.
+pub const PORT_FALLBACK_TRIES: u16 = 10;
+// Where builds before the platform data directory kept notes on Linux and macOS, relative to the
+// directory they were started from.
+const LEGACY_DATA_DIR: &str = "../.levelnotes";
+
+/// One layer of settings; unset fields fall through to the layer below.
+/// Also the shape of `levelnotes.toml`.
+#[derive(Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+  pub data_dir: Option<PathBuf>,
+  pub bind: Option<IpAddr>,
+  pub port: Option<u16>,
+  pub tokenizer: Option<Tokenizer>,
+  pub remove_diacritics: Option<bool>,
+  pub metadata_dir: Option<PathBuf>,
+  pub metadata_fixtures: Option<PathBuf>,
+  pub trash_retention_days: Option<u32>,
+  pub max_upload_mb: Option<u64>,
+}
+
+/// How note text is split into search terms. Changing it rebuilds the index on the next start.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Tokenizer {
+  /// Whole words, case-folded (the default).
+  Unicode61,
+  /// Whole words with English stemming, so `running` finds `run`.
+  Porter,
+  /// Any substring of three or more characters; suits CJK text and partial words, at several times the index size.
+  Trigram,
+}
+
+impl std::str::FromStr for Tokenizer {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, String> {
+    match s.to_ascii_lowercase().as_str() {
+      "unicode61" => Ok(Tokenizer::Unicode61),
+      "porter" => Ok(Tokenizer::Porter),
+      "trigram" => Ok(Tokenizer::Trigram),
+      _ => Err(format!("tokenizer must be unicode61, porter or trigram, not {:?}", s)),
+    }
+  }
+}
+
+/// Effective settings, with the layer each value came from. Served as-is by `/config`.
+#[derive(Serialize, Clone, Debug)]
+pub struct Config {
+  pub data_dir: PathBuf,
+  pub db_path: PathBuf,
+  pub bind: IpAddr,
+  /// The port actually listened on; differs from `requested_port` when that one was busy.
+  pub port: u16,
+  pub requested_port: u16,
+  pub config_file: Option<PathBuf>,
+  pub tokenizer: Tokenizer,
+  /// Fold accents so `cancion` finds `canción`.
+  pub remove_diacritics: bool,
+  /// Offline metadata for sources: Crossref work records in `crossref/`, PDFs to read in `pdf/`.
+  pub metadata_dir: PathBuf,
+  /// A `FixtureResolver` file consulted before anything else; for tests.
+  pub metadata_fixtures: Option<PathBuf>,
+  /// Days a deleted note stays in the trash before it is purged; 0 keeps it until purged by hand.
+  pub trash_retention_days: u32,
+  /// Largest file `/assets` accepts, in MiB.
+  pub max_upload_mb: u64,
+  pub sources: BTreeMap<&'static str, &'static str>,
+}
+
+#[derive(Debug)]
+pub struct ConfigError(pub String);
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "config: {}", self.0) }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Platform data directory: `%LOCALAPPDATA%\LevelNotes` on Windows (where existing installs live),
+/// `$XDG_DATA_HOME/LevelNotes` on Linux, `~/Library/Application Support/LevelNotes` on macOS.
+pub fn default_data_dir() -> PathBuf {
+  dirs::data_local_dir().unwrap_or_else(|| PathBuf::from(".")).join("LevelNotes")
+}
+
+pub fn default_config_file() -> Option<PathBuf> {
+  dirs::config_dir().map(|d| d.join("LevelNotes").join(CONFIG_FILE))
+}
+
+fn read_file_layer(path: &FsPath) -> Result<ConfigLayer, ConfigError> {
+  let text = fs::read_to_string(path).map_err(|e| ConfigError(format!("{}: {}", path.display(), e)))?;
+  toml::from_str(&text).map_err(|e| ConfigError(format!("{}: {}", path.display(), e)))
+}
+
+fn env_layer() -> Result<ConfigLayer, ConfigError> {
+  fn var<T: std::str::FromStr>(name: &str) -> Result<Option<T>, ConfigError> {
+    match std::env::var(name) {
+      Ok(v) if !v.trim().is_empty() => v.trim().parse().map(Some).map_err(|_| ConfigError(format!("{}={:?} is not valid", name, v))),
+      _ => Ok(None),
+    }
+  }
+  Ok(ConfigLayer {
+    data_dir: var("LEVELNOTES_DATA_DIR")?, bind: var("LEVELNOTES_BIND")?, port: var("LEVELNOTES_PORT")?,
+    tokenizer: var("LEVELNOTES_TOKENIZER")?, remove_diacritics: var("LEVELNOTES_REMOVE_DIACRITICS")?,
+    metadata_dir: var("LEVELNOTES_METADATA_DIR")?, metadata_fixtures: var("LEVELNOTES_METADATA_FIXTURES")?,
+    trash_retention_days: var("LEVELNOTES_TRASH_RETENTION_DAYS")?, max_upload_mb: var("LEVELNOTES_MAX_UPLOAD_MB")?,
+  })
+}
+
+impl Config {
+  /// Resolves settings from, lowest to highest precedence: built-in defaults, `levelnotes.toml`,
+  /// `LEVELNOTES_*` environment variables, then `cli`.
+  ///
+  /// The file is `config_file` if given, else `$LEVELNOTES_CONFIG`, else `levelnotes.toml` in the
+  /// platform config directory. An explicitly named file must exist; the default one is optional.
+  pub fn load(cli: ConfigLayer, config_file: Option<PathBuf>) -> Result<Config, ConfigError> {
+    let explicit = config_file.or_else(|| std::env::var_os("LEVELNOTES_CONFIG").map(PathBuf::from));
+    let (file, file_layer) = match explicit {
+      Some(path) => { let layer = read_file_layer(&path)?; (Some(path), layer) }
+      None => match default_config_file().filter(|p| p.is_file()) {
+        Some(path) => { let layer = read_file_layer(&path)?; (Some(path), layer) }
+        None => (None, ConfigLayer::default()),
+      },
+    };
+    let layers = [("cli", cli), ("env", env_layer()?), ("file", file_layer)];
+
+    let mut sources = BTreeMap::new();
+    let mut pick = |key: &'static str, get: &dyn Fn(&ConfigLayer) -> bool| {
+      let source = layers.iter().find(|(_, l)| get(l)).map(|(s, _)| *s).unwrap_or("default");
+      sources.insert(key, source);
+    };
+    pick("data_dir", &|l| l.data_dir.is_some());
+    pick("bind", &|l| l.bind.is_some());
+    pick("port", &|l| l.port.is_some());
+    pick("tokenizer", &|l| l.tokenizer.is_some());
+    pick("remove_diacritics", &|l| l.remove_diacritics.is_some());
+    pick("metadata_dir", &|l| l.metadata_dir.is_some());
+    pick("metadata_fixtures", &|l| l.metadata_fixtures.is_some());
+    pick("trash_retention_days", &|l| l.trash_retention_days.is_some());
+    pick("max_upload_mb", &|l| l.max_upload_mb.is_some());
+
+    let data_dir = layers.iter().find_map(|(_, l)| l.data_dir.clone()).unwrap_or_else(default_data_dir);
+    let bind = layers.iter().find_map(|(_, l)| l.bind).unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let port = layers.iter().find_map(|(_, l)| l.port).unwrap_or(DEFAULT_PORT);
+    let tokenizer = layers.iter().find_map(|(_, l)| l.tokenizer).unwrap_or(Tokenizer::Unicode61);
+    let remove_diacritics = layers.iter().find_map(|(_, l)| l.remove_diacritics).unwrap_or(true);
+    let metadata_dir = layers.iter().find_map(|(_, l)| l.metadata_dir.clone()).unwrap_or_else(|| data_dir.join("metadata"));
+    let metadata_fixtures = layers.iter().find_map(|(_, l)| l.metadata_fixtures.clone());
+    let trash_retention_days = layers.iter().find_map(|(_, l)| l.trash_retention_days).unwrap_or(DEFAULT_TRASH_RETENTION_DAYS);
+    let max_upload_mb = layers.iter().find_map(|(_, l)| l.max_upload_mb).unwrap_or(DEFAULT_MAX_UPLOAD_MB);
+    if max_upload_mb == 0 { return Err(ConfigError("max_upload_mb must be at least 1".into())); }
+    Ok(Config {
+      db_path: data_dir.join("levelnotes.db"), data_dir, bind, port, requested_port: port, config_file: file, tokenizer, remove_diacritics,
+      metadata_dir, metadata_fixtures, trash_retention_days, max_upload_mb, sources,
+    })
+  }
+
+  /// Binds `bind:port`; if the port is taken, tries the next `PORT_FALLBACK_TRIES`. Updates
+  /// `self.port` to the port actually bound. Port 0 means "let the OS pick".
+  pub fn bind_listener(&mut self) -> io::Result<TcpListener> {
+    let first = self.requested_port;
+    let tries = if first == 0 { 0 } else { PORT_FALLBACK_TRIES };
+    let candidates = (0..=tries).filter_map(|i| first.checked_add(i));
+    let mut last_err = None;
+    for port in candidates {
+      match TcpListener::bind(SocketAddr::new(self.bind, port)) {
+        Ok(listener) => {
+          listener.set_nonblocking(true)?;
+          self.port = listener.local_addr()?.port();
+          if self.port != first && first != 0 { println!("LevelNotes HTTP port {} is busy, using {}", first, self.port); }
+          return Ok(listener);
+        }
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => last_err = Some(e),
+        Err(e) => return Err(e),
+      }
+    }
+    let last = first.saturating_add(tries);
+    Err(io::Error::new(io::ErrorKind::AddrInUse, format!("ports {}-{} are all busy ({}); set another port",
+      first, last, last_err.map_or_else(|| "no free port".into(), |e| e.to_string()))))
+  }
+
+  /// Copies the notes an older build kept in `../.levelnotes` into the data directory, if that is the
+  /// default one and has no database yet. The old copy stays where it was. If both hold a database,
+  /// says where the old one is, as its notes won't show here.
+  pub fn adopt_legacy_data(&self) -> Result<(), ConfigError> {
+    let legacy = PathBuf::from(LEGACY_DATA_DIR);
+    let legacy_db = legacy.join("levelnotes.db");
+    if self.sources.get("data_dir") != Some(&"default") || !legacy_db.is_file() { return Ok(()); }
+    let legacy_db = legacy_db.canonicalize().unwrap_or(legacy_db);
+    if self.db_path.exists() {
+      println!("LevelNotes DB  notes from an older version are in {}; this version uses {}", legacy_db.display(), self.db_path.display());
+      return Ok(());
+    }
+    let fail = |e: &dyn fmt::Display| ConfigError(format!("copying notes from {}: {}", legacy.display(), e));
+    copy_files(&legacy, &self.data_dir).map_err(|e| fail(&e))?;
+    // Last, and through SQLite, so the copy has whatever was still in the old write-ahead log and a
+    // half-done copy is never mistaken for the database.
+    let part = self.db_path.with_extension("db.part");
+    let _ = fs::remove_file(&part);
+    rusqlite::Connection::open(&legacy_db)
+      .and_then(|db| db.execute("VACUUM INTO ?1", [part.to_string_lossy()]))
+      .map_err(|e| fail(&e))?;
+    fs::rename(&part, &self.db_path).map_err(|e| fail(&e))?;
+    println!("LevelNotes DB  copied notes from {} (left in place)", legacy_db.display());
+    Ok(())
+  }
+
+  pub fn base_url(&self) -> String {
+    let host = if self.bind.is_unspecified() { IpAddr::V4(Ipv4Addr::LOCALHOST) } else { self.bind };
+    format!("http://{}", SocketAddr::new(host, self.port))
+  }
+
+  fn advert_path(&self) -> PathBuf { self.data_dir.join("server.json") }
+
+  /// Writes `server.json` into the data directory so other local clients can find the API
+  /// even when it had to move off the default port.
+  pub fn advertise(&self) -> io::Result<()> {
+    fs::create_dir_all(&self.data_dir)?;
+    let body = serde_json::json!({ "url": self.base_url(), "port": self.port, "pid": std::process::id() });
+    fs::write(self.advert_path(), serde_json::to_vec_pretty(&body)?)
+  }
+
+  pub fn withdraw_advert(&self) { let _ = fs::remove_file(self.advert_path()); }
+}
+
+// Copies the files under `from` into `to`, except the database itself.
+fn copy_files(from: &FsPath, to: &FsPath) -> io::Result<()> {
+  fs::create_dir_all(to)?;
+  for entry in fs::read_dir(from)? {
+    let entry = entry?;
+    let (kind, name) = (entry.file_type()?, entry.file_name());
+    if name.to_string_lossy().starts_with("levelnotes.db") { continue; }
+    if kind.is_dir() { copy_files(&entry.path(), &to.join(&name))?; }
+    else if kind.is_file() && !to.join(&name).exists() { fs::copy(entry.path(), to.join(&name))?; }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+
+  // `Config::load` reads the process environment, which the tests here change.
+  static ENV: Mutex<()> = Mutex::new(());
+  const VARS: &[&str] = &["LEVELNOTES_PORT", "LEVELNOTES_TOKENIZER", "LEVELNOTES_CONFIG"];
+
+  fn temp_file(contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("levelnotes-test-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join(CONFIG_FILE), contents).unwrap();
+    dir.join(CONFIG_FILE)
+  }
+
+  #[test]
+  fn layers_cli_over_env_over_file_over_defaults() {
+    let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    let file = temp_file("port = 4000\ntokenizer = \"porter\"\ntrash_retention_days = 7\n");
+    std::env::set_var("LEVELNOTES_PORT", "5000");
+    std::env::set_var("LEVELNOTES_TOKENIZER", "Trigram");
+    let cli = ConfigLayer { port: Some(6000), data_dir: Some(file.with_file_name("data")), ..Default::default() };
+    let config = Config::load(cli, Some(file.clone())).unwrap();
+    assert_eq!((config.port, config.tokenizer, config.trash_retention_days, config.max_upload_mb, config.remove_diacritics),
+      (6000, Tokenizer::Trigram, 7, DEFAULT_MAX_UPLOAD_MB, true));
+    assert_eq!(config.db_path, file.with_file_name("data").join("levelnotes.db"));
+    let from = |key| config.sources[key];
+    assert_eq!([from("port"), from("tokenizer"), from("trash_retention_days"), from("max_upload_mb"), from("data_dir")], ["cli", "env", "file", "default", "cli"]);
+    assert_eq!(Config::load(ConfigLayer::default(), Some(file.clone())).unwrap().port, 5000);
+    std::env::remove_var("LEVELNOTES_PORT");
+    assert_eq!(Config::load(ConfigLayer::default(), Some(file.clone())).unwrap().port, 4000);
+
+    std::env::set_var("LEVELNOTES_TOKENIZER", "snowball");
+    assert!(Config::load(ConfigLayer::default(), Some(file.clone())).unwrap_err().0.contains("LEVELNOTES_TOKENIZER"));
+    for var in VARS { std::env::remove_var(var); }
+    fs::remove_dir_all(file.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn refuses_bad_files_and_limits() {
+    let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    for var in VARS { std::env::remove_var(var); }
+    let missing = std::env::temp_dir().join(format!("levelnotes-test-{}.toml", uuid::Uuid::new_v4()));
+    assert!(Config::load(ConfigLayer::default(), Some(missing)).is_err());
+    for contents in ["port = \"high\"", "colour = \"blue\"", "max_upload_mb = 0"] {
+      let file = temp_file(contents);
+      assert!(Config::load(ConfigLayer::default(), Some(file.clone())).is_err(), "{}", contents);
+      fs::remove_dir_all(file.parent().unwrap()).unwrap();
+    }
+  }
+
+  #[test]
+  fn moves_up_from_a_busy_port() {
+    let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    let busy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let taken = busy.local_addr().unwrap().port();
+    let file = temp_file("");
+    let mut config = Config::load(ConfigLayer { port: Some(taken), ..Default::default() }, Some(file.clone())).unwrap();
+    let listener = config.bind_listener().unwrap();
+    assert_eq!((config.requested_port, listener.local_addr().unwrap().port()), (taken, config.port));
+    assert!(config.port > taken && config.port <= taken.saturating_add(PORT_FALLBACK_TRIES), "{} after {}", config.port, taken);
+    assert_eq!(config.base_url(), format!("http://127.0.0.1:{}", config.port));
+
+    let mut config = Config::load(ConfigLayer { port: Some(0), ..Default::default() }, Some(file.clone())).unwrap();
+    config.bind_listener().unwrap();
+    assert_ne!(config.port, 0);
+    fs::remove_dir_all(file.parent().unwrap()).unwrap();
+  }
+}
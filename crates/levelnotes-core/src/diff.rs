@@ -0,0 +1,125 @@
+//! Word-level diffs of note text, and of note HTML with tags kept whole so a change never splits
+//! one. Both give up refining after `TIMEOUT` and fall back to a coarser but still correct diff.
+
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use similar::{capture_diff_slices_deadline, Algorithm, ChangeTag, TextDiff};
+
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A run of `text` that is the same on both sides (`equal`), or only in the newer (`insert`) or
+/// older (`delete`) one.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DiffOp { pub op: &'static str, pub text: String }
+
+fn tag_name(tag: ChangeTag) -> &'static str {
+  match tag { ChangeTag::Equal => "equal", ChangeTag::Insert => "insert", ChangeTag::Delete => "delete" }
+}
+
+// Appends to the last op when it is of the same kind, so callers see runs rather than tokens.
+fn push(ops: &mut Vec<DiffOp>, op: &'static str, text: &str) {
+  if text.is_empty() { return; }
+  match ops.last_mut() {
+    Some(last) if last.op == op => last.text.push_str(text),
+    _ => ops.push(DiffOp { op, text: text.to_string() }),
+  }
+}
+
+/// Word-by-word diff of two texts.
+pub fn text(old: &str, new: &str) -> Vec<DiffOp> {
+  let diff = TextDiff::configure().algorithm(Algorithm::Myers).timeout(TIMEOUT).diff_words(old, new);
+  let mut ops = Vec::new();
+  for change in diff.iter_all_changes() { push(&mut ops, tag_name(change.tag()), change.value()); }
+  ops
+}
+
+// Tags (and comments), runs of whitespace, and words, in order; concatenated they give back `html`.
+fn html_tokens(html: &str) -> Vec<&str> {
+  let mut tokens = Vec::new();
+  let mut i = 0;
+  while i < html.len() {
+    let rest = &html[i..];
+    let len = if rest.starts_with("<!--") {
+      rest.find("-->").map(|e| e + 3).unwrap_or(rest.len())
+    } else if rest.starts_with('<') && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!') {
+      rest.find('>').map(|e| e + 1).unwrap_or(rest.len())
+    } else {
+      let space = rest.starts_with(char::is_whitespace);
+      // From the second character on, so a stray `<` (as in `1 < 2`) is a token of its own.
+      rest.char_indices().skip(1).find(|&(_, c)| c == '<' || c.is_whitespace() != space).map_or(rest.len(), |(e, _)| e)
+    };
+    tokens.push(&rest[..len]);
+    i += len;
+  }
+  tokens
+}
+
+fn is_tag(token: &str) -> bool { token.starts_with('<') && token.len() > 1 }
+
+// Wraps the text tokens of `slice` in `<wrap>`, closing it around any tag; tags are kept only if `keep_tags`.
+fn mark(out: &mut String, slice: &[&str], wrap: &str, keep_tags: bool) {
+  let mut open = false;
+  for token in slice {
+    if is_tag(token) {
+      if open { out.push_str(&format!("</{}>", wrap)); open = false; }
+      if keep_tags { out.push_str(token); }
+    } else {
+      if !open { out.push_str(&format!("<{}>", wrap)); open = true; }
+      out.push_str(token);
+    }
+  }
+  if open { out.push_str(&format!("</{}>", wrap)); }
+}
+
+/// Diff of two HTML fragments in which every tag is one token. Also returns the newer HTML with
+/// inserted text wrapped in `<ins>` and deleted text put back in `<del>`; deleted tags are dropped
+/// so the markup stays that of the newer version.
+pub fn html(old: &str, new: &str) -> (Vec<DiffOp>, String) {
+  let (old_tokens, new_tokens) = (html_tokens(old), html_tokens(new));
+  let mut ops = Vec::new();
+  let mut marked = String::with_capacity(new.len());
+  for op in capture_diff_slices_deadline(Algorithm::Myers, &old_tokens, &new_tokens, Some(Instant::now() + TIMEOUT)) {
+    for (tag, slice) in op.iter_slices(&old_tokens, &new_tokens) {
+      for token in slice { push(&mut ops, tag_name(tag), token); }
+      match tag {
+        ChangeTag::Equal => slice.iter().for_each(|t| marked.push_str(t)),
+        ChangeTag::Insert => mark(&mut marked, slice, "ins", true),
+        ChangeTag::Delete => mark(&mut marked, slice, "del", false),
+      }
+    }
+  }
+  (ops, marked)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ops(list: &[(&'static str, &str)]) -> Vec<DiffOp> { list.iter().map(|&(op, text)| DiffOp { op, text: text.to_string() }).collect() }
+
+  #[test]
+  fn diffs_text_word_by_word() {
+    assert_eq!(text("the quick brown fox", "the slow brown fox"),
+      ops(&[("equal", "the "), ("delete", "quick"), ("insert", "slow"), ("equal", " brown fox")]));
+    assert_eq!(text("", "new words"), ops(&[("insert", "new words")]));
+    assert!(text("same", "same").iter().all(|o| o.op == "equal"));
+  }
+
+  #[test]
+  fn splits_html_into_whole_tags_and_words() {
+    let html = "<p class=\"a b\">Hi  there<!-- c > d --></p> 1 < 2";
+    let tokens = html_tokens(html);
+    assert_eq!(tokens, ["<p class=\"a b\">", "Hi", "  ", "there", "<!-- c > d -->", "</p>", " ", "1", " ", "<", " ", "2"]);
+    assert_eq!(tokens.concat(), html);
+  }
+
+  #[test]
+  fn marks_html_changes_without_breaking_tags() {
+    let (ops_, marked) = html("<p>the quick fox</p>", "<p>the <b>slow</b> fox</p>");
+    assert_eq!(marked, "<p>the <del>quick</del><b><ins>slow</ins></b> fox</p>");
+    assert!(ops_.iter().any(|o| o.op == "insert" && o.text == "<b>slow</b>"), "{:?}", ops_);
+    // Deleted tags go, so the result has the newer version's markup.
+    let (_, marked) = html("<p>keep <i>this</i> too</p>", "<p>keep too</p>");
+    assert_eq!(marked, "<p>keep <del>this</del><del> </del>too</p>");
+  }
+}
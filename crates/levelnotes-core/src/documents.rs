@@ -0,0 +1,168 @@
+//! Whole PDFs that clips were taken from. A document is a PDF asset plus what the file says about
+//! itself; a clip naming it with `source.documentId` keeps its `ops.page` and `ops.highlights`, so
+//! the note can reopen the PDF where the passage was. Documents stay stored after their notes go.
+
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+use crate::{assets, error::{ApiError, ApiResult}, model::{Document, DocumentClip, Rect}, resolve, store::NoteStore};
+
+const COLUMNS: &str = "d.id, d.hash, d.filename, d.title, d.page_count, a.size, a.path, d.created_at";
+
+fn from_row(r: &rusqlite::Row) -> rusqlite::Result<Document> {
+  Ok(Document {
+    id: r.get(0)?, hash: r.get(1)?, filename: r.get(2)?, title: r.get(3)?,
+    page_count: r.get(4)?, size: r.get(5)?, path: r.get(6)?, created_at: r.get(7)?,
+  })
+}
+
+/// Document `id`, if there is one.
+pub(crate) fn load(db: &Connection, id: &str) -> rusqlite::Result<Option<Document>> {
+  db.query_row(&format!("SELECT {COLUMNS} FROM documents d JOIN assets a ON a.hash = d.hash WHERE d.id=?1"), params![id], from_row).optional()
+}
+
+/// Checks that a clip from document `id` can be kept: the document exists, and `page` (1-based)
+/// is one of its pages when both are known.
+pub(crate) fn ensure_clip(db: &Connection, id: &str, page: Option<i32>) -> ApiResult<()> {
+  let doc = load(db, id)?.ok_or_else(|| ApiError::NotFound(format!("document {} not found", id)))?;
+  match (page, doc.page_count) {
+    (Some(p), Some(count)) if p < 1 || p as u32 > count =>
+      Err(ApiError::Validation(format!("page {} is not in document {}, which has {} pages", p, id, count))),
+    _ => Ok(()),
+  }
+}
+
+/// Records that note `note_id` has a clip from page `page` of document `id`, after its other clips.
+pub(crate) fn record_clip(db: &Connection, note_id: &str, id: &str, page: Option<i32>, highlights_json: &str, now: &str) -> rusqlite::Result<()> {
+  db.execute(
+    "INSERT INTO document_clips (note_id, position, document_id, page_number, highlights_json, created_at)
+     VALUES (?1, (SELECT COALESCE(MAX(position) + 1, 0) FROM document_clips WHERE note_id=?1), ?2, ?3, ?4, ?5)",
+    params![note_id, id, page, highlights_json, now])?;
+  Ok(())
+}
+
+/// The document clips of note `note_id`, in order.
+pub(crate) fn clips_for_note(db: &Connection, note_id: &str) -> rusqlite::Result<Vec<DocumentClip>> {
+  let mut stmt = db.prepare(&format!(
+    "SELECT {COLUMNS}, c.page_number, c.highlights_json, c.position, c.created_at
+     FROM document_clips c JOIN documents d ON d.id = c.document_id JOIN assets a ON a.hash = d.hash
+     WHERE c.note_id=?1 ORDER BY c.position"))?;
+  let rows = stmt.query_map(params![note_id], |r| {
+    let highlights: Option<String> = r.get(9)?;
+    Ok(DocumentClip {
+      document: from_row(r)?, page_number: r.get(8)?,
+      highlights: highlights.and_then(|j| serde_json::from_str::<Vec<Rect>>(&j).ok()).unwrap_or_default(),
+      position: r.get(10)?, created_at: r.get(11)?,
+    })
+  })?.collect();
+  rows
+}
+
+impl NoteStore {
+  /// Makes the stored asset `hash` a document, reading its title and page count from the file.
+  /// The same PDF registered again returns the document it already is; `filename` is only kept
+  /// the first time.
+  pub fn add_document(&self, hash: &str, filename: Option<&str>) -> ApiResult<Document> {
+    let hash = hash.trim().to_ascii_lowercase();
+    let asset = assets::find(&*self.conn()?, &hash)?
+      .ok_or_else(|| ApiError::NotFound(format!("asset {} not found; upload it to /assets first", hash)))?;
+    if asset.mime != "application/pdf" {
+      return Err(ApiError::Validation(format!("asset {} is {}, not a PDF", hash, asset.mime)));
+    }
+    // Read without the lock; PDFs can be large.
+    let (bytes, _) = self.assets().read(&asset.path)?;
+    let title = resolve::pdf_metadata(&bytes).and_then(|m| m.title);
+    let page_count = resolve::pdf_page_count(&bytes);
+    let filename = filename.map(str::trim).filter(|f| !f.is_empty());
+
+    let db = self.conn()?;
+    // Purged while it was being read.
+    if assets::find(&db, &hash)?.is_none() { return Err(ApiError::NotFound(format!("asset {} not found", hash))); }
+    db.execute(
+      "INSERT INTO documents (id, hash, filename, page_count, title, created_at) VALUES (?1,?2,?3,?4,?5,?6) ON CONFLICT(hash) DO NOTHING",
+      params![Uuid::new_v4().to_string(), hash, filename, page_count, title, Utc::now().to_rfc3339()])?;
+    db.query_row(&format!("SELECT {COLUMNS} FROM documents d JOIN assets a ON a.hash = d.hash WHERE d.hash=?1"), params![hash], from_row)
+      .optional()?.ok_or_else(|| ApiError::NotFound(format!("asset {} not found", hash)))
+  }
+
+  pub fn document(&self, id: &str) -> ApiResult<Document> {
+    load(&*self.conn()?, id)?.ok_or_else(|| ApiError::NotFound(format!("document {} not found", id)))
+  }
+
+  /// Every document, newest first.
+  pub fn documents(&self) -> ApiResult<Vec<Document>> {
+    let db = self.conn()?;
+    let mut stmt = db.prepare(&format!("SELECT {COLUMNS} FROM documents d JOIN assets a ON a.hash = d.hash ORDER BY d.created_at DESC"))?;
+    let rows = stmt.query_map([], from_row)?.collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{conditional::Precondition, store::tests::{clip, temp_store}};
+
+  const PDF: &[u8] = b"%PDF-1.4
+1 0 obj << /Title (Three Pages) >> endobj
+2 0 obj << /Type /Pages /Kids [] /Count 3 >> endobj
+%%EOF";
+
+  fn upload(store: &NoteStore, bytes: &[u8]) -> String {
+    let mut staged = store.assets().stage(1 << 20).unwrap();
+    staged.write(bytes).unwrap();
+    store.add_staged(staged).unwrap().hash
+  }
+
+  fn on_page(doc: &str, page: i32) -> serde_json::Value {
+    serde_json::json!({ "source": { "kind": "pdf", "documentId": doc }, "selection": { "text": format!("page {}", page) }, "ops": { "page": page } })
+  }
+
+  #[test]
+  fn registers_a_pdf_once() {
+    let (dir, store) = temp_store();
+    let hash = upload(&store, PDF);
+    let doc = store.add_document(&hash.to_uppercase(), Some(" paper.pdf ")).unwrap();
+    assert_eq!((doc.filename.as_deref(), doc.title.as_deref(), doc.page_count), (Some("paper.pdf"), Some("Three Pages"), Some(3)));
+    let again = store.add_document(&hash, Some("renamed.pdf")).unwrap();
+    assert_eq!((again.id.as_str(), again.filename.as_deref()), (doc.id.as_str(), Some("paper.pdf")));
+    let png = upload(&store, b"\x89PNG\r\n\x1a\nimage");
+    assert!(matches!(store.add_document(&png, None), Err(ApiError::Validation(_))));
+    assert!(matches!(store.add_document("0000", None), Err(ApiError::NotFound(_))));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn keeps_clips_only_from_pages_the_document_has() {
+    let (dir, store) = temp_store();
+    let doc = store.add_document(&upload(&store, PDF), None).unwrap().id;
+    let db = store.conn().unwrap();
+    for page in [Some(1), Some(3), None] { ensure_clip(&db, &doc, page).unwrap(); }
+    for page in [0, 4, -1] {
+      match ensure_clip(&db, &doc, Some(page)) {
+        Err(ApiError::Validation(m)) => assert_eq!(m, format!("page {} is not in document {}, which has 3 pages", page, doc)),
+        other => panic!("page {}: {:?}", page, other),
+      }
+    }
+    assert!(matches!(ensure_clip(&db, "nope", Some(1)), Err(ApiError::NotFound(_))));
+    // A PDF whose page count couldn't be read takes any page.
+    drop(db);
+    let unknown = store.add_document(&upload(&store, b"%PDF-1.7 compressed"), None).unwrap().id;
+    ensure_clip(&store.conn().unwrap(), &unknown, Some(40)).unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn links_each_clip_to_its_page() {
+    let (dir, store) = temp_store();
+    let doc = store.add_document(&upload(&store, PDF), None).unwrap().id;
+    assert!(matches!(store.create(&clip(on_page(&doc, 4))), Err(ApiError::Validation(_))));
+    let id = store.create(&clip(on_page(&doc, 2))).unwrap();
+    assert!(matches!(store.append(&id, &clip(on_page(&doc, 9)), &Precondition::default()), Err(ApiError::Validation(_))));
+    store.append(&id, &clip(on_page(&doc, 3)), &Precondition::default()).unwrap();
+    let note = store.get(&id).unwrap();
+    assert_eq!(note.clips.iter().map(|c| (c.position, c.page_number, c.document.id.as_str())).collect::<Vec<_>>(), [(0, Some(2), doc.as_str()), (1, Some(3), doc.as_str())]);
+    assert_eq!((note.page_number, note.document.map(|d| d.id)), (Some(2), Some(doc)));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,139 @@
+use std::{fmt, sync::PoisonError};
+use axum::{extract::{multipart::{MultipartError, MultipartRejection}, rejection::{JsonRejection, QueryRejection}}, http::StatusCode, response::{IntoResponse, Response}, Json};
+use rusqlite::ErrorCode;
+use serde::Serialize;
+use crate::model::NoteDetail;
+
+// Every handler in build_router returns Result<_, ApiError>, so clients always get
+// `{ok:false, code, message}` with a matching status instead of a dropped connection.
+#[derive(Debug)]
+pub enum ApiError {
+  NotFound(String),
+  Unauthorized(String),
+  Validation(String),
+  Conflict(String),
+  /// An `If-Match`/`If-Unmodified-Since` write whose note has changed since the client read it.
+  PreconditionFailed(String),
+  /// An update made against an older `version` of the note; carries the note as it is now.
+  VersionConflict(String, Box<NoteDetail>),
+  /// An upload over the configured size limit.
+  PayloadTooLarge(String),
+  Storage(String),
+  Io(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+  ok: bool, code: &'static str, message: String,
+  #[serde(skip_serializing_if = "Option::is_none")] current: Option<Box<NoteDetail>>,
+}
+
+impl ApiError {
+  pub fn status(&self) -> StatusCode {
+    match self {
+      ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+      ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+      ApiError::Validation(_) => StatusCode::BAD_REQUEST,
+      ApiError::Conflict(_) | ApiError::VersionConflict(..) => StatusCode::CONFLICT,
+      ApiError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+      ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+      ApiError::Storage(_) | ApiError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+
+  pub fn code(&self) -> &'static str {
+    match self {
+      ApiError::NotFound(_) => "not_found",
+      ApiError::Unauthorized(_) => "unauthorized",
+      ApiError::Validation(_) => "validation",
+      ApiError::Conflict(_) => "conflict",
+      ApiError::PreconditionFailed(_) => "precondition_failed",
+      ApiError::VersionConflict(..) => "version_conflict",
+      ApiError::PayloadTooLarge(_) => "payload_too_large",
+      ApiError::Storage(_) => "storage",
+      ApiError::Io(_) => "io",
+    }
+  }
+
+  pub fn message(&self) -> &str {
+    match self {
+      ApiError::NotFound(m) | ApiError::Unauthorized(m) | ApiError::Validation(m) | ApiError::Conflict(m) | ApiError::PreconditionFailed(m) | ApiError::Storage(m) | ApiError::Io(m)
+        | ApiError::VersionConflict(m, _) | ApiError::PayloadTooLarge(m) => m,
+    }
+  }
+}
+
+impl fmt::Display for ApiError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}: {}", self.code(), self.message()) }
+}
+
+impl std::error::Error for ApiError {}
+
+impl IntoResponse for ApiError {
+  fn into_response(self) -> Response {
+    if self.status().is_server_error() { eprintln!("LevelNotes HTTP {}", self); }
+    let (status, code) = (self.status(), self.code());
+    let (message, current) = match self { ApiError::VersionConflict(m, note) => (m, Some(note)), other => (other.message().to_string(), None) };
+    let body = ErrorBody { ok: false, code, message, current };
+    (status, Json(body)).into_response()
+  }
+}
+
+// A missing row is only a 404 where the caller knows what was asked for, so callers map it with
+// `.optional()` and name the note, revision or document themselves; one reaching here is a bug.
+impl From<rusqlite::Error> for ApiError {
+  fn from(e: rusqlite::Error) -> Self {
+    match &e {
+      rusqlite::Error::SqliteFailure(f, _) if f.code == ErrorCode::ConstraintViolation => ApiError::Conflict(e.to_string()),
+      _ => ApiError::Storage(e.to_string()),
+    }
+  }
+}
+
+impl From<std::io::Error> for ApiError {
+  fn from(e: std::io::Error) -> Self { ApiError::Io(e.to_string()) }
+}
+
+impl<T> From<PoisonError<T>> for ApiError {
+  fn from(_: PoisonError<T>) -> Self { ApiError::Storage("database lock poisoned by an earlier failure".into()) }
+}
+
+impl From<JsonRejection> for ApiError {
+  fn from(e: JsonRejection) -> Self { ApiError::Validation(e.body_text()) }
+}
+
+impl From<QueryRejection> for ApiError {
+  fn from(e: QueryRejection) -> Self { ApiError::Validation(e.body_text()) }
+}
+
+impl From<MultipartRejection> for ApiError {
+  fn from(e: MultipartRejection) -> Self { ApiError::Validation(e.body_text()) }
+}
+
+// A body over the route's limit surfaces here, as a 413.
+impl From<MultipartError> for ApiError {
+  fn from(e: MultipartError) -> Self {
+    if e.status() == StatusCode::PAYLOAD_TOO_LARGE { ApiError::PayloadTooLarge("request body is larger than the upload limit".into()) } else { ApiError::Validation(e.body_text()) }
+  }
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::tests::{clip, temp_store};
+
+  #[test]
+  fn names_what_was_not_found() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(serde_json::json!({ "selection": { "text": "body" } }))).unwrap();
+    let missing = |r: ApiResult<()>| match r { Err(ApiError::NotFound(m)) => m, other => panic!("expected 404, got {:?}", other) };
+    assert_eq!(missing(store.get("nope").map(drop)), "note nope not found");
+    assert_eq!(missing(store.revision(&id, 999).map(drop)), format!("revision 999 of note {} not found", id));
+    assert_eq!(missing(store.document("nope").map(drop)), "document nope not found");
+    // A row a query was sure of but didn't get is a server error, not someone else's 404.
+    assert_eq!(ApiError::from(rusqlite::Error::QueryReturnedNoRows).status(), StatusCode::INTERNAL_SERVER_ERROR);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,370 @@
+use crate::{error::{ApiError, ApiResult}, listing::MAX_PAGE, model::*, store::NoteStore};
+
+pub fn sanitize_filename(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  for ch in s.chars().take(60) {
+    if ch.is_ascii_alphanumeric() || ch=='-'||ch=='_'||ch==' ' { out.push(ch); } else { out.push('-'); }
+  }
+  let t = out.trim();
+  if t.is_empty() { "note".into() } else { t.into() }
+}
+
+pub fn to_markdown(note: &NoteDetail) -> String {
+  let mut md=String::new();
+  md.push_str(&format!("# {}\n\n", note.title));
+  md.push_str(&format!("- **Created:** {}\n", note.created_at));
+  if let Some(u)=&note.source_url { md.push_str(&format!("- **Source:** {}\n", u)); }
+  if !note.tags.is_empty(){ md.push_str("- **Tags:** "); md.push_str(&note.tags.iter().map(|t|format!("#{}",t)).collect::<Vec<_>>().join(" ")); md.push('\n'); }
+  md.push('\n');
+  if let Some(pt)=&note.plaintext { md.push_str("## Clip (plaintext)\n\n"); md.push_str(pt); md.push_str("\n\n"); }
+  if let Some(h)=&note.html { md.push_str("## Clip (HTML)\n\n```html\n"); md.push_str(h); md.push_str("\n```\n"); }
+  md
+}
+
+/// What `/export/:id.<ext>` and `/citations?format=` can produce.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format { Markdown, BibTex, Ris, CslJson }
+
+impl Format {
+  // Longest suffix first: `.csl.json` must win over a plain `.json`.
+  const SUFFIXES: &'static [(&'static str, Format)] =
+    &[(".csl.json", Format::CslJson), (".bib", Format::BibTex), (".ris", Format::Ris), (".md", Format::Markdown)];
+
+  /// Splits `{id}.{ext}` into the id and the format its extension names.
+  pub fn split_file(file: &str) -> Option<(&str, Format)> {
+    Self::SUFFIXES.iter().find_map(|(suffix, f)| file.strip_suffix(suffix).filter(|id| !id.is_empty()).map(|id| (id, *f)))
+  }
+
+  /// A citation format by name (`bib`, `ris` or `csl.json`), as `/citations?format=` takes it.
+  pub fn citation(name: &str) -> ApiResult<Format> {
+    match name.trim_start_matches('.') {
+      "bib" | "bibtex" => Ok(Format::BibTex),
+      "ris" => Ok(Format::Ris),
+      "csl.json" | "csl" | "json" => Ok(Format::CslJson),
+      other => Err(ApiError::Validation(format!("format must be bib, ris or csl.json, not {:?}", other))),
+    }
+  }
+
+  pub fn extension(self) -> &'static str {
+    match self { Format::Markdown => "md", Format::BibTex => "bib", Format::Ris => "ris", Format::CslJson => "csl.json" }
+  }
+
+  pub fn content_type(self) -> &'static str {
+    match self {
+      Format::Markdown => "text/markdown; charset=utf-8",
+      Format::BibTex => "application/x-bibtex; charset=utf-8",
+      Format::Ris => "application/x-research-info-systems; charset=utf-8",
+      Format::CslJson => "application/vnd.citationstyles.csl+json; charset=utf-8",
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind { Article, Book, Webpage, Document }
+
+struct Name { family: String, given: Option<String> }
+
+// "Family, Given" or "Given Family"; a single word is taken as the family name.
+fn parse_name(s: &str) -> Option<Name> {
+  let s = s.trim();
+  if s.is_empty() { return None; }
+  if let Some((family, given)) = s.split_once(',') {
+    let given = given.trim();
+    return Some(Name { family: family.trim().to_string(), given: (!given.is_empty()).then(|| given.to_string()) });
+  }
+  Some(match s.rsplit_once(char::is_whitespace) {
+    Some((given, family)) => Name { family: family.to_string(), given: Some(given.trim().to_string()) },
+    None => Name { family: s.to_string(), given: None },
+  })
+}
+
+// Leading `YYYY[-MM[-DD]]` of a date string (RFC 3339 timestamps included).
+fn date_parts(s: &str) -> Option<Vec<u32>> {
+  let parts: Vec<u32> = s.trim().get(..s.trim().len().min(10))?.split('-').map_while(|p| p.parse().ok()).take(3).collect();
+  (parts.first().is_some_and(|y| (1000..=9999).contains(y))).then_some(parts)
+}
+
+// A metadata value as text; numbers count, so `"year": 2020` works.
+fn meta_text(meta: &serde_json::Map<String, serde_json::Value>, keys: &[&str]) -> Option<String> {
+  keys.iter().filter_map(|k| meta.get(*k)).find_map(|v| match v {
+    serde_json::Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+    serde_json::Value::Number(n) => Some(n.to_string()),
+    _ => None,
+  })
+}
+
+/// One citable note, with everything the formats need worked out once. `locator` is the page the
+/// clip came from; it is not the work's page range.
+struct Cite {
+  key: String, kind: Kind, title: String, authors: Vec<Name>,
+  issued: Option<Vec<u32>>, accessed: Option<Vec<u32>>, container: Option<String>,
+  doi: Option<String>, isbn: Option<String>, url: Option<String>, locator: Option<i32>,
+}
+
+impl Cite {
+  fn new(note: &NoteDetail) -> Option<Cite> {
+    let source = note.source.as_ref()?;
+    let meta = &source.metadata;
+    let kind = match (source.doi.is_some(), source.isbn.is_some(), source.kind.as_str()) {
+      (true, _, _) => Kind::Article,
+      (false, true, _) => Kind::Book,
+      (false, false, "web") => Kind::Webpage,
+      _ => Kind::Document,
+    };
+    let authors: Vec<Name> = source.author.as_deref().unwrap_or("").split(';').filter_map(parse_name).collect();
+    // A resolved year is authoritative, but a full date from the page is more precise when they agree.
+    let issued = meta_text(meta, &["issued", "datePublished", "publishedDate", "published", "date", "year"]).as_deref().and_then(date_parts)
+      .filter(|d| source.year.is_none_or(|y| d[0] as i32 == y))
+      .or_else(|| source.year.map(|y| vec![y as u32]));
+    let title = source.title.clone().unwrap_or_else(|| note.title.clone());
+    let author_key: String = authors.first().map(|a| a.family.as_str()).unwrap_or("").chars().filter(char::is_ascii_alphanumeric).collect();
+    let word_key: String = title.split_whitespace().find(|w| w.chars().filter(char::is_ascii_alphanumeric).count() > 3)
+      .map(|w| w.chars().filter(char::is_ascii_alphanumeric).collect()).unwrap_or_default();
+    let year_key = issued.as_ref().map(|d| d[0].to_string()).unwrap_or_default();
+    let key = if author_key.is_empty() && word_key.is_empty() { format!("note{}", note.id.chars().filter(char::is_ascii_alphanumeric).take(8).collect::<String>()) }
+      else { format!("{}{}{}", author_key, year_key, word_key).to_lowercase() };
+    Some(Cite {
+      key, kind, title, authors, issued,
+      accessed: date_parts(&source.accessed_at),
+      container: source.venue.clone().or_else(|| meta_text(meta, &["containerTitle", "container_title", "journal", "venue"])).or_else(|| source.site_name.clone()),
+      doi: source.doi.clone(),
+      isbn: source.isbn.clone(),
+      url: source.url.clone().filter(|u| u.contains("://")),
+      locator: None,
+    })
+  }
+
+  fn date(parts: &[u32]) -> String { parts.iter().enumerate().map(|(i, p)| if i == 0 { p.to_string() } else { format!("{:02}", p) }).collect::<Vec<_>>().join("-") }
+}
+
+// Escapes BibTeX's special characters; URLs and DOIs go through `bib_verbatim` instead.
+fn bib_text(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '\\' => out.push_str("\\textbackslash{}"),
+      '{' | '}' | '&' | '%' | '$' | '#' | '_' => { out.push('\\'); out.push(c); }
+      '~' => out.push_str("\\textasciitilde{}"),
+      '^' => out.push_str("\\textasciicircum{}"),
+      '\n' | '\r' => out.push(' '),
+      _ => out.push(c),
+    }
+  }
+  out
+}
+
+fn bib_verbatim(s: &str) -> String { s.chars().filter(|c| !matches!(c, '{' | '}' | '\n' | '\r')).collect() }
+
+fn bibtex(c: &Cite) -> String {
+  let entry = match c.kind { Kind::Article => "article", Kind::Book => "book", Kind::Webpage => "online", Kind::Document => "misc" };
+  let mut fields: Vec<(&str, String)> = vec![("title", format!("{{{}}}", bib_text(&c.title)))];
+  if !c.authors.is_empty() {
+    let names = c.authors.iter().map(|a| match &a.given { Some(g) => format!("{}, {}", bib_text(&a.family), bib_text(g)), None => format!("{{{}}}", bib_text(&a.family)) });
+    fields.push(("author", names.collect::<Vec<_>>().join(" and ")));
+  }
+  if let Some(d) = &c.issued { fields.push(("year", d[0].to_string())); }
+  if let Some(container) = &c.container {
+    fields.push((match c.kind { Kind::Article => "journal", Kind::Book => "publisher", _ => "organization" }, bib_text(container)));
+  }
+  if let Some(doi) = &c.doi { fields.push(("doi", bib_verbatim(doi))); }
+  if let Some(isbn) = &c.isbn { fields.push(("isbn", isbn.clone())); }
+  if let Some(url) = &c.url { fields.push(("url", bib_verbatim(url))); }
+  if let Some(d) = &c.accessed { fields.push(("urldate", Cite::date(d))); }
+  if let Some(p) = c.locator { fields.push(("note", format!("p. {}", p))); }
+  let body = fields.iter().map(|(k, v)| format!("  {} = {{{}}}", k, v)).collect::<Vec<_>>().join(",\n");
+  format!("@{}{{{},\n{}\n}}\n", entry, c.key, body)
+}
+
+fn ris(c: &Cite) -> String {
+  let one_line = |s: &str| s.replace(['\r', '\n'], " ");
+  let mut lines = vec![("TY", match c.kind { Kind::Article => "JOUR", Kind::Book => "BOOK", Kind::Webpage => "ELEC", Kind::Document => "GEN" }.to_string())];
+  lines.push(("TI", one_line(&c.title)));
+  for a in &c.authors { lines.push(("AU", match &a.given { Some(g) => format!("{}, {}", a.family, g), None => a.family.clone() })); }
+  if let Some(d) = &c.issued { lines.push(("PY", d[0].to_string())); }
+  if let Some(container) = &c.container { lines.push((if c.kind == Kind::Article { "JO" } else { "PB" }, one_line(container))); }
+  if let Some(doi) = &c.doi { lines.push(("DO", doi.clone())); }
+  if let Some(isbn) = &c.isbn { lines.push(("SN", isbn.clone())); }
+  if let Some(url) = &c.url { lines.push(("UR", url.clone())); }
+  if let Some(d) = &c.accessed { lines.push(("Y2", Cite::date(d))); }
+  if let Some(p) = c.locator { lines.push(("N1", format!("p. {}", p))); }
+  lines.push(("ID", c.key.clone()));
+  let mut out: String = lines.iter().map(|(tag, v)| format!("{}  - {}\r\n", tag, v)).collect();
+  out.push_str("ER  - \r\n");
+  out
+}
+
+fn csl(c: &Cite) -> serde_json::Value {
+  let mut item = serde_json::json!({
+    "id": c.key,
+    "type": match c.kind { Kind::Article => "article-journal", Kind::Book => "book", Kind::Webpage => "webpage", Kind::Document => "document" },
+    "title": c.title,
+  });
+  let obj = item.as_object_mut().unwrap();
+  if !c.authors.is_empty() {
+    obj.insert("author".into(), c.authors.iter().map(|a| match &a.given {
+      Some(g) => serde_json::json!({ "family": a.family, "given": g }),
+      None => serde_json::json!({ "family": a.family }),
+    }).collect());
+  }
+  if let Some(d) = &c.issued { obj.insert("issued".into(), serde_json::json!({ "date-parts": [d] })); }
+  if let Some(d) = &c.accessed { obj.insert("accessed".into(), serde_json::json!({ "date-parts": [d] })); }
+  if let Some(container) = &c.container { obj.insert((if c.kind == Kind::Book { "publisher" } else { "container-title" }).into(), container.clone().into()); }
+  if let Some(doi) = &c.doi { obj.insert("DOI".into(), doi.clone().into()); }
+  if let Some(isbn) = &c.isbn { obj.insert("ISBN".into(), isbn.clone().into()); }
+  if let Some(url) = &c.url { obj.insert("URL".into(), url.clone().into()); }
+  if let Some(p) = c.locator {
+    obj.insert("locator".into(), p.to_string().into());
+    obj.insert("label".into(), "page".into());
+  }
+  item
+}
+
+// The pages a note's clips came from: each document clip's, or the note's own when it has none.
+fn pages(note: &NoteDetail) -> Vec<Option<i32>> {
+  if note.clips.is_empty() { vec![note.page_number] } else { note.clips.iter().map(|c| c.page_number).collect() }
+}
+
+/// `notes` in `format`, as a bibliography. For citation formats only notes with a source yield an
+/// entry, one per page clipped from it (clips of the same source and page collapse into one), and
+/// clashing keys get `a`, `b`, ... appended.
+pub fn render(notes: &[NoteDetail], format: Format) -> String {
+  if format == Format::Markdown { return notes.iter().map(to_markdown).collect::<Vec<_>>().join("\n---\n\n"); }
+  let mut seen = std::collections::HashSet::new();
+  let mut keys = std::collections::HashMap::<String, usize>::new();
+  let cites: Vec<Cite> = notes.iter()
+    .flat_map(|n| pages(n).into_iter().map(move |p| (n, p)))
+    .filter(|(n, p)| n.source.as_ref().is_some_and(|s| seen.insert((s.id.clone(), *p))))
+    .filter_map(|(n, p)| Cite::new(n).map(|c| Cite { locator: p, ..c }))
+    .map(|mut c| {
+      let n = keys.entry(c.key.clone()).or_insert(0);
+      if *n > 0 { c.key.push((b'a' + ((*n - 1) % 26) as u8) as char); }
+      *n += 1;
+      c
+    }).collect();
+  render_cites(&cites, format)
+}
+
+/// One note in `format`. A citation names the page the clip came from, if it has one, with an
+/// entry per page when the note's clips came from several.
+pub fn render_note(note: &NoteDetail, format: Format) -> String { render(std::slice::from_ref(note), format) }
+
+fn render_cites(cites: &[Cite], format: Format) -> String {
+  match format {
+    Format::BibTex => cites.iter().map(bibtex).collect::<Vec<_>>().join("\n"),
+    Format::Ris => cites.iter().map(ris).collect::<Vec<_>>().join("\r\n"),
+    Format::CslJson | Format::Markdown => serde_json::to_string_pretty(&cites.iter().map(csl).collect::<Vec<_>>()).unwrap(),
+  }
+}
+
+impl NoteStore {
+  /// The notes `/citations` covers: those listed in `ids`, then those tagged `tag`, each once.
+  /// Notes in the trash are left out.
+  pub fn notes_for_export(&self, query: &CitationQuery) -> ApiResult<Vec<NoteDetail>> {
+    let mut ids: Vec<String> = query.ids.iter().flat_map(|s| s.split(',')).map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect();
+    if let Some(tag) = query.tag.as_deref().filter(|t| !t.trim().is_empty()) {
+      let mut page = ListQuery { tag: Some(tag.to_string()), sort: Some("created".into()), order: Some("asc".into()), limit: Some(MAX_PAGE), ..Default::default() };
+      loop {
+        let NotePage { items, next_cursor } = self.list(&page)?;
+        ids.extend(items.into_iter().map(|i| i.id));
+        match next_cursor { Some(c) => page.cursor = Some(c), None => break }
+      }
+    }
+    if ids.is_empty() && query.ids.is_none() && query.tag.is_none() { return Err(ApiError::Validation("give ids (comma-separated) or a tag to export".into())); }
+    let mut seen = std::collections::HashSet::new();
+    ids.retain(|id| seen.insert(id.clone()));
+    ids.iter().map(|id| self.get(id)).filter(|n| !matches!(n, Ok(n) if n.deleted_at.is_some())).collect()
+  }
+
+  /// The note `/export/:file` renders. A note in the trash is a 409, as for other writes.
+  pub fn note_for_export(&self, id: &str) -> ApiResult<NoteDetail> {
+    let db = self.conn()?;
+    crate::store::ensure_live(&db, id)?;
+    crate::store::load_note(&db, id)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::tests::{clip, temp_store};
+
+  fn paper(page: i32) -> serde_json::Value {
+    serde_json::json!({
+      "source": { "kind": "pdf", "doi": "10.1000/xyz", "metadata": {
+        "title": "Deep Learning & Its {Limits}", "author": "LeCun, Yann; Geoffrey Hinton", "year": 2015, "journal": "Nature",
+      } },
+      "selection": { "text": format!("from page {}", page) }, "ops": { "page": page },
+    })
+  }
+
+  fn export(store: &NoteStore, ids: &[String]) -> Vec<NoteDetail> {
+    store.notes_for_export(&CitationQuery { format: "bib".into(), ids: Some(ids.join(",")), tag: None }).unwrap()
+  }
+
+  #[test]
+  fn writes_a_bibtex_entry_per_page() {
+    let (dir, store) = temp_store();
+    let ids: Vec<String> = [3, 7, 3].into_iter().map(|p| store.create(&clip(paper(p))).unwrap()).collect();
+    let bib = render(&export(&store, &ids), Format::BibTex);
+    assert_eq!(bib.matches("@article{").count(), 2, "{}", bib);
+    assert!(bib.starts_with("@article{lecun2015deep,\n  title = {{Deep Learning \\& Its \\{Limits\\}}},\n  author = {LeCun, Yann and Hinton, Geoffrey},\n  year = {2015},\n  journal = {Nature},\n  doi = {10.1000/xyz},"), "{}", bib);
+    assert!(bib.contains("  note = {p. 3}\n}\n\n@article{lecun2015deepa,") && bib.contains("note = {p. 7}"), "{}", bib);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn cites_every_page_a_note_was_clipped_from() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(paper(3))).unwrap();
+    let mut note = store.get(&id).unwrap();
+    let document = Document { id: "d".into(), hash: "h".into(), filename: None, title: None, page_count: Some(9), size: 1, path: "assets/h.pdf".into(), created_at: note.created_at.clone() };
+    note.clips = [3, 5, 3].into_iter().enumerate().map(|(i, p)| DocumentClip {
+      document: document.clone(), page_number: Some(p), highlights: Vec::new(), position: i as i64, created_at: note.created_at.clone(),
+    }).collect();
+    let ris = render(std::slice::from_ref(&note), Format::Ris);
+    assert_eq!(ris.matches("TY  - JOUR\r\n").count(), 2, "{}", ris);
+    assert!(ris.contains("N1  - p. 3\r\n") && ris.contains("N1  - p. 5\r\n"), "{}", ris);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn writes_ris_records() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(paper(4))).unwrap();
+    let ris = render_note(&store.get(&id).unwrap(), Format::Ris);
+    let lines: Vec<&str> = ris.split("\r\n").collect();
+    assert_eq!(&lines[..7], ["TY  - JOUR", "TI  - Deep Learning & Its {Limits}", "AU  - LeCun, Yann", "AU  - Hinton, Geoffrey", "PY  - 2015", "JO  - Nature", "DO  - 10.1000/xyz"]);
+    assert!(lines.contains(&"N1  - p. 4") && lines.contains(&"ID  - lecun2015deep"), "{}", ris);
+    assert_eq!(lines[lines.len() - 2..], ["ER  - ", ""]);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn writes_csl_json_items() {
+    let (dir, store) = temp_store();
+    let web = store.create(&clip(serde_json::json!({
+      "source": { "kind": "web", "url": "https://example.com/post", "metadata": { "title": "A Post", "siteName": "Example", "datePublished": "2021-03-04T10:00:00Z" } },
+      "selection": { "text": "quoted" },
+    }))).unwrap();
+    let ids = [store.create(&clip(paper(2))).unwrap(), web];
+    let items: serde_json::Value = serde_json::from_str(&render(&export(&store, &ids), Format::CslJson)).unwrap();
+    assert_eq!(items[0]["type"], "article-journal");
+    assert_eq!(items[0]["author"], serde_json::json!([{ "family": "LeCun", "given": "Yann" }, { "family": "Hinton", "given": "Geoffrey" }]));
+    assert_eq!(items[0]["issued"], serde_json::json!({ "date-parts": [[2015]] }));
+    assert_eq!((&items[0]["container-title"], &items[0]["DOI"], &items[0]["locator"], &items[0]["label"]), (&"Nature".into(), &"10.1000/xyz".into(), &"2".into(), &"page".into()));
+    assert_eq!((&items[1]["id"], &items[1]["type"], &items[1]["URL"]), (&"2021post".into(), &"webpage".into(), &"https://example.com/post".into()));
+    assert_eq!(items[1]["issued"], serde_json::json!({ "date-parts": [[2021, 3, 4]] }));
+    assert!(items[1].get("locator").is_none());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn leaves_notes_in_the_trash_out() {
+    let (dir, store) = temp_store();
+    let ids: Vec<String> = [1, 2].into_iter().map(|p| store.create(&clip(paper(p))).unwrap()).collect();
+    store.delete(&ids[1]).unwrap();
+    let notes = export(&store, &ids);
+    assert_eq!(notes.iter().map(|n| n.id.as_str()).collect::<Vec<_>>(), [ids[0].as_str()]);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
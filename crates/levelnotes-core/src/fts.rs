@@ -0,0 +1,95 @@
+//! Maintenance of the `notes_fts` search index. Triggers keep it current row by row; this
+//! rebuilds it wholesale when what gets indexed changes.
+
+use rusqlite::{params, OptionalExtension, Transaction};
+use crate::{config::Tokenizer, error::ApiResult, store::NoteStore, text::html_to_text};
+
+// FTS5 `tokenize` argument for a tokenizer setting.
+fn tokenize_spec(tokenizer: Tokenizer, remove_diacritics: bool) -> String {
+  match tokenizer {
+    // 2 also folds diacritics written as separate combining marks; 1 only handles precomposed letters.
+    Tokenizer::Unicode61 => format!("unicode61 remove_diacritics {}", if remove_diacritics { 2 } else { 0 }),
+    Tokenizer::Porter => format!("porter unicode61 remove_diacritics {}", if remove_diacritics { 2 } else { 0 }),
+    Tokenizer::Trigram => format!("trigram remove_diacritics {}", remove_diacritics as u8),
+  }
+}
+
+fn create_sql(tokenizer: Tokenizer, remove_diacritics: bool) -> String {
+  format!("CREATE VIRTUAL TABLE notes_fts USING fts5(title, plaintext, html, tags, tokenize = '{}')", tokenize_spec(tokenizer, remove_diacritics))
+}
+
+/// Re-derives `html_text` for every note and refills `notes_fts` from scratch, leaving out trashed
+/// notes. Returns how many notes were indexed.
+pub(crate) fn rebuild(tx: &Transaction) -> rusqlite::Result<usize> {
+  let rows: Vec<(i64, String)> = {
+    let mut stmt = tx.prepare("SELECT rowid, html FROM notes WHERE html IS NOT NULL")?;
+    let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    rows
+  };
+  let mut upd = tx.prepare("UPDATE notes SET html_text=?1 WHERE rowid=?2")?;
+  for (rowid, html) in rows { upd.execute(params![html_to_text(&html), rowid])?; }
+  tx.execute("DELETE FROM notes_fts", [])?;
+  tx.execute(
+    "INSERT INTO notes_fts(rowid, title, plaintext, html, tags)
+     SELECT rowid, title, plaintext, html_text,
+       (SELECT COALESCE(group_concat(value, ' '), '') FROM json_each(notes.tags_json)) FROM notes WHERE deleted_at IS NULL", [])
+}
+
+impl NoteStore {
+  /// Makes `notes_fts` use the given tokenizer, rebuilding it in place if it was created with a
+  /// different one. Returns whether it had to rebuild.
+  pub fn use_tokenizer(&self, tokenizer: Tokenizer, remove_diacritics: bool) -> ApiResult<bool> {
+    let want = create_sql(tokenizer, remove_diacritics);
+    let mut db = self.conn()?;
+    let have: Option<String> = db.query_row("SELECT sql FROM sqlite_master WHERE type='table' AND name='notes_fts'", [], |r| r.get(0)).optional()?;
+    if have.as_deref() == Some(want.as_str()) { return Ok(false); }
+    // The triggers on `notes` name the table, not its definition, so they keep working across the swap.
+    let tx = db.transaction()?;
+    tx.execute_batch(&format!("DROP TABLE IF EXISTS notes_fts; {};", want))?;
+    rebuild(&tx)?;
+    tx.commit()?;
+    Ok(true)
+  }
+
+  /// Rebuilds the search index from the notes table. Returns how many notes were indexed.
+  pub fn reindex(&self) -> ApiResult<usize> {
+    let mut db = self.conn()?;
+    let tx = db.transaction()?;
+    let n = rebuild(&tx)?;
+    tx.commit()?;
+    Ok(n)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::tests::{clip, temp_store};
+
+  fn found(store: &NoteStore, q: &str) -> usize { store.search(q, 10).unwrap().len() }
+
+  #[test]
+  fn rebuilds_only_when_the_tokenizer_changes() {
+    let (dir, store) = temp_store();
+    store.create(&clip(serde_json::json!({ "selection": { "text": "She runs to the café", "html": "<p class=\"attribute\">climate <b>models</b></p>" } }))).unwrap();
+    let trashed = store.create(&clip(serde_json::json!({ "selection": { "text": "runs elsewhere" } }))).unwrap();
+    store.delete(&trashed).unwrap();
+
+    store.use_tokenizer(Tokenizer::Unicode61, true).unwrap();
+    assert!(!store.use_tokenizer(Tokenizer::Unicode61, true).unwrap());
+    assert_eq!((found(&store, "cafe"), found(&store, "running"), found(&store, "limat")), (1, 0, 0));
+
+    assert!(store.use_tokenizer(Tokenizer::Porter, true).unwrap());
+    assert!(!store.use_tokenizer(Tokenizer::Porter, true).unwrap());
+    assert_eq!((found(&store, "running"), found(&store, "model")), (1, 1));
+
+    assert!(store.use_tokenizer(Tokenizer::Unicode61, false).unwrap());
+    assert_eq!((found(&store, "cafe"), found(&store, "café")), (0, 1));
+
+    assert!(store.use_tokenizer(Tokenizer::Trigram, true).unwrap());
+    assert_eq!(found(&store, "limat"), 1);
+    // Rebuilt from the notes table, the index still leaves the trash out and never saw markup.
+    assert_eq!((found(&store, "elsewhere"), found(&store, "attribute")), (0, 0));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,49 @@
+//! LevelNotes backend: the SQLite note store, clip assets and the local HTTP API.
+//! The Tauri app is a thin shell over this crate; anything headless can link it too.
+
+pub mod assets;
+pub mod auth;
+pub mod conditional;
+pub mod config;
+pub mod diff;
+pub mod documents;
+pub mod error;
+pub mod export;
+mod fts;
+pub mod listing;
+pub mod migrations;
+pub mod model;
+mod query;
+pub mod resolve;
+pub mod revisions;
+pub mod router;
+pub mod sources;
+pub mod store;
+pub mod text;
+pub mod thumbnails;
+pub mod trash;
+
+pub use assets::AssetStore;
+pub use auth::Auth;
+pub use config::{Config, ConfigError, ConfigLayer, Tokenizer};
+pub use error::{ApiError, ApiResult};
+pub use resolve::{Enricher, MetadataResolver};
+pub use router::{build_router, AppState};
+pub use store::NoteStore;
+pub use thumbnails::Thumbnailer;
+
+use std::future::Future;
+use tokio::net::TcpListener;
+
+/// Serves the HTTP API on `listener` until `shutdown` resolves, then lets in-flight requests finish.
+/// Source metadata lookups, thumbnails and the trash purge run in the background for as long as the API does.
+pub async fn serve(listener: TcpListener, state: AppState, shutdown: impl Future<Output = ()> + Send + 'static) -> std::io::Result<()> {
+  let enricher = tokio::spawn(state.enricher.clone().run());
+  let thumbnailer = tokio::spawn(state.thumbnailer.clone().run());
+  let purge = tokio::spawn(trash::purge_expired(state.store.clone(), state.config.trash_retention_days));
+  let result = axum::serve(listener, build_router(state)).with_graceful_shutdown(shutdown).await;
+  enricher.abort();
+  thumbnailer.abort();
+  purge.abort();
+  result
+}
@@ -0,0 +1,309 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use rusqlite::{params_from_iter, types::Value};
+use serde::{Deserialize, Serialize};
+use crate::{error::{ApiError, ApiResult}, model::*, query, sources, store::{list_item_from_row, NoteStore, LIST_COLUMNS, LIST_COLUMN_COUNT}};
+
+pub const DEFAULT_PAGE: usize = 50;
+pub const MAX_PAGE: usize = 200;
+
+// bm25 weights in `notes_fts` column order (title, plaintext, html, tags): a hit in the title
+// counts most, then tags, then body text, and the text extracted from the HTML (`html_text`,
+// mostly a repeat of the body) least.
+const RANK_SQL: &str = "bm25(notes_fts, 10.0, 2.0, 0.5, 5.0)";
+// Excerpt and highlight markers. Control characters can't occur in clipped text, so they survive
+// FTS untouched and are swapped for `<mark>` after the text around them has been escaped.
+const HIT_OPEN: char = '\u{1}';
+const HIT_CLOSE: char = '\u{2}';
+const SNIPPET_TOKENS: u32 = 24;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Sort { Created, Updated, Opened, Title, Relevance, Deleted }
+
+impl Sort {
+  fn parse(s: Option<&str>, searching: bool, trashed: bool) -> ApiResult<Sort> {
+    match s.unwrap_or(if searching { "relevance" } else if trashed { "deleted" } else { "created" }) {
+      "created" => Ok(Sort::Created),
+      "updated" => Ok(Sort::Updated),
+      "opened" => Ok(Sort::Opened),
+      "title" => Ok(Sort::Title),
+      "relevance" => Ok(Sort::Relevance),
+      "deleted" if trashed => Ok(Sort::Deleted),
+      other => Err(ApiError::Validation(format!("sort must be created, updated, opened, title{} or relevance, not {:?}", if trashed { ", deleted" } else { "" }, other))),
+    }
+  }
+
+  fn key_sql(self) -> &'static str {
+    match self {
+      Sort::Created => "n.created_at",
+      Sort::Updated => "COALESCE(n.updated_at, n.created_at)",
+      // Never-opened notes sort after every opened one.
+      Sort::Opened => "COALESCE(n.last_opened_at, '')",
+      Sort::Title => "n.title COLLATE NOCASE",
+      Sort::Relevance => RANK_SQL,
+      Sort::Deleted => "n.deleted_at",
+    }
+  }
+
+  // Newest first for dates, A→Z for titles, best match first (lowest bm25 rank) for relevance.
+  fn default_desc(self) -> bool { matches!(self, Sort::Created | Sort::Updated | Sort::Opened | Sort::Deleted) }
+}
+
+/// Keyset position after the last item of a page; handed to clients as an opaque base64 string.
+#[derive(Serialize, Deserialize)]
+struct Cursor { s: Sort, k: serde_json::Value, id: String }
+
+impl Cursor {
+  fn encode(&self) -> String { URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap()) }
+
+  fn decode(s: &str) -> ApiResult<Cursor> {
+    URL_SAFE_NO_PAD.decode(s).ok()
+      .and_then(|b| serde_json::from_slice(&b).ok())
+      .ok_or_else(|| ApiError::Validation("cursor is not one this server issued".into()))
+  }
+
+  fn key_value(&self) -> ApiResult<Value> {
+    match &self.k {
+      serde_json::Value::String(s) => Ok(Value::Text(s.clone())),
+      serde_json::Value::Number(n) => n.as_f64().map(Value::Real).ok_or_else(|| ApiError::Validation("cursor is malformed".into())),
+      _ => Err(ApiError::Validation("cursor is malformed".into())),
+    }
+  }
+}
+
+/// Strips the hit markers from FTS `highlight()`/`snippet()` output. Returns the plain text, the
+/// HTML-escaped text with `<mark>` around hits, and the hit offsets in UTF-16 code units.
+fn split_hits(marked: &str) -> (String, String, Vec<(usize, usize)>) {
+  let (mut plain, mut html, mut hits) = (String::with_capacity(marked.len()), String::with_capacity(marked.len()), Vec::new());
+  let (mut pos, mut open) = (0usize, None);
+  for c in marked.chars() {
+    match c {
+      HIT_OPEN => { open = Some(pos); html.push_str("<mark>"); }
+      HIT_CLOSE => { if let Some(start) = open.take() { hits.push((start, pos)); } html.push_str("</mark>"); }
+      _ => {
+        pos += c.len_utf16();
+        plain.push(c);
+        match c { '&' => html.push_str("&amp;"), '<' => html.push_str("&lt;"), '>' => html.push_str("&gt;"), '"' => html.push_str("&quot;"), _ => html.push(c) }
+      }
+    }
+  }
+  (plain, html, hits)
+}
+
+// Accepts RFC 3339 timestamps or bare dates. A bare `to` date includes that whole day.
+fn parse_bound(name: &str, s: &str, end_of_day: bool) -> ApiResult<String> {
+  if let Ok(t) = DateTime::parse_from_rfc3339(s) { return Ok(t.with_timezone(&Utc).to_rfc3339()); }
+  let d = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+    .map_err(|_| ApiError::Validation(format!("{} must be YYYY-MM-DD or an RFC 3339 timestamp", name)))?;
+  let d = if end_of_day { d + Duration::days(1) } else { d };
+  Ok(d.and_hms_opt(0, 0, 0).unwrap().and_utc().to_rfc3339())
+}
+
+// `example.com` also matches `docs.example.com`.
+fn push_domain(wheres: &mut Vec<String>, args: &mut Vec<Value>, domain: &str, not: bool) {
+  let domain = domain.trim().trim_start_matches("www.").to_ascii_lowercase();
+  let test = "(n.source_domain = ? OR n.source_domain LIKE '%.' || ?)";
+  wheres.push(if not { format!("NOT COALESCE({test}, 0)") } else { test.to_string() });
+  args.push(Value::Text(domain.clone())); args.push(Value::Text(domain));
+}
+
+// Fills the search-only fields from the extra columns `list` selects after the sort key when there is a `q`.
+fn add_hits(item: &mut NoteListItem, row: &rusqlite::Row) -> rusqlite::Result<()> {
+  let at = LIST_COLUMN_COUNT + 1;
+  item.score = row.get(at)?;
+  if let Some(title) = row.get::<_, Option<String>>(at + 1)? {
+    let (_, html, hits) = split_hits(&title);
+    item.highlighted_title = Some(html);
+    item.matches.extend(hits.into_iter().map(|(start, end)| MatchSpan { field: "title", start, end }));
+  }
+  if let Some(body) = row.get::<_, Option<String>>(at + 2)? {
+    let (_, _, hits) = split_hits(&body);
+    item.matches.extend(hits.into_iter().map(|(start, end)| MatchSpan { field: "plaintext", start, end }));
+  }
+  if let Some(snippet) = row.get::<_, Option<String>>(at + 3)?.filter(|s| !s.trim().is_empty()) {
+    let (plain, html, _) = split_hits(&snippet);
+    item.snippet = Some(plain);
+    item.highlighted_snippet = Some(html);
+  }
+  Ok(())
+}
+
+impl NoteStore {
+  /// One page of notes matching `query`, with the cursor for the next page if there is one.
+  /// Filters apply the same way with or without a full-text `q`.
+  pub fn list(&self, query: &ListQuery) -> ApiResult<NotePage> {
+    let parsed = query::parse(query.q.as_deref().unwrap_or(""))?;
+    let text = parsed.fts.as_deref();
+    let sort = Sort::parse(query.sort.as_deref(), text.is_some(), query.trashed)?;
+    if sort == Sort::Relevance && text.is_none() { return Err(ApiError::Validation("sort=relevance needs a search query q".into())); }
+    // Trashed notes are out of the search index; filters in `q` still apply to them.
+    if query.trashed && text.is_some() { return Err(ApiError::Validation("the trash can't be searched by text, only filtered".into())); }
+    let desc = match query.order.as_deref() {
+      None => sort.default_desc(),
+      Some("desc") => true,
+      Some("asc") => false,
+      Some(other) => return Err(ApiError::Validation(format!("order must be asc or desc, not {:?}", other))),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+
+    let mut wheres: Vec<String> = vec![format!("n.deleted_at IS {}NULL", if query.trashed { "NOT " } else { "" })];
+    let mut args: Vec<Value> = Vec::new();
+    let from = if text.is_some() { "notes n JOIN notes_fts f ON f.rowid=n.rowid" } else { "notes n" };
+    if let Some(q) = text { wheres.push("notes_fts MATCH ?".into()); args.push(Value::Text(q.to_string())); }
+    if let Some(q) = parsed.exclude_fts {
+      wheres.push("n.rowid NOT IN (SELECT rowid FROM notes_fts WHERE notes_fts MATCH ?)".into()); args.push(Value::Text(q));
+    }
+    let mut tags: Vec<String> = query.tag.iter().flat_map(|t| t.split(',')).map(str::trim).filter(|t| !t.is_empty()).map(str::to_string).collect();
+    tags.extend(parsed.tags);
+    for tag in tags { wheres.push("EXISTS (SELECT 1 FROM json_each(n.tags_json) WHERE value = ?)".into()); args.push(Value::Text(tag)); }
+    for tag in parsed.not_tags { wheres.push("NOT EXISTS (SELECT 1 FROM json_each(n.tags_json) WHERE value = ?)".into()); args.push(Value::Text(tag)); }
+    if let Some(kind) = query.source_kind.as_deref() { wheres.push("n.source_kind = ?".into()); args.push(Value::Text(kind.to_string())); }
+    if let Some(domain) = query.source_domain.as_deref() { push_domain(&mut wheres, &mut args, domain, false); }
+    if let Some(sid) = query.source_id.as_deref() { wheres.push("n.source_id = ?".into()); args.push(Value::Text(sid.to_string())); }
+    if let Some(doi) = query.doi.as_deref() {
+      let doi = sources::normalize_doi(doi).ok_or_else(|| ApiError::Validation(format!("doi {:?} is not a DOI (expected 10.prefix/suffix)", doi)))?;
+      wheres.push("n.source_id IN (SELECT id FROM sources WHERE doi = ?)".into()); args.push(Value::Text(doi));
+    }
+    if let Some(has) = query.has_doi {
+      wheres.push(format!("{}EXISTS (SELECT 1 FROM sources s WHERE s.id = n.source_id AND s.doi IS NOT NULL)", if has { "" } else { "NOT " }));
+    }
+    // `source:` in q names a kind (`pdf`, `web`, `image`) or a domain.
+    for (source, not) in parsed.sources.iter().map(|s| (s, false)).chain(parsed.not_sources.iter().map(|s| (s, true))) {
+      if sources::KINDS.contains(&source.as_str()) {
+        wheres.push(format!("n.source_kind {} ?", if not { "IS NOT" } else { "=" })); args.push(Value::Text(source.clone()));
+      } else {
+        push_domain(&mut wheres, &mut args, source, not);
+      }
+    }
+    if let Some(s) = query.from.as_deref() { wheres.push("n.created_at >= ?".into()); args.push(Value::Text(parse_bound("from", s, false)?)); }
+    if let Some(s) = query.to.as_deref() { wheres.push("n.created_at < ?".into()); args.push(Value::Text(parse_bound("to", s, true)?)); }
+    if let Some(s) = parsed.after.as_deref() { wheres.push("n.created_at >= ?".into()); args.push(Value::Text(parse_bound("after:", s, false)?)); }
+    if let Some(s) = parsed.before.as_deref() { wheres.push("n.created_at < ?".into()); args.push(Value::Text(parse_bound("before:", s, false)?)); }
+    match query.has_preview {
+      Some(true) => wheres.push("n.preview_path IS NOT NULL".into()),
+      Some(false) => wheres.push("n.preview_path IS NULL".into()),
+      None => {}
+    }
+    let key = sort.key_sql();
+    let (cmp, dir) = if desc { ("<", "DESC") } else { (">", "ASC") };
+    if let Some(c) = query.cursor.as_deref() {
+      let c = Cursor::decode(c)?;
+      if c.s != sort { return Err(ApiError::Validation("cursor belongs to a different sort".into())); }
+      let k = c.key_value()?;
+      wheres.push(format!("({key} {cmp} ? OR ({key} = ? AND n.id {cmp} ?))"));
+      args.push(k.clone()); args.push(k); args.push(Value::Text(c.id));
+    }
+    let where_sql = format!("WHERE {}", wheres.join(" AND "));
+    let hits_sql = if text.is_some() {
+      format!(", {RANK_SQL}, highlight(notes_fts, 0, char(1), char(2)), highlight(notes_fts, 1, char(1), char(2)),
+         snippet(notes_fts, -1, char(1), char(2), '…', {SNIPPET_TOKENS})")
+    } else { String::new() };
+    let sql = format!(
+      "SELECT {LIST_COLUMNS}, {key}{hits_sql}
+       FROM {from} {where_sql} ORDER BY {key} {dir}, n.id {dir} LIMIT {}", limit + 1);
+
+    let db = self.conn()?;
+    let mut stmt = db.prepare(&sql)?;
+    let searching = text.is_some();
+    let mut rows = stmt.query_map(params_from_iter(args), |row| {
+      let key: Value = row.get(LIST_COLUMN_COUNT)?;
+      let mut item = list_item_from_row(row)?;
+      if searching { add_hits(&mut item, row)?; }
+      Ok((item, key))
+    })?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let next_cursor = if rows.len() > limit {
+      rows.truncate(limit);
+      rows.last().map(|(item, key)| {
+        let k = match key { Value::Real(f) => serde_json::json!(f), Value::Integer(i) => serde_json::json!(i), Value::Text(s) => serde_json::json!(s), _ => serde_json::Value::Null };
+        Cursor { s: sort, k, id: item.id.clone() }.encode()
+      })
+    } else { None };
+    Ok(NotePage { items: rows.into_iter().map(|(item, _)| item).collect(), next_cursor })
+  }
+
+  /// Full-text search shortcut: the best `limit` matches for `q`.
+  pub fn search(&self, q: &str, limit: usize) -> ApiResult<Vec<NoteListItem>> {
+    let query = ListQuery { q: Some(q.to_string()), limit: Some(limit), ..Default::default() };
+    Ok(self.list(&query)?.items)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rusqlite::params;
+  use crate::store::tests::{clip, temp_store};
+
+  // Every page of `query`, `limit` at a time, following `next_cursor` to the end.
+  fn walk(store: &NoteStore, query: ListQuery, limit: usize) -> Vec<String> {
+    let mut query = ListQuery { limit: Some(limit), ..query };
+    let mut ids = Vec::new();
+    loop {
+      let page = store.list(&query).unwrap();
+      assert!(page.items.len() <= limit);
+      ids.extend(page.items.into_iter().map(|i| i.id));
+      match page.next_cursor { Some(c) => query.cursor = Some(c), None => return ids }
+    }
+  }
+
+  // Seven notes whose creation times and titles tie in places, so pages must break ties by id.
+  fn tied_notes(store: &NoteStore) -> Vec<(String, String, String)> {
+    [("b", 1), ("a", 2), ("B", 2), ("c", 2), ("a", 3), ("d", 3), ("A", 4)].into_iter().map(|(title, day)| {
+      let id = store.create(&clip(serde_json::json!({ "selection": { "text": format!("climate note {}", title) } }))).unwrap();
+      let created = format!("2024-01-0{}T00:00:00+00:00", day);
+      store.conn().unwrap().execute("UPDATE notes SET title=?1, created_at=?2 WHERE id=?3", params![title, created, id]).unwrap();
+      (id, title.to_string(), created)
+    }).collect()
+  }
+
+  #[test]
+  fn pages_through_every_note_once_in_order() {
+    let (dir, store) = temp_store();
+    let mut notes = tied_notes(&store);
+
+    notes.sort_by(|x, y| (&y.2, &y.0).cmp(&(&x.2, &x.0)));
+    let newest_first: Vec<String> = notes.iter().map(|n| n.0.clone()).collect();
+    for limit in [1, 2, 3, 7, 10] { assert_eq!(walk(&store, ListQuery::default(), limit), newest_first, "limit {}", limit); }
+    let oldest_first: Vec<String> = newest_first.iter().rev().cloned().collect();
+    assert_eq!(walk(&store, ListQuery { order: Some("asc".into()), ..Default::default() }, 2), oldest_first);
+
+    notes.sort_by(|x, y| (x.1.to_lowercase(), &x.0).cmp(&(y.1.to_lowercase(), &y.0)));
+    let by_title: Vec<String> = notes.iter().map(|n| n.0.clone()).collect();
+    assert_eq!(walk(&store, ListQuery { sort: Some("title".into()), ..Default::default() }, 2), by_title);
+
+    let everything = walk(&store, ListQuery { q: Some("climate".into()), ..Default::default() }, 100);
+    let mut paged = walk(&store, ListQuery { q: Some("climate".into()), ..Default::default() }, 2);
+    assert_eq!(paged, everything);
+    paged.sort();
+    let mut all: Vec<String> = notes.into_iter().map(|n| n.0).collect();
+    all.sort();
+    assert_eq!(paged, all);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn counts_hit_offsets_in_utf16_units() {
+    let (plain, html, hits) = split_hits("caf\u{e9} \u{1}na\u{ef}ve\u{2} \u{1f600} <\u{1}b&\u{2}>");
+    assert_eq!(plain, "caf\u{e9} na\u{ef}ve \u{1f600} <b&>");
+    assert_eq!(html, "caf\u{e9} <mark>na\u{ef}ve</mark> \u{1f600} &lt;<mark>b&amp;</mark>&gt;");
+    // The emoji is two UTF-16 units, so the second hit starts at 15 where counting chars gives 14.
+    assert_eq!(hits, [(5, 10), (15, 17)]);
+    let utf16: Vec<u16> = plain.encode_utf16().collect();
+    assert_eq!(String::from_utf16(&utf16[5..10]).unwrap(), "na\u{ef}ve");
+    assert_eq!(String::from_utf16(&utf16[15..17]).unwrap(), "b&");
+  }
+
+  #[test]
+  fn refuses_cursors_it_did_not_issue_for_the_sort() {
+    let (dir, store) = temp_store();
+    tied_notes(&store);
+    let cursor = store.list(&ListQuery { limit: Some(2), ..Default::default() }).unwrap().next_cursor.unwrap();
+    let by_title = store.list(&ListQuery { sort: Some("title".into()), cursor: Some(cursor), ..Default::default() });
+    assert!(matches!(by_title, Err(ApiError::Validation(m)) if m.contains("different sort")));
+    let forged = store.list(&ListQuery { cursor: Some("not-a-cursor".into()), ..Default::default() });
+    assert!(matches!(forged, Err(ApiError::Validation(m)) if m.contains("not one this server issued")));
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
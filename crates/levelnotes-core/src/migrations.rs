@@ -0,0 +1,643 @@
+use std::{fmt, path::{Path as FsPath, PathBuf}};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use uuid::Uuid;
+use self::frozen::{canonical_url, html_to_text, read_asset, sha256_hex, url_domain};
+
+// One schema step: `sql` runs first, then `backfill` for data changes SQL can't express.
+struct Migration { name: &'static str, sql: &'static str, backfill: Option<fn(&Transaction) -> rusqlite::Result<()>> }
+
+// Ordered schema steps. Each one runs in its own transaction and bumps `PRAGMA user_version`
+// to its index + 1. Never edit a shipped step: append a new one instead. Backfills write with their
+// own SQL, frozen with the step: the store's helpers follow the latest schema, which a database
+// being upgraded doesn't have yet. The Rust helpers they call are frozen too, in `frozen` below.
+const MIGRATIONS: &[Migration] = &[
+  Migration { name: "initial schema", backfill: None, sql: r#"
+    CREATE TABLE IF NOT EXISTS notes (
+      id TEXT PRIMARY KEY,
+      created_at TEXT NOT NULL,
+      title TEXT NOT NULL,
+      plaintext TEXT, html TEXT, source_url TEXT, text_quote TEXT,
+      preview_path TEXT, tags_json TEXT, page_number INTEGER, highlights_json TEXT
+    );
+    CREATE INDEX IF NOT EXISTS idx_notes_created_at ON notes(created_at DESC);
+
+    CREATE VIRTUAL TABLE IF NOT EXISTS notes_fts
+    USING fts5(title, plaintext, html, tags, content='notes', content_rowid='rowid');
+
+    CREATE TRIGGER IF NOT EXISTS notes_ai AFTER INSERT ON notes BEGIN
+      INSERT INTO notes_fts(rowid, title, plaintext, html, tags)
+      VALUES (new.rowid, new.title, new.plaintext, new.html,
+        (SELECT COALESCE(group_concat(value, ' '), '') FROM json_each(new.tags_json)));
+    END;
+    CREATE TRIGGER IF NOT EXISTS notes_ad AFTER DELETE ON notes BEGIN
+      INSERT INTO notes_fts(notes_fts, rowid) VALUES ('delete', old.rowid);
+    END;
+    CREATE TRIGGER IF NOT EXISTS notes_au AFTER UPDATE ON notes BEGIN
+      INSERT INTO notes_fts(notes_fts, rowid) VALUES ('delete', old.rowid);
+      INSERT INTO notes_fts(rowid, title, plaintext, html, tags)
+      VALUES (new.rowid, new.title, new.plaintext, new.html,
+        (SELECT COALESCE(group_concat(value, ' '), '') FROM json_each(new.tags_json)));
+    END;
+  "# },
+  Migration { name: "paired clients", backfill: None, sql: r#"
+    CREATE TABLE clients (
+      origin TEXT PRIMARY KEY,
+      name TEXT,
+      paired_at TEXT NOT NULL
+    );
+  "# },
+  // notes_fts was an external-content table over `notes`, but `notes` has no `tags` column and the
+  // delete triggers passed no old values, so stale terms stayed matchable and FTS5 could flag the
+  // index as corrupt. It now keeps its own copy of the indexed text.
+  Migration { name: "standalone search index", backfill: None, sql: r#"
+    DROP TRIGGER IF EXISTS notes_ai;
+    DROP TRIGGER IF EXISTS notes_ad;
+    DROP TRIGGER IF EXISTS notes_au;
+    DROP TABLE IF EXISTS notes_fts;
+    CREATE VIRTUAL TABLE notes_fts USING fts5(title, plaintext, html, tags);
+    INSERT INTO notes_fts(rowid, title, plaintext, html, tags)
+      SELECT rowid, title, plaintext, html,
+        (SELECT COALESCE(group_concat(value, ' '), '') FROM json_each(notes.tags_json)) FROM notes;
+
+    CREATE TRIGGER notes_ai AFTER INSERT ON notes BEGIN
+      INSERT INTO notes_fts(rowid, title, plaintext, html, tags)
+      VALUES (new.rowid, new.title, new.plaintext, new.html,
+        (SELECT COALESCE(group_concat(value, ' '), '') FROM json_each(new.tags_json)));
+    END;
+    CREATE TRIGGER notes_ad AFTER DELETE ON notes BEGIN
+      DELETE FROM notes_fts WHERE rowid = old.rowid;
+    END;
+    CREATE TRIGGER notes_au AFTER UPDATE OF title, plaintext, html, tags_json ON notes BEGIN
+      DELETE FROM notes_fts WHERE rowid = old.rowid;
+      INSERT INTO notes_fts(rowid, title, plaintext, html, tags)
+      VALUES (new.rowid, new.title, new.plaintext, new.html,
+        (SELECT COALESCE(group_concat(value, ' '), '') FROM json_each(new.tags_json)));
+    END;
+  "# },
+  Migration { name: "listing columns", backfill: Some(backfill_source_domain), sql: r#"
+    ALTER TABLE notes ADD COLUMN updated_at TEXT;
+    ALTER TABLE notes ADD COLUMN source_kind TEXT;
+    ALTER TABLE notes ADD COLUMN source_domain TEXT;
+    UPDATE notes SET updated_at = created_at,
+      source_kind = CASE WHEN page_number IS NOT NULL THEN 'pdf' WHEN source_url LIKE 'http%' THEN 'web' END;
+    CREATE INDEX idx_notes_updated_at ON notes(updated_at DESC);
+    CREATE INDEX idx_notes_title ON notes(title COLLATE NOCASE);
+    CREATE INDEX idx_notes_source_domain ON notes(source_domain);
+  "# },
+  // The `html` search column indexed raw markup, so `div` or `class` matched nearly every clip.
+  // It now holds `html_text`, the clip HTML reduced to text in Rust (see `text::html_to_text`).
+  Migration { name: "index text of clip html", backfill: Some(backfill_html_text), sql: r#"
+    ALTER TABLE notes ADD COLUMN html_text TEXT;
+    DROP TRIGGER notes_ai;
+    DROP TRIGGER notes_au;
+    CREATE TRIGGER notes_ai AFTER INSERT ON notes BEGIN
+      INSERT INTO notes_fts(rowid, title, plaintext, html, tags)
+      VALUES (new.rowid, new.title, new.plaintext, new.html_text,
+        (SELECT COALESCE(group_concat(value, ' '), '') FROM json_each(new.tags_json)));
+    END;
+    CREATE TRIGGER notes_au AFTER UPDATE OF title, plaintext, html_text, tags_json ON notes BEGIN
+      DELETE FROM notes_fts WHERE rowid = old.rowid;
+      INSERT INTO notes_fts(rowid, title, plaintext, html, tags)
+      VALUES (new.rowid, new.title, new.plaintext, new.html_text,
+        (SELECT COALESCE(group_concat(value, ' '), '') FROM json_each(new.tags_json)));
+    END;
+  "# },
+  Migration { name: "clip anchors", backfill: None, sql: r#"
+    ALTER TABLE notes ADD COLUMN selectors_json TEXT;
+  "# },
+  // Until now only `source.url` survived a clip. Existing notes get a source built from it.
+  Migration { name: "sources", backfill: Some(backfill_sources), sql: r#"
+    CREATE TABLE sources (
+      id TEXT PRIMARY KEY,
+      kind TEXT NOT NULL,
+      url TEXT, canonical_url TEXT, doi TEXT,
+      title TEXT, author TEXT, site_name TEXT,
+      accessed_at TEXT NOT NULL,
+      metadata_json TEXT
+    );
+    CREATE INDEX idx_sources_doi ON sources(doi);
+    CREATE INDEX idx_sources_canonical_url ON sources(canonical_url);
+    ALTER TABLE notes ADD COLUMN source_id TEXT REFERENCES sources(id);
+    CREATE INDEX idx_notes_source_id ON notes(source_id);
+  "# },
+  // Sources with a DOI or ISBN, and PDFs, get their details filled in by `resolve::Enricher`.
+  // `metadata_jobs` is its queue and keeps the outcome of the last attempt.
+  Migration { name: "source metadata resolution", backfill: None, sql: r#"
+    ALTER TABLE sources ADD COLUMN isbn TEXT;
+    ALTER TABLE sources ADD COLUMN year INTEGER;
+    ALTER TABLE sources ADD COLUMN venue TEXT;
+    CREATE INDEX idx_sources_isbn ON sources(isbn);
+    CREATE TABLE metadata_jobs (
+      source_id TEXT PRIMARY KEY REFERENCES sources(id) ON DELETE CASCADE,
+      status TEXT NOT NULL,
+      attempts INTEGER NOT NULL DEFAULT 0,
+      next_attempt_at TEXT NOT NULL,
+      last_error TEXT,
+      resolved_by TEXT,
+      updated_at TEXT NOT NULL
+    );
+    CREATE INDEX idx_metadata_jobs_due ON metadata_jobs(status, next_attempt_at);
+    INSERT INTO metadata_jobs (source_id, status, next_attempt_at, updated_at)
+      SELECT id, 'pending', accessed_at, accessed_at FROM sources WHERE doi IS NOT NULL OR kind = 'pdf';
+  "# },
+  // Each note starts its history with the content it has today.
+  Migration { name: "note revisions", backfill: None, sql: r#"
+    CREATE TABLE note_revisions (
+      id INTEGER PRIMARY KEY AUTOINCREMENT,
+      note_id TEXT NOT NULL,
+      created_at TEXT NOT NULL,
+      updated_at TEXT NOT NULL,
+      origin TEXT NOT NULL,
+      restored_from INTEGER,
+      title TEXT NOT NULL,
+      plaintext TEXT, html TEXT, tags_json TEXT
+    );
+    CREATE INDEX idx_note_revisions_note ON note_revisions(note_id, id);
+    INSERT INTO note_revisions (note_id, created_at, updated_at, origin, title, plaintext, html, tags_json)
+      SELECT id, COALESCE(updated_at, created_at), COALESCE(updated_at, created_at), 'import', title, plaintext, html, tags_json
+      FROM notes ORDER BY created_at;
+  "# },
+  // Deleting a note stamps `deleted_at`; the row stays until purged. Trashed notes leave the search index.
+  Migration { name: "trash", backfill: None, sql: r#"
+    ALTER TABLE notes ADD COLUMN deleted_at TEXT;
+    CREATE INDEX idx_notes_deleted_at ON notes(deleted_at);
+    DROP TRIGGER notes_ai;
+    DROP TRIGGER notes_au;
+    CREATE TRIGGER notes_ai AFTER INSERT ON notes WHEN new.deleted_at IS NULL BEGIN
+      INSERT INTO notes_fts(rowid, title, plaintext, html, tags)
+      VALUES (new.rowid, new.title, new.plaintext, new.html_text,
+        (SELECT COALESCE(group_concat(value, ' '), '') FROM json_each(new.tags_json)));
+    END;
+    CREATE TRIGGER notes_au AFTER UPDATE OF title, plaintext, html_text, tags_json, deleted_at ON notes BEGIN
+      DELETE FROM notes_fts WHERE rowid = old.rowid;
+      INSERT INTO notes_fts(rowid, title, plaintext, html, tags)
+      SELECT new.rowid, new.title, new.plaintext, new.html_text,
+        (SELECT COALESCE(group_concat(value, ' '), '') FROM json_each(new.tags_json))
+      WHERE new.deleted_at IS NULL;
+    END;
+  "# },
+  Migration { name: "last opened", backfill: None, sql: r#"
+    ALTER TABLE notes ADD COLUMN last_opened_at TEXT;
+    CREATE INDEX idx_notes_last_opened_at ON notes(last_opened_at DESC);
+  "# },
+  // Bumped by every content write, so a client can tell whether the note it edited is still current.
+  Migration { name: "note versions", backfill: None, sql: r#"
+    ALTER TABLE notes ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+  "# },
+  // Files are stored once per content hash and linked to the notes using them; see `assets`.
+  // `notes.preview_path` stays as the path of the note's card image.
+  Migration { name: "asset store", backfill: Some(backfill_assets), sql: r#"
+    CREATE TABLE assets (
+      hash TEXT PRIMARY KEY,
+      mime TEXT NOT NULL,
+      size INTEGER NOT NULL,
+      path TEXT NOT NULL UNIQUE,
+      created_at TEXT NOT NULL
+    );
+    CREATE TABLE note_assets (
+      note_id TEXT NOT NULL,
+      asset_hash TEXT NOT NULL REFERENCES assets(hash),
+      role TEXT NOT NULL,
+      position INTEGER NOT NULL,
+      created_at TEXT NOT NULL,
+      PRIMARY KEY (note_id, asset_hash)
+    );
+    CREATE INDEX idx_note_assets_asset ON note_assets(asset_hash);
+  "# },
+  // An attachment's file name as clipped; screenshots have none.
+  Migration { name: "asset names", backfill: None, sql: r#"
+    ALTER TABLE note_assets ADD COLUMN name TEXT;
+  "# },
+  // Downscaled copies of image assets; see `thumbnails`. An image's size is filled in once it has
+  // been decoded, so assets still without one are the thumbnailer's queue.
+  Migration { name: "thumbnails", backfill: None, sql: r#"
+    ALTER TABLE assets ADD COLUMN width INTEGER;
+    ALTER TABLE assets ADD COLUMN height INTEGER;
+    ALTER TABLE assets ADD COLUMN thumbnail_error TEXT;
+    CREATE TABLE asset_thumbnails (
+      asset_hash TEXT NOT NULL REFERENCES assets(hash),
+      size INTEGER NOT NULL,
+      mime TEXT NOT NULL,
+      path TEXT NOT NULL UNIQUE,
+      width INTEGER NOT NULL,
+      height INTEGER NOT NULL,
+      created_at TEXT NOT NULL,
+      PRIMARY KEY (asset_hash, size)
+    );
+  "# },
+  // PDFs clips were taken from, stored as assets; see `documents`.
+  Migration { name: "documents", backfill: None, sql: r#"
+    CREATE TABLE documents (
+      id TEXT PRIMARY KEY,
+      hash TEXT NOT NULL UNIQUE REFERENCES assets(hash),
+      filename TEXT,
+      page_count INTEGER,
+      title TEXT,
+      created_at TEXT NOT NULL
+    );
+    ALTER TABLE notes ADD COLUMN document_id TEXT REFERENCES documents(id);
+    CREATE INDEX idx_notes_document_id ON notes(document_id);
+  "# },
+  // Where in its document each clip of a note came from, appended clips included. `notes.document_id`,
+  // `page_number` and `highlights_json` stay as the first clip's.
+  Migration { name: "document clips", backfill: None, sql: r#"
+    CREATE TABLE document_clips (
+      note_id TEXT NOT NULL,
+      position INTEGER NOT NULL,
+      document_id TEXT NOT NULL REFERENCES documents(id),
+      page_number INTEGER,
+      highlights_json TEXT,
+      created_at TEXT NOT NULL,
+      PRIMARY KEY (note_id, position)
+    );
+    CREATE INDEX idx_document_clips_document ON document_clips(document_id);
+    INSERT INTO document_clips (note_id, position, document_id, page_number, highlights_json, created_at)
+      SELECT id, 0, document_id, page_number, highlights_json, created_at FROM notes WHERE document_id IS NOT NULL;
+  "# },
+  // Uploads used to keep the type the client declared when their bytes weren't a known one; those
+  // are plain files now, like new uploads of the kind (see `assets::SERVABLE`).
+  Migration { name: "untrusted asset types", backfill: None, sql: r#"
+    UPDATE assets SET mime = 'application/octet-stream'
+      WHERE mime NOT IN ('image/png', 'image/jpeg', 'image/webp', 'image/gif', 'image/avif', 'application/pdf');
+  "# },
+];
+
+fn backfill_source_domain(tx: &Transaction) -> rusqlite::Result<()> {
+  let rows: Vec<(String, String)> = {
+    let mut stmt = tx.prepare("SELECT id, source_url FROM notes WHERE source_url IS NOT NULL")?;
+    let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    rows
+  };
+  let mut upd = tx.prepare("UPDATE notes SET source_domain=?1 WHERE id=?2")?;
+  for (id, url) in rows {
+    if let Some(domain) = url_domain(&url) { upd.execute(params![domain, id])?; }
+  }
+  Ok(())
+}
+
+fn backfill_sources(tx: &Transaction) -> rusqlite::Result<()> {
+  let rows: Vec<(String, String, Option<String>, String)> = {
+    let mut stmt = tx.prepare("SELECT id, source_url, source_kind, created_at FROM notes WHERE TRIM(COALESCE(source_url, '')) != '' ORDER BY created_at")?;
+    let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    rows
+  };
+  let mut link = tx.prepare("UPDATE notes SET source_id=?1 WHERE id=?2")?;
+  for (id, url, kind, created_at) in rows {
+    let (url, kind) = (url.trim(), kind.unwrap_or_else(|| "web".into()));
+    let canonical = canonical_url(url);
+    // Notes clipped from the same page share its source, as `sources::upsert` matched them in v7.
+    let existing: Option<String> = match &canonical {
+      Some(c) => tx.query_row("SELECT id FROM sources WHERE canonical_url=?1", params![c], |r| r.get(0)).optional()?,
+      None => tx.query_row("SELECT id FROM sources WHERE kind=?1 AND url=?2 AND canonical_url IS NULL", params![kind, url], |r| r.get(0)).optional()?,
+    };
+    let source_id = match existing {
+      Some(sid) => {
+        tx.execute("UPDATE sources SET url=?1, accessed_at=MAX(accessed_at,?2) WHERE id=?3", params![url, created_at, sid])?;
+        sid
+      }
+      None => {
+        let sid = Uuid::new_v4().to_string();
+        tx.execute("INSERT INTO sources (id, kind, url, canonical_url, accessed_at, metadata_json) VALUES (?1,?2,?3,?4,?5,'{}')",
+          params![sid, kind, url, canonical, created_at])?;
+        sid
+      }
+    };
+    link.execute(params![source_id, id])?;
+  }
+  Ok(())
+}
+
+// Clip screenshots were `previews/{id}.png` before there was an asset store. They stay where they
+// are and get registered under their hash, with their real type. Notes whose screenshots were
+// identical share the first one's file; the other copies stay on disk, as a migration that rolls
+// back mustn't have deleted anything.
+fn backfill_assets(tx: &Transaction) -> rusqlite::Result<()> {
+  let db_file: String = tx.query_row("SELECT file FROM pragma_database_list WHERE name='main'", [], |r| r.get(0))?;
+  let data_dir = FsPath::new(&db_file).parent().map(FsPath::to_path_buf).unwrap_or_default();
+  let rows: Vec<(String, String, String)> = {
+    let mut stmt = tx.prepare("SELECT id, preview_path, created_at FROM notes WHERE preview_path IS NOT NULL ORDER BY created_at")?;
+    let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    rows
+  };
+  for (id, rel, created_at) in rows {
+    // A preview whose file is gone keeps its path; `/file` answers 404 for it as before.
+    let Some((bytes, mime)) = read_asset(&data_dir, &rel) else { continue };
+    let hash = sha256_hex(&bytes);
+    tx.execute("INSERT INTO assets (hash, mime, size, path, created_at) VALUES (?1,?2,?3,?4,?5) ON CONFLICT(hash) DO NOTHING",
+      params![hash, mime, bytes.len() as i64, rel, created_at])?;
+    let path: String = tx.query_row("SELECT path FROM assets WHERE hash=?1", params![hash], |r| r.get(0))?;
+    tx.execute("INSERT OR IGNORE INTO note_assets (note_id, asset_hash, role, position, created_at) VALUES (?1, ?2, 'screenshot', 0, ?3)",
+      params![id, hash, created_at])?;
+    tx.execute("UPDATE notes SET preview_path=?1 WHERE id=?2", params![path, id])?;
+  }
+  Ok(())
+}
+
+fn backfill_html_text(tx: &Transaction) -> rusqlite::Result<()> {
+  let rows: Vec<(i64, String)> = {
+    let mut stmt = tx.prepare("SELECT rowid, html FROM notes WHERE html IS NOT NULL")?;
+    let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    rows
+  };
+  let mut upd = tx.prepare("UPDATE notes SET html_text=?1 WHERE rowid=?2")?;
+  for (rowid, html) in rows { upd.execute(params![html_to_text(&html), rowid])?; }
+  tx.execute_batch(
+    "DELETE FROM notes_fts;
+     INSERT INTO notes_fts(rowid, title, plaintext, html, tags)
+       SELECT rowid, title, plaintext, html_text, (SELECT COALESCE(group_concat(value, ' '), '') FROM json_each(notes.tags_json)) FROM notes;")
+}
+
+// Copies of the helpers the backfills call, as they were when those steps shipped. The live ones
+// may change with the app; an old database upgraded later must still end up the way it would have then.
+mod frozen {
+  use std::{fs, path::{Component, Path as FsPath}};
+  use sha2::{Digest, Sha256};
+
+  // Query parameters that only track how a visitor arrived; two URLs differing in these are one page.
+  const TRACKING_PARAMS: &[&str] = &["fbclid", "gclid", "dclid", "msclkid", "mc_cid", "mc_eid", "igshid", "ref_src"];
+
+  /// Host of an http(s) URL, lowercased and without `www.` or a port (v4, `source_domain`).
+  pub(super) fn url_domain(url: &str) -> Option<String> {
+    let rest = url.split_once("://").filter(|(scheme, _)| scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https"))?.1;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?.split(':').next()?.to_ascii_lowercase();
+    let host = host.strip_prefix("www.").map(str::to_string).unwrap_or(host);
+    if host.is_empty() { None } else { Some(host) }
+  }
+
+  /// An http(s) URL without its fragment and tracking parameters (`utm_*`, `fbclid`, ...). Other URLs,
+  /// like the file names PDF clips send, have no canonical form (v7, `sources`).
+  pub(super) fn canonical_url(url: &str) -> Option<String> {
+    let url = url.trim();
+    let (scheme, _) = url.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") { return None; }
+    let url = url.split('#').next().unwrap_or(url);
+    let Some((base, query)) = url.split_once('?') else { return Some(url.to_string()) };
+    let kept: Vec<&str> = query.split('&').filter(|kv| {
+      let key = kv.split('=').next().unwrap_or("").to_ascii_lowercase();
+      !kv.is_empty() && !key.starts_with("utm_") && !TRACKING_PARAMS.contains(&key.as_str())
+    }).collect();
+    Some(if kept.is_empty() { base.to_string() } else { format!("{}?{}", base, kept.join("&")) })
+  }
+
+  /// Lowercase hex SHA-256 of `bytes` (v13, asset hashes).
+  pub(super) fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+  }
+
+  /// The bytes of the file at `rel` under `data_dir` and the MIME type they look like (v13); `None`
+  /// if it can't be read or lies outside `data_dir`.
+  pub(super) fn read_asset(data_dir: &FsPath, rel: &str) -> Option<(Vec<u8>, &'static str)> {
+    if !FsPath::new(rel).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) { return None; }
+    let bytes = fs::read(data_dir.join(rel)).ok()?;
+    let starts = |magic: &[u8]| bytes.starts_with(magic);
+    let mime = if starts(b"\x89PNG\r\n\x1a\n") { "image/png" }
+      else if starts(b"\xff\xd8\xff") { "image/jpeg" }
+      else if bytes.len() >= 12 && starts(b"RIFF") && &bytes[8..12] == b"WEBP" { "image/webp" }
+      else if starts(b"GIF87a") || starts(b"GIF89a") { "image/gif" }
+      else if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" && matches!(&bytes[8..12], b"avif" | b"avis") { "image/avif" }
+      else if starts(b"%PDF-") { "application/pdf" }
+      else { "application/octet-stream" };
+    Some((bytes, mime))
+  }
+
+  // The rest reduces clip HTML to text (v5, `html_text`).
+
+  // Elements whose content isn't readable text.
+  const SKIPPED: &[&str] = &["script", "style", "noscript", "template", "head", "svg", "math", "iframe", "object"];
+  // Blocks that read as separate paragraphs, and ones that only start a new line.
+  const PARAGRAPHS: &[&str] = &["p", "h1", "h2", "h3", "h4", "h5", "h6", "blockquote", "pre", "table", "ul", "ol", "dl", "figure", "hr"];
+  const LINES: &[&str] = &[
+    "div", "section", "article", "aside", "header", "footer", "nav", "main", "li", "dt", "dd", "tr",
+    "caption", "figcaption", "address", "details", "summary", "form", "fieldset", "legend",
+  ];
+  // Cells sit side by side; keep their words apart.
+  const CELLS: &[&str] = &["td", "th"];
+
+  // HTML's Latin-1 entities, U+00A0 to U+00FF in order.
+  const LATIN1: [&str; 96] = [
+    "nbsp", "iexcl", "cent", "pound", "curren", "yen", "brvbar", "sect", "uml", "copy", "ordf", "laquo", "not",
+    "shy", "reg", "macr", "deg", "plusmn", "sup2", "sup3", "acute", "micro", "para", "middot", "cedil", "sup1",
+    "ordm", "raquo", "frac14", "frac12", "frac34", "iquest", "Agrave", "Aacute", "Acirc", "Atilde", "Auml",
+    "Aring", "AElig", "Ccedil", "Egrave", "Eacute", "Ecirc", "Euml", "Igrave", "Iacute", "Icirc", "Iuml",
+    "ETH", "Ntilde", "Ograve", "Oacute", "Ocirc", "Otilde", "Ouml", "times", "Oslash", "Ugrave", "Uacute",
+    "Ucirc", "Uuml", "Yacute", "THORN", "szlig", "agrave", "aacute", "acirc", "atilde", "auml", "aring",
+    "aelig", "ccedil", "egrave", "eacute", "ecirc", "euml", "igrave", "iacute", "icirc", "iuml", "eth",
+    "ntilde", "ograve", "oacute", "ocirc", "otilde", "ouml", "divide", "oslash", "ugrave", "uacute", "ucirc",
+    "uuml", "yacute", "thorn", "yuml",
+  ];
+
+  fn entity(name: &str) -> Option<char> {
+    if let Some(num) = name.strip_prefix('#') {
+      let code = match num.strip_prefix(['x', 'X']) { Some(hex) => u32::from_str_radix(hex, 16).ok()?, None => num.parse().ok()? };
+      return char::from_u32(code).filter(|c| *c != '\0');
+    }
+    if let Some(i) = LATIN1.iter().position(|n| *n == name) { return char::from_u32(0xa0 + i as u32); }
+    Some(match name {
+      "amp" => '&', "lt" => '<', "gt" => '>', "quot" => '"', "apos" => '\'',
+      "ndash" => '–', "mdash" => '—', "hellip" => '…', "lsquo" => '‘', "rsquo" => '’', "ldquo" => '“', "rdquo" => '”',
+      "bull" => '•', "trade" => '™', "euro" => '€',
+      _ => return None,
+    })
+  }
+
+  struct Text { out: String, space: bool, breaks: usize, pre: usize }
+
+  impl Text {
+    fn push(&mut self, c: char) {
+      if c == '\u{ad}' { return; }
+      if self.pre > 0 && c == '\n' { self.breaks += 1; return; }
+      if c.is_whitespace() { self.space = true; return; }
+      if !self.out.is_empty() {
+        if self.breaks > 0 { for _ in 0..self.breaks.min(2) { self.out.push('\n'); } }
+        else if self.space { self.out.push(' '); }
+      }
+      self.space = false; self.breaks = 0;
+      self.out.push(c);
+    }
+  }
+
+  /// Readable text of `html`: tags removed, entities decoded, `<script>`/`<style>` and the like
+  /// dropped, and block elements turned into line breaks so words on either side don't run together.
+  pub(super) fn html_to_text(html: &str) -> String {
+    let mut t = Text { out: String::with_capacity(html.len() / 2), space: false, breaks: 0, pre: 0 };
+    let lower = html.to_ascii_lowercase();
+    let mut i = 0;
+    while i < html.len() {
+      let rest = &html[i..];
+      if rest.starts_with("<!--") {
+        i += rest.find("-->").map(|e| e + 3).unwrap_or(rest.len());
+        continue;
+      }
+      if rest.starts_with('<') {
+        let Some(end) = rest.find('>') else { rest.chars().for_each(|c| t.push(c)); break };
+        let tag = &lower[i + 1..i + end];
+        let closing = tag.starts_with('/');
+        let name: String = tag.trim_start_matches('/').chars().take_while(|c| c.is_ascii_alphanumeric()).collect();
+        // `<` not followed by a tag name (`a < b`, `<!DOCTYPE>`) is text or a declaration.
+        if name.is_empty() && !tag.starts_with('!') && !tag.starts_with('?') { t.push('<'); i += 1; continue; }
+        i += end + 1;
+        if !closing && !tag.ends_with('/') && SKIPPED.contains(&name.as_str()) {
+          let close = format!("</{}", name);
+          i = lower[i..].find(&close).map(|p| i + p).unwrap_or(html.len());
+          i += html[i..].find('>').map(|e| e + 1).unwrap_or(html.len() - i);
+          continue;
+        }
+        if name == "pre" { if closing { t.pre = t.pre.saturating_sub(1) } else { t.pre += 1 } }
+        if name == "br" { t.breaks += 1; }
+        else if PARAGRAPHS.contains(&name.as_str()) { t.breaks = t.breaks.max(2); }
+        else if LINES.contains(&name.as_str()) { t.breaks = t.breaks.max(1); }
+        else if CELLS.contains(&name.as_str()) { t.space = true; }
+        continue;
+      }
+      if let Some(after) = rest.strip_prefix('&') {
+        let decoded = after.find(';').filter(|&e| e <= 10).and_then(|e| entity(&after[..e]).map(|c| (c, e + 2)));
+        if let Some((c, len)) = decoded { t.push(c); i += len; continue; }
+      }
+      let c = rest.chars().next().unwrap_or_default();
+      t.push(c);
+      i += c.len_utf8();
+    }
+    t.out
+  }
+}
+
+pub fn latest_version() -> i64 { MIGRATIONS.len() as i64 }
+
+#[derive(Debug)]
+pub enum MigrateError {
+  // The file was written by a newer LevelNotes; opening it could corrupt data we don't understand.
+  TooNew { found: i64, supported: i64 },
+  Backup { path: PathBuf, source: rusqlite::Error },
+  Step { version: i64, name: &'static str, source: rusqlite::Error },
+  Sqlite(rusqlite::Error),
+  Io(std::io::Error),
+}
+
+impl fmt::Display for MigrateError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      MigrateError::TooNew { found, supported } =>
+        write!(f, "database schema v{} is newer than this build supports (v{}); please upgrade LevelNotes", found, supported),
+      MigrateError::Backup { path, source } => write!(f, "backup to {} failed: {}", path.display(), source),
+      MigrateError::Step { version, name, source } => write!(f, "migration v{} ({}) failed: {}", version, name, source),
+      MigrateError::Sqlite(e) => write!(f, "sqlite: {}", e),
+      MigrateError::Io(e) => write!(f, "io: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for MigrateError {}
+
+impl From<rusqlite::Error> for MigrateError {
+  fn from(e: rusqlite::Error) -> Self { MigrateError::Sqlite(e) }
+}
+
+fn user_version(db: &Connection) -> rusqlite::Result<i64> {
+  db.query_row("PRAGMA user_version", [], |r| r.get(0))
+}
+
+// Databases created before versioning have user_version 0 but already hold a notes table.
+fn has_notes_table(db: &Connection) -> rusqlite::Result<bool> {
+  db.query_row("SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type='table' AND name='notes')", [], |r| r.get(0))
+}
+
+fn backup_path(db_path: &FsPath, from: i64) -> PathBuf {
+  let stamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+  let name = db_path.file_name().and_then(|n| n.to_str()).unwrap_or("levelnotes.db");
+  db_path.with_file_name(format!("{}.v{}-{}.bak", name, from, stamp))
+}
+
+/// Brings `db` up to `latest_version()`, copying the file next to `db_path` first if it holds data.
+/// Returns the backup path when one was taken.
+pub fn migrate(db: &mut Connection, db_path: &FsPath) -> Result<Option<PathBuf>, MigrateError> {
+  let current = user_version(db)?;
+  let latest = latest_version();
+  if current > latest { return Err(MigrateError::TooNew { found: current, supported: latest }); }
+  if current == latest { return Ok(None); }
+
+  let backup = if current > 0 || has_notes_table(db)? {
+    let path = backup_path(db_path, current);
+    // VACUUM INTO takes a consistent snapshot even with WAL pages not yet checkpointed.
+    db.execute("VACUUM INTO ?1", params![path.to_string_lossy()])
+      .map_err(|source| MigrateError::Backup { path: path.clone(), source })?;
+    Some(path)
+  } else { None };
+
+  for (idx, m) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+    let (version, name) = (idx as i64 + 1, m.name);
+    let step = |db: &mut Connection| -> rusqlite::Result<()> {
+      let tx = db.transaction()?;
+      tx.execute_batch(m.sql)?;
+      if let Some(backfill) = m.backfill { backfill(&tx)?; }
+      tx.pragma_update(None, "user_version", version)?;
+      tx.commit()
+    };
+    step(db).map_err(|source| MigrateError::Step { version, name, source })?;
+    println!("LevelNotes DB  migrated to v{} ({})", version, name);
+  }
+  Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A database file in a fresh directory, so backups and assets land beside it.
+  fn temp_db() -> (PathBuf, Connection) {
+    let dir = std::env::temp_dir().join(format!("levelnotes-test-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("levelnotes.db");
+    let db = Connection::open(&path).unwrap();
+    (path, db)
+  }
+
+  #[test]
+  fn upgrades_a_pre_versioning_database_with_sources() {
+    let (path, mut db) = temp_db();
+    // What the app created before schema versioning: the first step's tables at user_version 0.
+    db.execute_batch(MIGRATIONS[0].sql).unwrap();
+    db.execute_batch(
+      "INSERT INTO notes (id, created_at, title, plaintext, html, source_url, tags_json)
+         VALUES ('a', '2024-01-01T00:00:00+00:00', 'First', 'hello', '<p>hello <b>world</b></p>', 'https://example.com/post?utm_source=x', '[\"t\"]');
+       INSERT INTO notes (id, created_at, title, source_url) VALUES ('b', '2024-01-02T00:00:00+00:00', 'Second', 'https://example.com/post');
+       INSERT INTO notes (id, created_at, title, source_url) VALUES ('c', '2024-01-03T00:00:00+00:00', 'Third', 'paper.pdf');").unwrap();
+
+    let backup = migrate(&mut db, &path).unwrap();
+    assert!(backup.is_some_and(|b| b.is_file()));
+    assert_eq!(user_version(&db).unwrap(), latest_version());
+
+    let source = |id: &str| db.query_row("SELECT source_id FROM notes WHERE id=?1", params![id], |r| r.get::<_, Option<String>>(0)).unwrap();
+    assert!(source("a").is_some());
+    assert_eq!(source("a"), source("b"));
+    assert_ne!(source("a"), source("c"));
+    let canonical: String = db.query_row("SELECT canonical_url FROM sources WHERE id=?1", params![source("a")], |r| r.get(0)).unwrap();
+    assert_eq!(canonical, "https://example.com/post");
+    let html_text: String = db.query_row("SELECT html_text FROM notes WHERE id='a'", [], |r| r.get(0)).unwrap();
+    assert_eq!(html_text.trim(), "hello world");
+    let hits: i64 = db.query_row("SELECT COUNT(*) FROM notes_fts WHERE notes_fts MATCH 'world'", [], |r| r.get(0)).unwrap();
+    assert_eq!(hits, 1);
+    let revisions: i64 = db.query_row("SELECT COUNT(*) FROM note_revisions", [], |r| r.get(0)).unwrap();
+    assert_eq!(revisions, 3);
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn creates_a_new_database_without_a_backup() {
+    let (path, mut db) = temp_db();
+    assert_eq!(migrate(&mut db, &path).unwrap(), None);
+    assert_eq!(user_version(&db).unwrap(), latest_version());
+    // Already current: nothing to do.
+    assert_eq!(migrate(&mut db, &path).unwrap(), None);
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn refuses_a_newer_schema() {
+    let (path, mut db) = temp_db();
+    db.pragma_update(None, "user_version", latest_version() + 1).unwrap();
+    assert!(matches!(migrate(&mut db, &path), Err(MigrateError::TooNew { .. })));
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+}
@@ -0,0 +1,285 @@
+use serde::{Deserialize, Serialize};
+pub use crate::diff::DiffOp;
+
+// Mirrors `ClipPayload` in package/core/src/types.ts.
+#[derive(Deserialize, Default)]
+pub struct ClipPayload {
+  pub source: Option<Source>, pub selection: Option<Selection>, pub media: Option<Media>, pub ops: Option<Ops>,
+  /// `/append/:id` only: the note `version` the clip is meant for, refused with 409 as in
+  /// `UpdatePayload::expected_version` when the note has moved on.
+  #[serde(rename = "expectedVersion")] pub expected_version: Option<i64>,
+}
+#[derive(Deserialize)]
+pub struct Source {
+  pub kind: String, pub url: Option<String>, pub doi: Option<String>, pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+  /// The `/documents` PDF the clip was taken from; `ops.page` and `ops.highlights` locate it there.
+  #[serde(rename = "documentId")] pub document_id: Option<String>,
+}
+#[derive(Deserialize)] pub struct Selection { pub text: Option<String>, pub html: Option<String>, pub anchors: Option<Anchors> }
+/// Where the selection sat in the page: the XPath of the element holding it with character offsets into its text, and the quote.
+#[derive(Deserialize)]
+pub struct Anchors {
+  pub xpath: Option<String>,
+  #[serde(rename = "startOffset")] pub start_offset: Option<u32>,
+  #[serde(rename = "endOffset")] pub end_offset: Option<u32>,
+  #[serde(rename = "textQuote")] pub text_quote: Option<TextQuote>,
+}
+#[derive(Deserialize)] pub struct TextQuote { pub exact: String, pub prefix: Option<String>, pub suffix: Option<String> }
+/// Files come inline as base64 data URLs, or as the hash `/assets` returned for an upload (`*AssetId`).
+#[derive(Deserialize)] pub struct Media {
+  #[serde(rename = "screenshotDataUrl")] pub screenshot_data_url: Option<String>,
+  #[serde(rename = "screenshotAssetId")] pub screenshot_asset_id: Option<String>,
+  /// Files clipped along with the selection, kept with the note after its screenshot.
+  #[serde(default)] pub attachments: Vec<Attachment>,
+}
+#[derive(Deserialize)] pub struct Attachment {
+  #[serde(rename = "dataUrl")] pub data_url: Option<String>,
+  #[serde(rename = "assetId")] pub asset_id: Option<String>,
+  pub name: Option<String>,
+}
+#[derive(Deserialize, Serialize, Clone, Debug)] pub struct Rect { pub x: f32, pub y: f32, pub w: f32, pub h: f32 }
+#[derive(Deserialize)] pub struct Ops { pub summarize: Option<bool>, pub tags: Option<Vec<String>>, pub page: Option<i32>, pub highlights: Option<Vec<Rect>> }
+
+#[derive(Deserialize, Default)]
+pub struct UpdatePayload {
+  pub title: Option<String>,
+  pub tags: Option<Vec<String>>,
+  pub html: Option<String>,
+  pub plaintext: Option<String>,
+  /// The `version` the edit was made against. If the note has moved on since, the update is
+  /// refused with 409 and the current note, rather than overwriting what changed in between.
+  #[serde(rename = "expectedVersion")] pub expected_version: Option<i64>,
+}
+
+/// Reply to `/health`. `service` lets clients probing ports tell the API from anything else there.
+#[derive(Serialize)] pub struct HealthResponse { pub ok: bool, pub service: &'static str }
+
+#[derive(Serialize)] pub struct ClipResponse { pub ok: bool, pub note_id: String }
+// `changed` is the number of rows the write touched, so scripts can tell a no-op from a success.
+#[derive(Serialize)] pub struct OkResponse {
+  pub ok: bool, pub changed: usize,
+  /// After `/update` and `/append`: when the note now counts as last modified (its `ETag` is in the
+  /// headers), and its new `version`.
+  #[serde(skip_serializing_if = "Option::is_none")] pub updated_at: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub version: Option<i64>,
+}
+
+/// Outcome of a write to a note: rows changed, and the note's new `updated_at` and `version`.
+#[derive(Debug)] pub struct Saved { pub changed: usize, pub updated_at: String, pub version: i64 }
+
+#[derive(Serialize, Debug)]
+pub struct NoteListItem {
+  pub id: String,
+  pub title: String,
+  pub created_at: String,
+  /// Last change to the content; `created_at` for notes never edited.
+  pub updated_at: String,
+  /// Last time the note was opened through `/open/:id`.
+  pub last_opened_at: Option<String>,
+  /// Goes up by one with every change to the content; see `UpdatePayload::expected_version`.
+  pub version: i64,
+  pub source_url: Option<String>,
+  pub tags: Vec<String>,
+  pub snippet: Option<String>,
+  pub preview_path: Option<String>,
+  pub html: Option<String>,
+  /// Set only for notes in the trash.
+  #[serde(skip_serializing_if = "Option::is_none")] pub deleted_at: Option<String>,
+  /// Search results only: bm25 score (lower is a better match), `<mark>`-highlighted title and
+  /// excerpt (HTML-escaped), and where the terms hit.
+  #[serde(skip_serializing_if = "Option::is_none")] pub score: Option<f64>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub highlighted_title: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub highlighted_snippet: Option<String>,
+  #[serde(skip_serializing_if = "Vec::is_empty")] pub matches: Vec<MatchSpan>,
+}
+
+/// One search hit in `field` (`title` or `plaintext`), as UTF-16 offsets so they index JS strings directly.
+#[derive(Serialize, Debug, Clone)] pub struct MatchSpan { pub field: &'static str, pub start: usize, pub end: usize }
+
+#[derive(Serialize, Debug)]
+pub struct NoteDetail {
+  pub id: String, pub created_at: String, pub title: String,
+  pub updated_at: String, pub last_opened_at: Option<String>, pub version: i64,
+  pub plaintext: Option<String>, pub html: Option<String>,
+  pub source_url: Option<String>, pub text_quote: Option<String>,
+  pub tags: Vec<String>, pub preview_path: Option<String>,
+  pub page_number: Option<i32>, pub highlights: Vec<Rect>,
+  /// W3C Web Annotation selectors for the clipped passage within `source_url`; any one is enough to find it again.
+  pub selectors: Vec<Selector>,
+  pub source: Option<SourceRecord>,
+  /// The PDF the note was clipped from, to reopen at `page_number` with `highlights`.
+  pub document: Option<Document>,
+  /// Where each of the note's clips sits in its PDF, appended ones included, in the order they came.
+  pub clips: Vec<DocumentClip>,
+  /// Screenshots and attachments of the clips that made the note, in the order they came.
+  /// `preview_path` is one of these, or a legacy file.
+  pub assets: Vec<NoteAsset>,
+  /// When the note was moved to the trash; absent for live notes.
+  #[serde(skip_serializing_if = "Option::is_none")] pub deleted_at: Option<String>,
+}
+
+/// A file in the asset store, named by the SHA-256 of its bytes. `path` is where `/file/*path` serves it.
+#[derive(Serialize, Debug, Clone)]
+pub struct Asset { pub hash: String, pub mime: String, pub size: i64, pub path: String }
+
+/// An asset as one note uses it: `role` is `screenshot` or `attachment`, `name` an attachment's file name.
+#[derive(Serialize, Debug, Clone)]
+pub struct NoteAsset {
+  #[serde(flatten)] pub asset: Asset,
+  pub role: String, pub name: Option<String>, pub position: i64, pub created_at: String,
+}
+
+/// Query string of `/file/*path`: `size` asks for an image's thumbnail of at least that many pixels.
+#[derive(Deserialize, Default)] pub struct FileQuery { pub size: Option<u32> }
+
+/// A PDF in the store. `path` is where `/file/*path` serves it; `title` and `page_count` are read
+/// from the file, when it says.
+#[derive(Serialize, Debug, Clone)]
+pub struct Document {
+  pub id: String, pub hash: String, pub filename: Option<String>, pub title: Option<String>,
+  pub page_count: Option<u32>, pub size: i64, pub path: String, pub created_at: String,
+}
+
+/// One clip's place in a document: the 1-based page and the highlighted rectangles on it.
+#[derive(Serialize, Debug, Clone)]
+pub struct DocumentClip { pub document: Document, pub page_number: Option<i32>, pub highlights: Vec<Rect>, pub position: i64, pub created_at: String }
+
+#[derive(Serialize)] pub struct DocumentResponse { pub ok: bool, pub document: Document }
+
+/// Reply to `POST /assets`: the stored file, whose `hash` clips refer to it by.
+#[derive(Serialize)] pub struct UploadResponse { pub ok: bool, pub asset: Asset }
+
+/// Body of `/note/:id/preview`: the hash of one of the note's images, to show on its card.
+#[derive(Deserialize)] pub struct PreviewPayload { pub hash: String }
+
+/// A row of `sources`: the work a clip was taken from, shared by all notes clipped from it.
+/// `metadata` is the clip payload's `source.metadata`, merged across clips.
+#[derive(Serialize, Debug, Clone)]
+pub struct SourceRecord {
+  pub id: String, pub kind: String,
+  pub url: Option<String>, pub canonical_url: Option<String>, pub doi: Option<String>, pub isbn: Option<String>,
+  pub title: Option<String>, pub author: Option<String>, pub site_name: Option<String>,
+  pub year: Option<i32>, pub venue: Option<String>,
+  pub accessed_at: String,
+  pub metadata: serde_json::Map<String, serde_json::Value>,
+  /// Where filling in the details from the DOI, ISBN or PDF stands; absent when there was nothing to look up.
+  pub resolution: Option<Resolution>,
+}
+
+/// A source's row in `metadata_jobs`. `status` is `pending`, `resolved`, `not_found` (no resolver
+/// knew it) or `failed` (gave up after repeated errors, the last of which is `last_error`).
+#[derive(Serialize, Debug, Clone)]
+pub struct Resolution {
+  pub status: String, pub attempts: u32,
+  #[serde(skip_serializing_if = "Option::is_none")] pub next_attempt_at: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub last_error: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")] pub resolved_by: Option<String>,
+}
+
+/// A selector from the W3C Web Annotation data model, tagged by `type` as in the spec.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum Selector {
+  TextQuoteSelector {
+    exact: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")] prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] suffix: Option<String>,
+  },
+  XPathSelector {
+    value: String,
+    #[serde(rename = "refinedBy", default, skip_serializing_if = "Option::is_none")] refined_by: Option<Box<Selector>>,
+  },
+  TextPositionSelector { start: u32, end: u32 },
+}
+
+impl Anchors {
+  /// The anchors as selectors; offsets only mean something relative to the XPath element, so they refine it.
+  pub fn selectors(&self) -> Vec<Selector> {
+    let mut out = Vec::new();
+    if let Some(q) = self.text_quote.as_ref().filter(|q| !q.exact.is_empty()) {
+      out.push(Selector::TextQuoteSelector { exact: q.exact.clone(), prefix: q.prefix.clone(), suffix: q.suffix.clone() });
+    }
+    if let Some(xpath) = self.xpath.as_ref().filter(|x| !x.trim().is_empty()) {
+      let refined_by = match (self.start_offset, self.end_offset) {
+        (Some(start), Some(end)) if start <= end => Some(Box::new(Selector::TextPositionSelector { start, end })),
+        _ => None,
+      };
+      out.push(Selector::XPathSelector { value: xpath.clone(), refined_by });
+    }
+    out
+  }
+}
+
+/// Query string of `/notes` and `/search`. `tag` may list several tags, comma-separated; all must match.
+#[derive(Deserialize, Default)]
+pub struct ListQuery {
+  pub q: Option<String>,
+  pub limit: Option<usize>,
+  pub cursor: Option<String>,
+  /// `created`, `updated`, `opened`, `title`, `relevance` (needs `q`) or `deleted` (trash only). Defaults to
+  /// `relevance` with `q`, else `deleted` in the trash and `created` elsewhere.
+  pub sort: Option<String>,
+  pub order: Option<String>,
+  pub tag: Option<String>,
+  pub source_kind: Option<String>,
+  pub source_domain: Option<String>,
+  /// Notes linked to this `sources` row.
+  pub source_id: Option<String>,
+  /// Notes whose source has this DOI (any of the forms `normalize_doi` accepts).
+  pub doi: Option<String>,
+  pub has_doi: Option<bool>,
+  pub from: Option<String>,
+  pub to: Option<String>,
+  pub has_preview: Option<bool>,
+  /// List the trash instead of live notes; set by `/trash`, not from the query string.
+  #[serde(skip)] pub trashed: bool,
+}
+
+/// One entry of `/note/:id/revisions`. `origin` is the kind of write that produced it: `create`,
+/// `update`, `append`, `restore` (of revision `restored_from`) or `import` (content from before
+/// revisions were kept). `chars` is the length of its plaintext.
+#[derive(Serialize, Debug)]
+pub struct RevisionSummary {
+  pub id: i64, pub created_at: String, pub updated_at: String, pub origin: String,
+  #[serde(skip_serializing_if = "Option::is_none")] pub restored_from: Option<i64>,
+  pub title: String, pub chars: usize,
+}
+
+/// A note's content as of one revision. `updated_at` moves when autosaves are folded into it.
+#[derive(Serialize, Debug)]
+pub struct Revision {
+  pub id: i64, pub note_id: String, pub created_at: String, pub updated_at: String, pub origin: String,
+  #[serde(skip_serializing_if = "Option::is_none")] pub restored_from: Option<i64>,
+  pub title: String, pub plaintext: Option<String>, pub html: Option<String>, pub tags: Vec<String>,
+}
+
+/// `/note/:id/diff`: word-level changes per field, and the newer HTML with `<ins>`/`<del>` marks.
+/// `from` is absent when the diff is against an empty note.
+#[derive(Serialize, Debug)]
+pub struct RevisionDiff {
+  pub from: Option<i64>, pub to: i64,
+  pub title: Vec<DiffOp>, pub text: Vec<DiffOp>, pub html: Vec<DiffOp>, pub html_marked: String,
+  pub tags_added: Vec<String>, pub tags_removed: Vec<String>,
+}
+
+#[derive(Deserialize, Default)] pub struct DiffQuery { pub from: Option<i64>, pub to: Option<i64> }
+#[derive(Serialize)] pub struct RestoreResponse { pub ok: bool, pub revision: i64 }
+
+/// Query string of `/purge`. Without `ids` (comma-separated) the whole trash is purged;
+/// `older_than_days` limits it to notes deleted at least that long ago.
+#[derive(Deserialize, Default)]
+pub struct PurgeQuery { pub ids: Option<String>, pub older_than_days: Option<u32> }
+
+/// How many notes `/purge` removed for good, and how many asset files went with them.
+#[derive(Serialize, Debug)] pub struct PurgeResponse { pub ok: bool, pub purged: usize, pub files_removed: usize }
+
+/// Query string of `/citations`: `format` is `bib`, `ris` or `csl.json`; `ids` is comma-separated.
+#[derive(Deserialize, Default)]
+pub struct CitationQuery { pub format: String, pub ids: Option<String>, pub tag: Option<String> }
+
+#[derive(Serialize, Debug)] pub struct NotePage { pub items: Vec<NoteListItem>, pub next_cursor: Option<String> }
+
+#[derive(Deserialize)] pub struct PairPayload { pub code: String, pub name: Option<String> }
+#[derive(Serialize)] pub struct PairStartResponse { pub ok: bool, pub code: String, pub expires_in: u64 }
+#[derive(Serialize)] pub struct PairResponse { pub ok: bool, pub token: String }
+#[derive(Serialize, Debug)] pub struct PairedClient { pub origin: String, pub name: Option<String>, pub paired_at: String }
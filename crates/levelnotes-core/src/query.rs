@@ -0,0 +1,177 @@
+use crate::error::{ApiError, ApiResult};
+
+/// `q` of `/search` and `/notes`, parsed. Supported syntax:
+///
+/// - `word`, `"exact phrase"`, and a trailing `*` for prefixes (`clim*`, `"climate chan"*`)
+/// - `-word` / `-"phrase"` to exclude
+/// - `tag:name`, `source:pdf|web|image|domain`, optionally negated (`-tag:draft`)
+/// - `after:DATE` (on or after) and `before:DATE` (strictly before), dates as in `from`/`to`
+///
+/// Everything else is literal text: nothing the user types reaches FTS5 unquoted.
+#[derive(Default, Debug)]
+pub(crate) struct SearchQuery {
+  /// FTS5 expression for the positive terms, all of which must match.
+  pub fts: Option<String>,
+  /// FTS5 expression matching notes to drop (any excluded term).
+  pub exclude_fts: Option<String>,
+  pub tags: Vec<String>,
+  pub not_tags: Vec<String>,
+  pub sources: Vec<String>,
+  pub not_sources: Vec<String>,
+  pub before: Option<String>,
+  pub after: Option<String>,
+}
+
+enum Field { Tag, Source, Before, After }
+
+impl Field {
+  fn parse(name: &str) -> Option<Field> {
+    match name.to_ascii_lowercase().as_str() {
+      "tag" => Some(Field::Tag),
+      "source" => Some(Field::Source),
+      "before" => Some(Field::Before),
+      "after" => Some(Field::After),
+      _ => None,
+    }
+  }
+}
+
+// An FTS5 string literal; the tokenizer still splits it into words, so `c++` searches for `c`.
+fn fts_phrase(text: &str, prefix: bool) -> String {
+  let quoted = format!("\"{}\"", text.replace('"', "\"\""));
+  if prefix { quoted + " *" } else { quoted }
+}
+
+fn invalid(msg: String) -> ApiError { ApiError::Validation(format!("{} (search syntax: words, \"phrases\", -exclude, prefix*, tag:, source:, before:, after:)", msg)) }
+
+struct Lexer<'a> { s: &'a str, pos: usize }
+
+impl<'a> Lexer<'a> {
+  fn rest(&self) -> &'a str { &self.s[self.pos..] }
+
+  fn skip_space(&mut self) { self.pos = self.s.len() - self.rest().trim_start().len(); }
+
+  // A quoted phrase (the opening quote is next) or a bare run up to whitespace, plus whether it ended in `*`.
+  fn value(&mut self) -> ApiResult<(String, bool)> {
+    let rest = self.rest();
+    if let Some(body) = rest.strip_prefix('"') {
+      let end = body.find('"').ok_or_else(|| invalid(format!("unclosed quote in {:?}; add the closing \"", rest)))?;
+      self.pos += end + 2;
+      let prefix = self.rest().starts_with('*');
+      if prefix { self.pos += 1; }
+      return Ok((body[..end].to_string(), prefix));
+    }
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    self.pos += end;
+    let word = &rest[..end];
+    match word.strip_suffix('*') {
+      Some(w) => Ok((w.to_string(), true)),
+      None => Ok((word.to_string(), false)),
+    }
+  }
+}
+
+pub(crate) fn parse(q: &str) -> ApiResult<SearchQuery> {
+  let mut out = SearchQuery::default();
+  let (mut include, mut exclude) = (Vec::new(), Vec::new());
+  let mut lx = Lexer { s: q, pos: 0 };
+  loop {
+    lx.skip_space();
+    if lx.rest().is_empty() { break; }
+    let negate = lx.rest().len() > 1 && lx.rest().starts_with('-') && !lx.rest()[1..].starts_with(char::is_whitespace);
+    if negate { lx.pos += 1; }
+
+    // `name:` is only a filter for the names above; `http://…` or `foo:bar` stays plain text.
+    let field = lx.rest().split_once(':')
+      .filter(|(name, _)| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphabetic()))
+      .and_then(|(name, _)| Field::parse(name).map(|f| (f, name.len())));
+    if let Some((field, len)) = field {
+      let name = &lx.rest()[..len];
+      lx.pos += len + 1;
+      let (value, prefix) = lx.value()?;
+      let value = value.trim().to_string();
+      if value.is_empty() { return Err(invalid(format!("{}: needs a value right after the colon", name))); }
+      if prefix { return Err(invalid(format!("{}: doesn't take a * prefix", name))); }
+      match (field, negate) {
+        (Field::Tag, false) => out.tags.push(value),
+        (Field::Tag, true) => out.not_tags.push(value),
+        (Field::Source, false) => out.sources.push(value.to_ascii_lowercase()),
+        (Field::Source, true) => out.not_sources.push(value.to_ascii_lowercase()),
+        (Field::Before | Field::After, true) => return Err(invalid(format!("{}: can't be negated; use the other of before:/after:", name))),
+        (Field::Before, false) => out.before = Some(value),
+        (Field::After, false) => out.after = Some(value),
+      }
+      continue;
+    }
+
+    let (text, prefix) = lx.value()?;
+    // Pure punctuation (`-`, `++`) tokenizes to nothing, and an empty phrase would match no note at all.
+    if !text.chars().any(char::is_alphanumeric) {
+      if prefix { return Err(invalid("* has to follow a word, as in clim*".into())); }
+      continue;
+    }
+    if negate { exclude.push(fts_phrase(&text, prefix)) } else { include.push(fts_phrase(&text, prefix)) }
+  }
+  if !include.is_empty() { out.fts = Some(include.join(" AND ")); }
+  if !exclude.is_empty() { out.exclude_fts = Some(exclude.join(" OR ")); }
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fts(q: &str) -> (Option<String>, Option<String>) {
+    let parsed = parse(q).unwrap();
+    (parsed.fts, parsed.exclude_fts)
+  }
+
+  fn rejects(q: &str) -> String {
+    match parse(q) {
+      Err(e) => { assert_eq!(e.status(), axum::http::StatusCode::BAD_REQUEST); e.message().to_string() }
+      Ok(parsed) => panic!("{:?} parsed as {:?}", q, parsed),
+    }
+  }
+
+  #[test]
+  fn quotes_words_and_phrases() {
+    assert_eq!(fts(r#"climate  "sea level" -coal -"fossil fuel""#),
+      (Some(r#""climate" AND "sea level""#.into()), Some(r#""coal" OR "fossil fuel""#.into())));
+    assert_eq!(fts(r#"say"hi""#).0.as_deref(), Some(r#""say""hi""""#));
+    assert_eq!(fts(""), (None, None));
+  }
+
+  #[test]
+  fn takes_a_trailing_star_as_a_prefix() {
+    assert_eq!(fts(r#"run* "climate chan"* -draft*"#),
+      (Some(r#""run" * AND "climate chan" *"#.into()), Some(r#""draft" *"#.into())));
+    assert!(rejects("*").contains("has to follow a word"));
+  }
+
+  #[test]
+  fn reads_field_prefixes() {
+    let q = parse(r#"tag:Reading -tag:"to do" source:PDF -source:example.com after:2024-01-01 before:2024-02-01 notes"#).unwrap();
+    assert_eq!((q.tags, q.not_tags), (vec!["Reading".to_string()], vec!["to do".to_string()]));
+    assert_eq!((q.sources, q.not_sources), (vec!["pdf".to_string()], vec!["example.com".to_string()]));
+    assert_eq!((q.after.as_deref(), q.before.as_deref()), (Some("2024-01-01"), Some("2024-02-01")));
+    assert_eq!(q.fts.as_deref(), Some(r#""notes""#));
+    assert_eq!(parse("TAG:x").unwrap().tags, ["x"]);
+  }
+
+  #[test]
+  fn keeps_everything_else_literal() {
+    assert_eq!(fts("c++ foo:bar https://example.com/a?b=c").0.as_deref(), Some(r#""c++" AND "foo:bar" AND "https://example.com/a?b=c""#));
+    assert_eq!(fts("AND OR NOT NEAR(x)").0.as_deref(), Some(r#""AND" AND "OR" AND "NOT" AND "NEAR(x)""#));
+    // A lone `-` or pure punctuation matches nothing, so it is dropped rather than searched for.
+    assert_eq!(fts("- ++ word -"), (Some(r#""word""#.into()), None));
+  }
+
+  #[test]
+  fn rejects_what_it_cannot_read() {
+    assert!(rejects(r#"climate "sea level"#).contains("unclosed quote"));
+    assert!(rejects(r#"tag:"draft"#).contains("unclosed quote"));
+    assert!(rejects("tag: draft").contains("needs a value"));
+    assert!(rejects("tag:dra*").contains("doesn't take a * prefix"));
+    assert!(rejects("-before:2024-01-01").contains("can't be negated"));
+  }
+}
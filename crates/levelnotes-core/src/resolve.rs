@@ -0,0 +1,657 @@
+//! Fills in a source's missing title, authors, year and venue from its DOI, ISBN or PDF file, without
+//! going online. Lookups are queued in `metadata_jobs` when a clip links a source (see
+//! `sources::upsert`) and worked off in the background by `Enricher`; a resolver error is recorded
+//! on the job and retried later, and never fails the clip.
+
+use std::{collections::HashMap, fmt, fs, io, path::{Path as FsPath, PathBuf}, sync::Arc, time::Duration};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Notify;
+use crate::{config::Config, error::ApiResult, sources::{normalize_doi, normalize_isbn}, store::NoteStore, text::html_to_text};
+
+/// Attempts before a job that keeps erroring is marked `failed`.
+pub const MAX_ATTEMPTS: u32 = 5;
+// Wait before retry n is RETRY_BASE * 4^(n-1): 30s, 2m, 8m, 32m.
+const RETRY_BASE: Duration = Duration::from_secs(30);
+// How often the worker looks for due retries when nothing wakes it.
+const IDLE_POLL: Duration = Duration::from_secs(60);
+const BATCH: usize = 20;
+
+/// What a resolver gets to go on: the source's identifiers, and for PDFs the file it came from.
+#[derive(Debug, Clone)]
+pub struct Lookup { pub doi: Option<String>, pub isbn: Option<String>, pub kind: String, pub url: Option<String> }
+
+impl Lookup {
+  /// What resolvers key their records by, in the order they are tried: the normalized DOI, then
+  /// `isbn-` and the ISBN's 13 digits.
+  pub fn keys(&self) -> Vec<String> {
+    self.doi.iter().cloned().chain(self.isbn.iter().map(|i| format!("isbn-{}", i))).collect()
+  }
+}
+
+/// Details a resolver found. Empty fields leave what the source already has.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ResolvedMetadata {
+  pub title: Option<String>,
+  #[serde(default)] pub authors: Vec<String>,
+  pub year: Option<i32>,
+  pub venue: Option<String>,
+}
+
+impl ResolvedMetadata {
+  fn is_empty(&self) -> bool { self.title.is_none() && self.authors.is_empty() && self.year.is_none() && self.venue.is_none() }
+}
+
+/// Why a lookup could not be answered this time; the job is retried.
+#[derive(Debug)]
+pub struct ResolveError(pub String);
+
+impl fmt::Display for ResolveError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(&self.0) }
+}
+
+impl std::error::Error for ResolveError {}
+
+impl From<io::Error> for ResolveError {
+  fn from(e: io::Error) -> Self { ResolveError(e.to_string()) }
+}
+
+/// One way of finding a source's details. `Ok(None)` means "not mine or not known here", and the
+/// next resolver in line gets a turn.
+pub trait MetadataResolver: Send + Sync {
+  fn name(&self) -> &'static str;
+  fn resolve(&self, lookup: &Lookup) -> Result<Option<ResolvedMetadata>, ResolveError>;
+}
+
+// The JSON in `path`, or None if there is no such file.
+fn read_json(path: &FsPath) -> Result<Option<Value>, ResolveError> {
+  match fs::read(path) {
+    Ok(bytes) => serde_json::from_slice(&bytes).map(Some).map_err(|e| ResolveError(format!("{}: {}", path.display(), e))),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(ResolveError(format!("{}: {}", path.display(), e))),
+  }
+}
+
+/// Work records in the Crossref REST API's JSON shape (`/works/{doi}`), read from files named by a
+/// `Lookup::keys` key with every character outside `[a-z0-9._-]` percent-encoded, plus `.json`.
+/// `10.1000/xyz` is looked up as `10.1000%2Fxyz.json`.
+pub struct CrossrefResolver { dir: PathBuf }
+
+impl CrossrefResolver {
+  pub fn new(dir: impl Into<PathBuf>) -> Self { CrossrefResolver { dir: dir.into() } }
+
+  pub fn file_name(lookup_key: &str) -> String {
+    let mut name = String::with_capacity(lookup_key.len() + 5);
+    for b in lookup_key.bytes() {
+      if b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'.' | b'_' | b'-') { name.push(b as char); }
+      else { name.push_str(&format!("%{:02X}", b)); }
+    }
+    name + ".json"
+  }
+}
+
+// The first string of a Crossref field, which is usually a one-element list.
+fn first_str(v: &Value, key: &str) -> Option<String> {
+  match v.get(key)? {
+    Value::String(s) => Some(s.clone()),
+    Value::Array(items) => items.iter().find_map(Value::as_str).map(str::to_string),
+    _ => None,
+  }.map(|s| html_to_text(&s)).filter(|s| !s.is_empty())
+}
+
+/// Title, authors, year and venue of a Crossref work record, bare or wrapped in `{"message": ...}`.
+pub fn parse_crossref(v: &Value) -> Option<ResolvedMetadata> {
+  let work = v.get("message").unwrap_or(v);
+  let authors = work.get("author").and_then(Value::as_array).map(|list| list.iter().filter_map(|a| {
+    let part = |k: &str| a.get(k).and_then(Value::as_str).map(str::trim).filter(|s| !s.is_empty());
+    match (part("given"), part("family")) {
+      (Some(given), Some(family)) => Some(format!("{}, {}", family, given)),
+      (None, Some(family)) => Some(family.to_string()),
+      _ => part("name").map(str::to_string),
+    }
+  }).collect()).unwrap_or_default();
+  let year = ["published-print", "published-online", "issued", "published", "created"].iter()
+    .filter_map(|k| work.get(*k)?.get("date-parts")?.get(0)?.get(0)?.as_i64()).next().map(|y| y as i32);
+  let venue = first_str(work, "container-title")
+    .or_else(|| work.get("event").and_then(|e| first_str(e, "name")))
+    .or_else(|| first_str(work, "publisher"));
+  let meta = ResolvedMetadata { title: first_str(work, "title"), authors, year, venue };
+  (!meta.is_empty()).then_some(meta)
+}
+
+impl MetadataResolver for CrossrefResolver {
+  fn name(&self) -> &'static str { "crossref" }
+
+  fn resolve(&self, lookup: &Lookup) -> Result<Option<ResolvedMetadata>, ResolveError> {
+    for key in lookup.keys() {
+      if let Some(v) = read_json(&self.dir.join(Self::file_name(&key)))? {
+        return parse_crossref(&v).map(Some).ok_or_else(|| ResolveError(format!("{}: not a Crossref work record", Self::file_name(&key))));
+      }
+    }
+    Ok(None)
+  }
+}
+
+/// Details embedded in a PDF clip's file: the XMP packet if there is one, else the document
+/// information dictionary. PDF clips carry a file name, which is read as given when it is an
+/// absolute path inside the asset store `assets`, else looked for in each of `dirs`; the URL is
+/// the client's, so nothing outside those is opened. Info dictionaries inside compressed object
+/// streams are not read.
+pub struct PdfResolver { assets: PathBuf, dirs: Vec<PathBuf> }
+
+impl PdfResolver {
+  pub fn new(assets: PathBuf, dirs: Vec<PathBuf>) -> Self { PdfResolver { assets, dirs } }
+
+  fn locate(&self, url: &str) -> Option<PathBuf> {
+    let path = PathBuf::from(url.strip_prefix("file://").unwrap_or(url));
+    if path.is_absolute() {
+      // Resolved first, so neither `..` nor a symlink leads out of the store.
+      let (path, assets) = (path.canonicalize().ok()?, self.assets.canonicalize().ok()?);
+      return (path.starts_with(&assets) && path.is_file()).then_some(path);
+    }
+    let name = path.file_name()?;
+    self.dirs.iter().map(|d| d.join(name)).find(|p| p.is_file())
+  }
+}
+
+// Text of the first XMP element `tag`, or of each `rdf:li` inside it.
+fn xmp_values(xmp: &str, tag: &str) -> Vec<String> {
+  let Some(start) = xmp.find(&format!("<{}", tag)) else { return Vec::new() };
+  let rest = &xmp[start..];
+  let Some(body_start) = rest.find('>').map(|i| i + 1) else { return Vec::new() };
+  // A self-closing element has no text; some producers write the value as an attribute (`xmp_attr`).
+  if rest[..body_start].ends_with("/>") { return Vec::new(); }
+  let Some(end) = rest.find(&format!("</{}>", tag)) else { return Vec::new() };
+  let body = &rest[body_start..end];
+  let items: Vec<String> = body.split("<rdf:li").skip(1)
+    .filter_map(|li| { let open = li.find('>')?; let close = li.find("</rdf:li>")?; Some(html_to_text(&li[open + 1..close])) })
+    .filter(|s| !s.is_empty()).collect();
+  if !items.is_empty() || body.contains("<rdf:") { return items; }
+  let text = html_to_text(body);
+  if text.is_empty() { Vec::new() } else { vec![text] }
+}
+
+// `name="value"` written as an attribute, as some producers do for simple XMP properties.
+fn xmp_attr(xmp: &str, name: &str) -> Option<String> {
+  let start = xmp.find(&format!("{}=\"", name))? + name.len() + 2;
+  let end = xmp[start..].find('"')?;
+  Some(html_to_text(&xmp[start..start + end])).filter(|s| !s.is_empty())
+}
+
+fn xmp_metadata(bytes: &[u8]) -> Option<ResolvedMetadata> {
+  let start = find_bytes(bytes, b"<x:xmpmeta")?;
+  let end = find_bytes(&bytes[start..], b"</x:xmpmeta>")? + start;
+  let xmp = String::from_utf8_lossy(&bytes[start..end]);
+  let one = |tag: &str| xmp_values(&xmp, tag).into_iter().next().or_else(|| xmp_attr(&xmp, tag));
+  let year = ["prism:coverDate", "prism:publicationDate", "dc:date", "xmp:CreateDate"].iter()
+    .filter_map(|t| one(t)).find_map(|d| d.get(..4)?.parse().ok());
+  let meta = ResolvedMetadata {
+    title: one("dc:title"),
+    authors: xmp_values(&xmp, "dc:creator"),
+    year,
+    venue: one("prism:publicationName"),
+  };
+  (!meta.is_empty()).then_some(meta)
+}
+
+fn find_bytes(hay: &[u8], needle: &[u8]) -> Option<usize> { hay.windows(needle.len()).position(|w| w == needle) }
+
+fn rfind_bytes(hay: &[u8], needle: &[u8]) -> Option<usize> { hay.windows(needle.len()).rposition(|w| w == needle) }
+
+// A PDF text string: UTF-16BE after a byte-order mark, else PDFDocEncoding, read as Latin-1.
+fn pdf_text(raw: &[u8]) -> String {
+  if let Some(utf16) = raw.strip_prefix(&[0xfe, 0xff]) {
+    let units: Vec<u16> = utf16.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+    return String::from_utf16_lossy(&units);
+  }
+  raw.iter().map(|&b| b as char).collect()
+}
+
+// The string object at the start of `s`: `(literal)` with escapes and nested parentheses, or `<hex>`.
+fn pdf_string(s: &[u8]) -> Option<Vec<u8>> {
+  match *s.first()? {
+    b'(' => {
+      let (mut out, mut depth, mut i) = (Vec::new(), 1, 1);
+      while i < s.len() {
+        match s[i] {
+          b'\\' => {
+            i += 1;
+            match *s.get(i)? {
+              b'n' => out.push(b'\n'), b'r' => out.push(b'\r'), b't' => out.push(b'\t'),
+              b'b' => out.push(8), b'f' => out.push(12),
+              b'\r' | b'\n' => {}
+              d @ b'0'..=b'7' => {
+                let mut v = (d - b'0') as u32;
+                for _ in 0..2 {
+                  match s.get(i + 1) { Some(n @ b'0'..=b'7') => { v = v * 8 + (n - b'0') as u32; i += 1; } _ => break }
+                }
+                out.push(v as u8);
+              }
+              c => out.push(c),
+            }
+          }
+          b'(' => { depth += 1; out.push(b'('); }
+          b')' => { depth -= 1; if depth == 0 { return Some(out); } out.push(b')'); }
+          c => out.push(c),
+        }
+        i += 1;
+      }
+      None
+    }
+    b'<' => {
+      let end = s.iter().position(|&b| b == b'>')?;
+      let hex: Vec<u8> = s[1..end].iter().copied().filter(|b| b.is_ascii_hexdigit()).collect();
+      Some(hex.chunks(2).map(|p| {
+        let hi = (p[0] as char).to_digit(16).unwrap();
+        let lo = p.get(1).and_then(|b| (*b as char).to_digit(16)).unwrap_or(0);
+        (hi * 16 + lo) as u8
+      }).collect())
+    }
+    _ => None,
+  }
+}
+
+// `/Key (value)` from the info dictionary. The last occurrence wins, as incremental updates append.
+fn info_entry(bytes: &[u8], key: &str) -> Option<String> {
+  let needle = format!("/{}", key);
+  let mut hay = bytes;
+  while let Some(at) = rfind_bytes(hay, needle.as_bytes()) {
+    let after = &hay[at + needle.len()..];
+    let value = after.iter().position(|b| !b.is_ascii_whitespace()).map(|p| &after[p..]);
+    if let Some(text) = value.filter(|v| matches!(v.first(), Some(b'(' | b'<')) && v.get(1) != Some(&b'<')).and_then(pdf_string) {
+      let text = pdf_text(&text).trim().to_string();
+      if !text.is_empty() { return Some(text); }
+    }
+    hay = &hay[..at];
+  }
+  None
+}
+
+fn info_metadata(bytes: &[u8]) -> Option<ResolvedMetadata> {
+  let authors = info_entry(bytes, "Author").map(|a| a.split([';', '\n']).map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect()).unwrap_or_default();
+  let year = info_entry(bytes, "CreationDate").and_then(|d| d.trim_start_matches("D:").get(..4)?.parse().ok());
+  let meta = ResolvedMetadata { title: info_entry(bytes, "Title"), authors, year, venue: None };
+  (!meta.is_empty()).then_some(meta)
+}
+
+/// Title, authors and year embedded in a PDF's bytes, from its XMP packet or info dictionary.
+pub(crate) fn pdf_metadata(bytes: &[u8]) -> Option<ResolvedMetadata> { xmp_metadata(bytes).or_else(|| info_metadata(bytes)) }
+
+/// How many pages a PDF has: the `/Count` of its page tree's root, the largest of the `/Type /Pages`
+/// nodes. Not found when the page tree is inside a compressed object stream.
+pub(crate) fn pdf_page_count(bytes: &[u8]) -> Option<u32> {
+  let mut count = None;
+  let mut from = 0;
+  while let Some(at) = find_bytes(&bytes[from..], b"/Count").map(|i| i + from) {
+    from = at + b"/Count".len();
+    let start = rfind_bytes(&bytes[..at], b"<<").unwrap_or(0);
+    let end = find_bytes(&bytes[at..], b">>").map_or(bytes.len(), |i| at + i);
+    let dict: Vec<u8> = bytes[start..end].iter().copied().filter(|b| !b.is_ascii_whitespace()).collect();
+    if find_bytes(&dict, b"/Type/Pages").is_none() { continue; }
+    let digits: String = bytes[from..].iter().skip_while(|b| b.is_ascii_whitespace()).take_while(|b| b.is_ascii_digit()).map(|&b| b as char).collect();
+    count = count.max(digits.parse::<u32>().ok());
+  }
+  count
+}
+
+impl MetadataResolver for PdfResolver {
+  fn name(&self) -> &'static str { "pdf" }
+
+  fn resolve(&self, lookup: &Lookup) -> Result<Option<ResolvedMetadata>, ResolveError> {
+    if lookup.kind != "pdf" { return Ok(None); }
+    let Some(path) = lookup.url.as_deref().and_then(|u| self.locate(u)) else { return Ok(None) };
+    let bytes = fs::read(&path)?;
+    if !bytes.starts_with(b"%PDF-") { return Err(ResolveError(format!("{} is not a PDF", path.display()))); }
+    Ok(pdf_metadata(&bytes))
+  }
+}
+
+/// Canned answers keyed as in `Lookup::keys` (a DOI or `isbn-{13 digits}`), for tests and demos. An entry of the form
+/// `{"error": "..."}` fails with that message, to exercise retries.
+#[derive(Default)]
+pub struct FixtureResolver { entries: HashMap<String, Result<ResolvedMetadata, String>> }
+
+impl FixtureResolver {
+  pub fn new() -> Self { Self::default() }
+
+  /// Reads a JSON object mapping keys to `ResolvedMetadata` or `{"error": ...}`.
+  pub fn from_file(path: &FsPath) -> Result<Self, ResolveError> {
+    let v = read_json(path)?.ok_or_else(|| ResolveError(format!("{}: no such file", path.display())))?;
+    let obj = v.as_object().ok_or_else(|| ResolveError(format!("{}: expected a JSON object", path.display())))?;
+    let mut fixtures = Self::new();
+    for (key, entry) in obj {
+      let value = match entry.get("error").and_then(Value::as_str) {
+        Some(msg) => Err(msg.to_string()),
+        None => Ok(serde_json::from_value(entry.clone()).map_err(|e| ResolveError(format!("{}: {}: {}", path.display(), key, e)))?),
+      };
+      fixtures.entries.insert(Self::key(key), value);
+    }
+    Ok(fixtures)
+  }
+
+  pub fn insert(&mut self, key: &str, meta: ResolvedMetadata) -> &mut Self { self.entries.insert(Self::key(key), Ok(meta)); self }
+
+  pub fn fail(&mut self, key: &str, message: &str) -> &mut Self { self.entries.insert(Self::key(key), Err(message.to_string())); self }
+
+  // `key` in the form `Lookup::keys` gives, so `doi:`/doi.org forms and hyphenated ISBNs still match.
+  fn key(key: &str) -> String {
+    let key = key.trim();
+    if let Some(doi) = normalize_doi(key) { return doi; }
+    let isbn = key.get(..5).filter(|p| p.eq_ignore_ascii_case("isbn-")).and_then(|_| normalize_isbn(&key[5..]));
+    match isbn { Some(isbn) => format!("isbn-{}", isbn), None => key.to_ascii_lowercase() }
+  }
+}
+
+impl MetadataResolver for FixtureResolver {
+  fn name(&self) -> &'static str { "fixture" }
+
+  fn resolve(&self, lookup: &Lookup) -> Result<Option<ResolvedMetadata>, ResolveError> {
+    for key in lookup.keys() {
+      match self.entries.get(&key) {
+        Some(Ok(meta)) => return Ok(Some(meta.clone())),
+        Some(Err(msg)) => return Err(ResolveError(msg.clone())),
+        None => {}
+      }
+    }
+    Ok(None)
+  }
+}
+
+/// The resolvers `config` asks for, in the order they are tried: fixtures (if configured), then
+/// Crossref records under `metadata_dir/crossref`, then embedded PDF metadata (of stored assets, or
+/// PDFs named relatively in `metadata_dir/pdf`).
+pub fn resolvers_for(config: &Config) -> Result<Vec<Box<dyn MetadataResolver>>, ResolveError> {
+  let mut resolvers: Vec<Box<dyn MetadataResolver>> = Vec::new();
+  if let Some(path) = &config.metadata_fixtures { resolvers.push(Box::new(FixtureResolver::from_file(path)?)); }
+  resolvers.push(Box::new(CrossrefResolver::new(config.metadata_dir.join("crossref"))));
+  resolvers.push(Box::new(PdfResolver::new(config.data_dir.join("assets"), vec![config.metadata_dir.join("pdf")])));
+  Ok(resolvers)
+}
+
+fn retry_delay(attempts: u32) -> Duration { RETRY_BASE * 4u32.pow(attempts.saturating_sub(1).min(8)) }
+
+/// Works off `metadata_jobs` with a chain of resolvers. Cheap to clone; `serve` runs one per API.
+#[derive(Clone)]
+pub struct Enricher { store: NoteStore, resolvers: Arc<Vec<Box<dyn MetadataResolver>>>, wake: Arc<Notify> }
+
+impl Enricher {
+  pub fn new(store: NoteStore, resolvers: Vec<Box<dyn MetadataResolver>>) -> Self {
+    Enricher { store, resolvers: Arc::new(resolvers), wake: Arc::new(Notify::new()) }
+  }
+
+  /// Asks the worker to look for due jobs now rather than at its next poll.
+  pub fn wake(&self) { self.wake.notify_one(); }
+
+  // The first answer in resolver order. Errors only count if no resolver had an answer.
+  fn resolve(&self, lookup: &Lookup) -> Result<Option<(&'static str, ResolvedMetadata)>, ResolveError> {
+    let mut errors = Vec::new();
+    for r in self.resolvers.iter() {
+      match r.resolve(lookup) {
+        Ok(Some(meta)) if !meta.is_empty() => return Ok(Some((r.name(), meta))),
+        Ok(_) => {}
+        Err(e) => errors.push(format!("{}: {}", r.name(), e)),
+      }
+    }
+    if errors.is_empty() { Ok(None) } else { Err(ResolveError(errors.join("; "))) }
+  }
+
+  /// Runs every job that is due now, once. Returns how many it ran.
+  pub fn run_due(&self) -> ApiResult<usize> {
+    let now = Utc::now();
+    let due: Vec<(String, u32, Lookup)> = {
+      let db = self.store.conn()?;
+      let mut stmt = db.prepare(
+        "SELECT j.source_id, j.attempts, s.doi, s.isbn, s.kind, s.url FROM metadata_jobs j JOIN sources s ON s.id = j.source_id
+         WHERE j.status = 'pending' AND j.next_attempt_at <= ?1 ORDER BY j.next_attempt_at LIMIT ?2")?;
+      let rows = stmt.query_map(params![now.to_rfc3339(), BATCH], |r| Ok((r.get(0)?, r.get(1)?,
+        Lookup { doi: r.get(2)?, isbn: r.get(3)?, kind: r.get(4)?, url: r.get(5)? })))?.collect::<rusqlite::Result<Vec<_>>>()?;
+      rows
+    };
+    // Resolvers may read files; the connection stays free meanwhile.
+    for (source_id, attempts, lookup) in &due {
+      let outcome = self.resolve(lookup);
+      let (now, attempts) = (Utc::now(), attempts + 1);
+      let mut db = self.store.conn()?;
+      let tx = db.transaction()?;
+      match outcome {
+        Ok(Some((by, meta))) => {
+          let authors = (!meta.authors.is_empty()).then(|| meta.authors.join("; "));
+          tx.execute("UPDATE sources SET title=COALESCE(title,?1), author=COALESCE(author,?2), year=COALESCE(year,?3), venue=COALESCE(venue,?4) WHERE id=?5",
+            params![meta.title, authors, meta.year, meta.venue, source_id])?;
+          tx.execute("UPDATE metadata_jobs SET status='resolved', attempts=?1, last_error=NULL, resolved_by=?2, updated_at=?3 WHERE source_id=?4",
+            params![attempts, by, now.to_rfc3339(), source_id])?;
+        }
+        Ok(None) => {
+          tx.execute("UPDATE metadata_jobs SET status='not_found', attempts=?1, last_error=NULL, updated_at=?2 WHERE source_id=?3",
+            params![attempts, now.to_rfc3339(), source_id])?;
+        }
+        Err(e) => {
+          let status = if attempts >= MAX_ATTEMPTS { "failed" } else { "pending" };
+          let next = now + chrono::Duration::from_std(retry_delay(attempts)).unwrap_or_default();
+          tx.execute("UPDATE metadata_jobs SET status=?1, attempts=?2, next_attempt_at=?3, last_error=?4, updated_at=?5 WHERE source_id=?6",
+            params![status, attempts, next.to_rfc3339(), e.to_string(), now.to_rfc3339(), source_id])?;
+          eprintln!("LevelNotes META source {} attempt {}/{} failed: {}", source_id, attempts, MAX_ATTEMPTS, e);
+        }
+      }
+      tx.commit()?;
+    }
+    Ok(due.len())
+  }
+
+  // Time until the earliest pending job is due, if there is one.
+  fn next_due_in(&self) -> ApiResult<Option<Duration>> {
+    let next: Option<String> = self.store.conn()?
+      .query_row("SELECT MIN(next_attempt_at) FROM metadata_jobs WHERE status = 'pending'", [], |r| r.get(0)).optional()?.flatten();
+    Ok(next.and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+      .map(|t| (t.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or(Duration::ZERO)))
+  }
+
+  /// Runs due jobs until the task is dropped, sleeping until the next retry, a `wake`, or the idle poll.
+  pub async fn run(self) {
+    loop {
+      let this = self.clone();
+      let ran = tokio::task::spawn_blocking(move || this.run_due()).await;
+      let ran = match ran {
+        Ok(Ok(n)) => n,
+        Ok(Err(e)) => { eprintln!("LevelNotes META {}", e); 0 }
+        Err(e) => { eprintln!("LevelNotes META worker panicked: {}", e); 0 }
+      };
+      // A full batch means there may be more due already.
+      if ran >= BATCH { continue; }
+      let wait = match self.next_due_in() { Ok(Some(d)) => d.min(IDLE_POLL), _ => IDLE_POLL };
+      tokio::select! { _ = self.wake.notified() => {}, _ = tokio::time::sleep(wait) => {} }
+    }
+  }
+}
+
+impl NoteStore {
+  /// Puts a source's lookup back in the queue, due now, with its attempts reset. Returns whether the
+  /// source exists.
+  pub fn requeue_resolution(&self, source_id: &str) -> ApiResult<bool> {
+    let db = self.conn()?;
+    let exists: bool = db.query_row("SELECT EXISTS(SELECT 1 FROM sources WHERE id=?1)", params![source_id], |r| r.get(0))?;
+    if !exists { return Ok(false); }
+    let now = Utc::now().to_rfc3339();
+    db.execute(
+      "INSERT INTO metadata_jobs (source_id, status, next_attempt_at, updated_at) VALUES (?1, 'pending', ?2, ?2)
+       ON CONFLICT(source_id) DO UPDATE SET status='pending', attempts=0, next_attempt_at=excluded.next_attempt_at, updated_at=excluded.updated_at",
+      params![source_id, now])?;
+    Ok(true)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+  use super::*;
+  use crate::store::tests::{clip, temp_store};
+
+  fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("levelnotes-test-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn lookup(doi: Option<&str>, isbn: Option<&str>) -> Lookup {
+    Lookup { doi: doi.map(str::to_string), isbn: isbn.map(str::to_string), kind: "web".into(), url: None }
+  }
+
+  fn meta(title: &str) -> ResolvedMetadata { ResolvedMetadata { title: Some(title.into()), ..Default::default() } }
+
+  const INFO_PDF: &[u8] = b"%PDF-1.4
+1 0 obj << /Title (A \\(nested\\) title) /Author (Doe, Jane; Roe, Rick) /CreationDate (D:20190301120000Z) >> endobj
+2 0 obj << /Type /Pages /Kids [3 0 R] /Count 12 >> endobj
+3 0 obj << /Type /Pages /Parent 2 0 R /Count 4 >> endobj
+%%EOF";
+
+  #[test]
+  fn parses_crossref_work_records() {
+    let record = json!({ "message": {
+      "title": ["A <i>study</i> of tests"],
+      "author": [{ "given": "Jane", "family": "Doe" }, { "name": "The Consortium" }],
+      "published-print": { "date-parts": [[2020, 5]] },
+      "issued": { "date-parts": [[2019]] },
+      "container-title": ["Journal of Tests"],
+    }});
+    assert_eq!(parse_crossref(&record), Some(ResolvedMetadata {
+      title: Some("A study of tests".into()), authors: vec!["Doe, Jane".into(), "The Consortium".into()],
+      year: Some(2020), venue: Some("Journal of Tests".into()),
+    }));
+    assert_eq!(parse_crossref(&json!({ "publisher": "Press" })).and_then(|m| m.venue), Some("Press".into()));
+    assert_eq!(parse_crossref(&json!({ "message": {} })), None);
+  }
+
+  #[test]
+  fn reads_crossref_records_by_doi_and_isbn() {
+    let dir = temp_dir();
+    fs::write(dir.join("10.1000%2Fxyz.json"), json!({ "title": "By DOI" }).to_string()).unwrap();
+    fs::write(dir.join("isbn-9780306406157.json"), json!({ "message": { "title": ["By ISBN"] } }).to_string()).unwrap();
+    fs::write(dir.join("10.1000%2Fbad.json"), json!({ "status": "ok" }).to_string()).unwrap();
+    let crossref = CrossrefResolver::new(&dir);
+    assert_eq!(CrossrefResolver::file_name("10.1000/xyz"), "10.1000%2Fxyz.json");
+    assert_eq!(crossref.resolve(&lookup(Some("10.1000/xyz"), Some("9780306406157"))).unwrap(), Some(meta("By DOI")));
+    assert_eq!(crossref.resolve(&lookup(Some("10.1000/other"), Some("9780306406157"))).unwrap(), Some(meta("By ISBN")));
+    assert_eq!(crossref.resolve(&lookup(Some("10.1000/other"), None)).unwrap(), None);
+    assert!(crossref.resolve(&lookup(Some("10.1000/bad"), None)).is_err());
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn fixtures_match_keys_in_any_accepted_form() {
+    let mut fixtures = FixtureResolver::new();
+    fixtures.insert("https://doi.org/10.1000/XYZ", meta("DOI")).insert("isbn-0-306-40615-2", meta("ISBN")).fail("10.1000/down", "offline");
+    assert_eq!(fixtures.resolve(&lookup(Some("10.1000/xyz"), None)).unwrap(), Some(meta("DOI")));
+    assert_eq!(fixtures.resolve(&lookup(None, Some("9780306406157"))).unwrap(), Some(meta("ISBN")));
+    assert_eq!(fixtures.resolve(&lookup(Some("10.1000/none"), None)).unwrap(), None);
+    assert_eq!(fixtures.resolve(&lookup(Some("10.1000/down"), None)).unwrap_err().0, "offline");
+
+    let dir = temp_dir();
+    let path = dir.join("fixtures.json");
+    fs::write(&path, json!({ "isbn-978-0-306-40615-7": { "title": "From file", "year": 1999 }, "10.1000/down": { "error": "offline" } }).to_string()).unwrap();
+    let fixtures = FixtureResolver::from_file(&path).unwrap();
+    assert_eq!(fixtures.resolve(&lookup(None, Some("9780306406157"))).unwrap().and_then(|m| m.year), Some(1999));
+    assert!(fixtures.resolve(&lookup(Some("10.1000/down"), None)).is_err());
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn reads_the_pdf_info_dictionary_and_page_count() {
+    assert_eq!(pdf_metadata(INFO_PDF), Some(ResolvedMetadata {
+      title: Some("A (nested) title".into()), authors: vec!["Doe, Jane".into(), "Roe, Rick".into()], year: Some(2019), venue: None,
+    }));
+    assert_eq!(pdf_page_count(INFO_PDF), Some(12));
+    // UTF-16 in a hex string.
+    assert_eq!(pdf_metadata(b"%PDF-1.4 << /Title <FEFF00480069> >>").and_then(|m| m.title), Some("Hi".into()));
+    assert_eq!(pdf_metadata(b"%PDF-1.4 << /Producer (x) >>"), None);
+  }
+
+  #[test]
+  fn prefers_xmp_over_the_info_dictionary() {
+    let mut pdf = INFO_PDF.to_vec();
+    pdf.extend_from_slice(br#"<x:xmpmeta><rdf:RDF><rdf:Description prism:coverDate="1843-09-01">
+      <dc:title><rdf:Alt><rdf:li xml:lang="x-default">XMP &amp; title</rdf:li></rdf:Alt></dc:title>
+      <dc:creator><rdf:Seq><rdf:li>Ada Lovelace</rdf:li><rdf:li>Charles Babbage</rdf:li></rdf:Seq></dc:creator>
+      <prism:publicationName>Scientific Memoirs</prism:publicationName>
+    </rdf:Description></rdf:RDF></x:xmpmeta>"#);
+    assert_eq!(pdf_metadata(&pdf), Some(ResolvedMetadata {
+      title: Some("XMP & title".into()), authors: vec!["Ada Lovelace".into(), "Charles Babbage".into()],
+      year: Some(1843), venue: Some("Scientific Memoirs".into()),
+    }));
+  }
+
+  #[test]
+  fn opens_only_pdfs_in_the_asset_store_or_by_name() {
+    let dir = temp_dir();
+    let (assets, named, outside) = (dir.join("assets"), dir.join("pdf"), dir.join("outside"));
+    for d in [&assets, &named, &outside] { fs::create_dir_all(d).unwrap(); }
+    fs::write(assets.join("stored.pdf"), INFO_PDF).unwrap();
+    fs::write(named.join("paper.pdf"), INFO_PDF).unwrap();
+    fs::write(outside.join("secret.pdf"), INFO_PDF).unwrap();
+    let pdf = PdfResolver::new(assets.clone(), vec![named]);
+    let found = |url: &str| pdf.resolve(&Lookup { doi: None, isbn: None, kind: "pdf".into(), url: Some(url.into()) }).unwrap().is_some();
+    assert!(found(&assets.join("stored.pdf").to_string_lossy()));
+    assert!(found("paper.pdf"));
+    assert!(!found(&outside.join("secret.pdf").to_string_lossy()));
+    assert!(!found(&assets.join("../outside/secret.pdf").to_string_lossy()));
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  // A note whose source has DOI 10.1000/xyz and a title of its own. Returns the note id.
+  fn clip_with_doi(store: &NoteStore) -> String {
+    store.create(&clip(json!({ "source": { "kind": "web", "url": "https://example.com/a", "doi": "10.1000/xyz", "metadata": { "title": "From the page" } } }))).unwrap()
+  }
+
+  #[test]
+  fn fills_only_the_gaps_of_a_source() {
+    let (dir, store) = temp_store();
+    let id = clip_with_doi(&store);
+    let mut fixtures = FixtureResolver::new();
+    fixtures.insert("10.1000/xyz", ResolvedMetadata { title: Some("Resolved".into()), authors: vec!["Doe, Jane".into()], year: Some(2020), venue: Some("J".into()) });
+    let enricher = Enricher::new(store.clone(), vec![Box::new(fixtures)]);
+    assert_eq!(enricher.run_due().unwrap(), 1);
+    let source = store.get(&id).unwrap().source.unwrap();
+    assert_eq!((source.title.as_deref(), source.author.as_deref(), source.year), (Some("From the page"), Some("Doe, Jane"), Some(2020)));
+    let resolution = source.resolution.unwrap();
+    assert_eq!((resolution.status.as_str(), resolution.resolved_by.as_deref()), ("resolved", Some("fixture")));
+    assert_eq!(enricher.run_due().unwrap(), 0);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn retries_a_failing_lookup_then_gives_up() {
+    let (dir, store) = temp_store();
+    let id = clip_with_doi(&store);
+    let mut fixtures = FixtureResolver::new();
+    fixtures.fail("10.1000/xyz", "resolver offline");
+    let enricher = Enricher::new(store.clone(), vec![Box::new(fixtures)]);
+    for attempt in 1..=MAX_ATTEMPTS {
+      assert_eq!(enricher.run_due().unwrap(), 1);
+      let resolution = store.get(&id).unwrap().source.unwrap().resolution.unwrap();
+      assert_eq!(resolution.attempts, attempt);
+      assert_eq!(resolution.last_error.as_deref(), Some("fixture: resolver offline"));
+      if attempt < MAX_ATTEMPTS {
+        assert_eq!(resolution.status, "pending");
+        // Not due again until its backoff has passed.
+        assert_eq!(enricher.run_due().unwrap(), 0);
+        store.conn().unwrap().execute("UPDATE metadata_jobs SET next_attempt_at='2000-01-01T00:00:00+00:00'", []).unwrap();
+      } else {
+        assert_eq!(resolution.status, "failed");
+      }
+    }
+    store.conn().unwrap().execute("UPDATE metadata_jobs SET next_attempt_at='2000-01-01T00:00:00+00:00'", []).unwrap();
+    assert_eq!(enricher.run_due().unwrap(), 0);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn marks_a_source_no_resolver_knows_not_found() {
+    let (dir, store) = temp_store();
+    let id = clip_with_doi(&store);
+    let enricher = Enricher::new(store.clone(), vec![Box::new(FixtureResolver::new())]);
+    enricher.run_due().unwrap();
+    assert_eq!(store.get(&id).unwrap().source.unwrap().resolution.unwrap().status, "not_found");
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
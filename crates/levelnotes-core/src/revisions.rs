@@ -0,0 +1,206 @@
+//! Snapshots of a note's title, text, HTML and tags after every change, in `note_revisions`.
+//! Autosaves through `/update` within `AUTOSAVE_WINDOW` of each other fold into one revision.
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::{diff, error::{ApiError, ApiResult}, model::*, store::{self, parse_tags, NoteStore}, text::html_to_text};
+
+// An update this soon after the last one replaces that revision instead of adding one...
+const AUTOSAVE_WINDOW: Duration = Duration::minutes(2);
+// ...unless the revision already spans this long, so a long editing session still leaves a trail.
+const MAX_COALESCED_SPAN: Duration = Duration::minutes(15);
+
+struct Snapshot { title: String, plaintext: Option<String>, html: Option<String>, tags_json: Option<String> }
+
+impl PartialEq for Snapshot {
+  fn eq(&self, other: &Self) -> bool {
+    self.title == other.title && self.plaintext == other.plaintext && self.html == other.html
+      && parse_tags(self.tags_json.clone()) == parse_tags(other.tags_json.clone())
+  }
+}
+
+fn revision_not_found(id: &str, rev: i64) -> ApiError { ApiError::NotFound(format!("revision {} of note {} not found", rev, id)) }
+
+fn older_than(ts: &str, now: DateTime<Utc>, age: Duration) -> bool {
+  DateTime::parse_from_rfc3339(ts).map(|t| now - t.with_timezone(&Utc) >= age).unwrap_or(true)
+}
+
+/// Snapshots note `id` as it is now, after a write of kind `origin` (`create`, `update`, `append`
+/// or `restore`). Writes that changed nothing leave no revision, and a restore always gets its own.
+/// Returns the id of the revision holding the current state.
+pub(crate) fn record(db: &Connection, id: &str, origin: &str, now: DateTime<Utc>, restored_from: Option<i64>) -> rusqlite::Result<i64> {
+  let current = db.query_row("SELECT title, plaintext, html, tags_json FROM notes WHERE id=?1", params![id],
+    |r| Ok(Snapshot { title: r.get(0)?, plaintext: r.get(1)?, html: r.get(2)?, tags_json: r.get(3)? }))?;
+  let latest = db.query_row(
+    "SELECT id, origin, created_at, updated_at, title, plaintext, html, tags_json FROM note_revisions WHERE note_id=?1 ORDER BY id DESC LIMIT 1",
+    params![id], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?, r.get::<_, String>(2)?, r.get::<_, String>(3)?,
+      Snapshot { title: r.get(4)?, plaintext: r.get(5)?, html: r.get(6)?, tags_json: r.get(7)? }))).optional()?;
+  let now_s = now.to_rfc3339();
+  if let Some((rev, last_origin, created_at, updated_at, snapshot)) = latest {
+    if origin != "restore" && snapshot == current { return Ok(rev); }
+    let autosave = origin == "update" && last_origin == "update"
+      && !older_than(&updated_at, now, AUTOSAVE_WINDOW) && !older_than(&created_at, now, MAX_COALESCED_SPAN);
+    if autosave {
+      db.execute("UPDATE note_revisions SET title=?1, plaintext=?2, html=?3, tags_json=?4, updated_at=?5 WHERE id=?6",
+        params![current.title, current.plaintext, current.html, current.tags_json, now_s, rev])?;
+      return Ok(rev);
+    }
+  }
+  db.execute(
+    "INSERT INTO note_revisions (note_id, created_at, updated_at, origin, restored_from, title, plaintext, html, tags_json)
+     VALUES (?1, ?2, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    params![id, now_s, origin, restored_from, current.title, current.plaintext, current.html, current.tags_json])?;
+  Ok(db.last_insert_rowid())
+}
+
+fn revision_from_row(r: &rusqlite::Row) -> rusqlite::Result<Revision> {
+  Ok(Revision {
+    id: r.get(0)?, note_id: r.get(1)?, created_at: r.get(2)?, updated_at: r.get(3)?, origin: r.get(4)?, restored_from: r.get(5)?,
+    title: r.get(6)?, plaintext: r.get(7)?, html: r.get(8)?, tags: parse_tags(r.get(9)?),
+  })
+}
+
+const REVISION_COLUMNS: &str = "id, note_id, created_at, updated_at, origin, restored_from, title, plaintext, html, tags_json";
+
+fn load(db: &Connection, id: &str, rev: i64) -> ApiResult<Revision> {
+  db.query_row(&format!("SELECT {REVISION_COLUMNS} FROM note_revisions WHERE note_id=?1 AND id=?2"), params![id, rev], revision_from_row)
+    .optional()?.ok_or_else(|| revision_not_found(id, rev))
+}
+
+fn note_exists(db: &Connection, id: &str) -> ApiResult<()> {
+  let exists: bool = db.query_row("SELECT EXISTS(SELECT 1 FROM notes WHERE id=?1)", params![id], |r| r.get(0))?;
+  if exists { Ok(()) } else { Err(ApiError::NotFound(format!("note {} not found", id))) }
+}
+
+impl NoteStore {
+  /// Revisions of note `id`, newest first.
+  pub fn revisions(&self, id: &str) -> ApiResult<Vec<RevisionSummary>> {
+    let db = self.conn()?;
+    note_exists(&db, id)?;
+    let mut stmt = db.prepare(
+      "SELECT id, created_at, updated_at, origin, restored_from, title, length(COALESCE(plaintext, ''))
+       FROM note_revisions WHERE note_id=?1 ORDER BY id DESC")?;
+    let rows = stmt.query_map(params![id], |r| Ok(RevisionSummary {
+      id: r.get(0)?, created_at: r.get(1)?, updated_at: r.get(2)?, origin: r.get(3)?, restored_from: r.get(4)?, title: r.get(5)?, chars: r.get(6)?,
+    }))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+  }
+
+  pub fn revision(&self, id: &str, rev: i64) -> ApiResult<Revision> { load(&*self.conn()?, id, rev) }
+
+  /// Changes from revision `from` to revision `to`. `to` defaults to the latest revision and `from`
+  /// to the one before `to`; with no earlier revision the diff is against an empty note.
+  pub fn diff_revisions(&self, id: &str, from: Option<i64>, to: Option<i64>) -> ApiResult<RevisionDiff> {
+    let db = self.conn()?;
+    note_exists(&db, id)?;
+    let to = match to {
+      Some(rev) => load(&db, id, rev)?,
+      None => {
+        let rev: i64 = db.query_row("SELECT MAX(id) FROM note_revisions WHERE note_id=?1", params![id], |r| r.get::<_, Option<i64>>(0))?
+          .ok_or_else(|| ApiError::NotFound(format!("note {} has no revisions", id)))?;
+        load(&db, id, rev)?
+      }
+    };
+    let from = match from {
+      Some(rev) => Some(load(&db, id, rev)?),
+      None => db.query_row("SELECT MAX(id) FROM note_revisions WHERE note_id=?1 AND id<?2", params![id, to.id], |r| r.get::<_, Option<i64>>(0))?
+        .map(|rev| load(&db, id, rev)).transpose()?,
+    };
+    let (old_title, old_text, old_html) = from.as_ref()
+      .map(|f| (f.title.as_str(), f.plaintext.as_deref().unwrap_or(""), f.html.as_deref().unwrap_or(""))).unwrap_or(("", "", ""));
+    let (html, html_marked) = diff::html(old_html, to.html.as_deref().unwrap_or(""));
+    let old_tags = from.as_ref().map(|f| f.tags.clone()).unwrap_or_default();
+    Ok(RevisionDiff {
+      from: from.as_ref().map(|f| f.id), to: to.id,
+      title: diff::text(old_title, &to.title),
+      text: diff::text(old_text, to.plaintext.as_deref().unwrap_or("")),
+      html, html_marked,
+      tags_added: to.tags.iter().filter(|t| !old_tags.contains(t)).cloned().collect(),
+      tags_removed: old_tags.iter().filter(|t| !to.tags.contains(t)).cloned().collect(),
+    })
+  }
+
+  /// Puts revision `rev` back as the note's content, recorded as a new revision. Returns its id.
+  pub fn restore_revision(&self, id: &str, rev: i64) -> ApiResult<i64> {
+    let mut db = self.conn()?;
+    let tx = db.transaction()?;
+    let old = load(&tx, id, rev)?;
+    store::ensure_live(&tx, id)?;
+    let now = Utc::now();
+    let changed = tx.execute(
+      "UPDATE notes SET title=?1, plaintext=?2, html=?3, html_text=?4, tags_json=?5, updated_at=?6, version=version+1 WHERE id=?7",
+      params![old.title, old.plaintext, old.html, old.html.as_deref().map(html_to_text), serde_json::to_string(&old.tags).unwrap(), now.to_rfc3339(), id])?;
+    if changed == 0 { return Err(ApiError::NotFound(format!("note {} not found", id))); }
+    let new_rev = record(&tx, id, "restore", now, Some(rev))?;
+    tx.commit()?;
+    Ok(new_rev)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::tests::{clip, temp_store};
+
+  // Sets the note's title as an update would, then records the revision as of `at`.
+  fn save(db: &Connection, id: &str, title: &str, at: DateTime<Utc>) -> i64 {
+    db.execute("UPDATE notes SET title=?1 WHERE id=?2", params![title, id]).unwrap();
+    record(db, id, "update", at, None).unwrap()
+  }
+
+  #[test]
+  fn folds_autosaves_into_one_revision() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(serde_json::json!({ "selection": { "text": "body" } }))).unwrap();
+    let db = store.conn().unwrap();
+    let t0 = Utc::now() + Duration::hours(1);
+    let first = save(&db, &id, "one", t0);
+    assert_eq!(save(&db, &id, "two", t0 + Duration::seconds(90)), first);
+    assert_eq!(save(&db, &id, "three", t0 + Duration::seconds(200)), first);
+    // A pause longer than the window starts a new revision.
+    let t1 = t0 + Duration::seconds(200) + AUTOSAVE_WINDOW;
+    let second = save(&db, &id, "four", t1);
+    assert_ne!(second, first);
+    let title: String = db.query_row("SELECT title FROM note_revisions WHERE id=?1", params![first], |r| r.get(0)).unwrap();
+    assert_eq!(title, "three");
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn a_long_editing_session_still_leaves_a_trail() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(serde_json::json!({ "selection": { "text": "body" } }))).unwrap();
+    let db = store.conn().unwrap();
+    let t0 = Utc::now() + Duration::hours(1);
+    let first = save(&db, &id, "0", t0);
+    // Saves every 90 seconds never leave the window, but the revision stops growing at 15 minutes.
+    let revs: Vec<i64> = (1..=12).map(|i| save(&db, &id, &i.to_string(), t0 + Duration::seconds(90 * i))).collect();
+    assert!(revs[..9].iter().all(|&r| r == first), "{:?}", revs);
+    assert_ne!(revs[9], first);
+    assert!(revs[10..].iter().all(|&r| r == revs[9]), "{:?}", revs);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn records_only_real_changes_and_every_restore() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(serde_json::json!({ "selection": { "text": "body" } }))).unwrap();
+    let created = store.revisions(&id).unwrap()[0].id;
+    {
+      let db = store.conn().unwrap();
+      let t0 = Utc::now() + Duration::hours(1);
+      assert_eq!(record(&db, &id, "update", t0, None).unwrap(), created);
+      // Appends are never folded, even in the autosave window.
+      let edited = save(&db, &id, "edited", t0);
+      db.execute("UPDATE notes SET plaintext='body appended' WHERE id=?1", params![id]).unwrap();
+      assert_ne!(record(&db, &id, "append", t0 + Duration::seconds(1), None).unwrap(), edited);
+    }
+    let restored = store.restore_revision(&id, created).unwrap();
+    let again = store.restore_revision(&id, created).unwrap();
+    assert_ne!(restored, again);
+    let revisions = store.revisions(&id).unwrap();
+    assert_eq!(revisions.iter().map(|r| r.origin.as_str()).collect::<Vec<_>>(), ["restore", "restore", "append", "update", "create"]);
+    assert_eq!(revisions[0].restored_from, Some(created));
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,347 @@
+use std::sync::Arc;
+use axum::{extract::{multipart::MultipartRejection, rejection::{JsonRejection, QueryRejection}, DefaultBodyLimit, Multipart, Path as AxPath, Query as AxQuery, Json as AxJson}, http::{HeaderMap, HeaderName, HeaderValue, header, Method, StatusCode}, middleware, response::IntoResponse, routing::{get, post}, Json, Router};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use crate::{assets, auth::{self, Auth}, conditional::{self, Precondition}, config::Config, error::ApiError, export, model::*, resolve::{Enricher, MetadataResolver}, store::NoteStore, thumbnails::Thumbnailer};
+
+#[derive(Clone)] pub struct AppState { pub store: NoteStore, pub config: Arc<Config>, pub auth: Auth, pub enricher: Enricher, pub thumbnailer: Thumbnailer }
+
+impl AppState {
+  /// Source lookups use `resolvers`; `resolve::resolvers_for(&config)` gives the configured ones.
+  pub fn new(store: NoteStore, config: Config, auth: Auth, resolvers: Vec<Box<dyn MetadataResolver>>) -> Self {
+    let enricher = Enricher::new(store.clone(), resolvers);
+    let thumbnailer = Thumbnailer::new(store.clone());
+    AppState { store, config: Arc::new(config), auth, enricher, thumbnailer }
+  }
+}
+
+// Room for the multipart boundaries and headers around an `/assets` file of the largest size.
+const MULTIPART_OVERHEAD: u64 = 64 * 1024;
+
+// The reply to a write: the note's new `updated_at`, and its validators for the next conditional write.
+fn saved_response(saved: Saved) -> (HeaderMap, Json<OkResponse>) {
+  (conditional::validators(&saved.updated_at), Json(OkResponse { ok: true, changed: saved.changed, updated_at: Some(saved.updated_at), version: Some(saved.version) }))
+}
+
+// `body` as an attachment named `{name}.{ext}`.
+fn download(format: export::Format, name: &str, body: String) -> Result<(HeaderMap, String), ApiError> {
+  let mut headers = HeaderMap::new();
+  headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+  let disposition = HeaderValue::from_str(&format!("attachment; filename=\"{}.{}\"", name, format.extension()))
+    .map_err(|e| ApiError::Validation(format!("export filename: {}", e)))?;
+  headers.insert(header::CONTENT_DISPOSITION, disposition);
+  Ok((headers, body))
+}
+
+pub fn build_router(state: AppState) -> Router {
+  let upload_limit = state.config.max_upload_mb.saturating_mul(1024 * 1024);
+  let cors = CorsLayer::new()
+    .allow_origin(AllowOrigin::predicate({
+      let auth = state.auth.clone();
+      move |origin, parts| auth::origin_allowed(&auth, origin, parts.uri.path())
+    }))
+    .allow_methods([Method::GET, Method::POST])
+    .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION, header::IF_MATCH, header::IF_NONE_MATCH, header::IF_UNMODIFIED_SINCE, HeaderName::from_static("x-levelnotes-token")])
+    .expose_headers([header::ETAG, header::LAST_MODIFIED]);
+
+  Router::new()
+    .route("/health", get(|| async { Json(HealthResponse { ok: true, service: "levelnotes" }) }))
+
+    .route("/config", get({
+      let state = state.clone();
+      move || async move { Json(state.config.as_ref().clone()) }
+    }))
+
+    .route("/pair/start", post({
+      let state = state.clone();
+      move || async move {
+        let code = state.auth.start_pairing()?;
+        Ok::<_, ApiError>(Json(PairStartResponse{ok:true,code,expires_in:auth::PAIRING_TTL.as_secs()}))
+      }
+    }))
+
+    // Public: the one-time code from /pair/start is the credential. A browser extension's origin
+    // is remembered so CORS lets it in from then on.
+    .route("/pair/complete", post({
+      let state = state.clone();
+      move |headers: HeaderMap, payload: Result<AxJson<PairPayload>, JsonRejection>| async move {
+        let AxJson(payload) = payload?;
+        let token = state.auth.complete_pairing(&payload.code)?;
+        if let Some(origin) = headers.get(header::ORIGIN).and_then(|o| o.to_str().ok()).filter(|o| auth::is_extension_origin(o)) {
+          state.store.add_paired_client(origin, payload.name.as_deref())?;
+          state.auth.allow_origin(origin);
+        }
+        Ok::<_, ApiError>(Json(PairResponse{ok:true,token}))
+      }
+    }))
+
+    .route("/pair/clients", get({
+      let state = state.clone();
+      move || async move { Ok::<_, ApiError>(Json(state.store.paired_clients()?)) }
+    }))
+
+    .route("/file/*path", get({
+      let state = state.clone();
+      move |AxPath(path): AxPath<String>, query: Result<AxQuery<FileQuery>, QueryRejection>, req_headers: HeaderMap| async move {
+        let AxQuery(query) = query?;
+        if !assets::is_served(&path) { return Err(ApiError::NotFound(format!("file {} not found", path))); }
+        // `?size=N` picks a thumbnail of an image; the original when there is none smaller yet.
+        let thumbnail = match query.size { Some(size) => state.store.thumbnail(&path, size)?, None => None };
+        let (path, asset) = match thumbnail {
+          Some(t) => (t.path, Some((format!("\"{}-{}\"", t.asset_hash, t.size), t.mime))),
+          None => { let asset = state.store.asset_at(&path)?.map(|a| (format!("\"{}\"", a.hash), a.mime)); (path, asset) }
+        };
+        // Files under `assets/` are named by their hash and never change, so browsers may keep them.
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(
+          if path.starts_with("assets/") { "public, max-age=31536000, immutable" } else { "no-cache" }));
+        headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        if let Some((etag, _)) = &asset {
+          let matched = req_headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.split(',').map(str::trim).any(|t| t == "*" || t.trim_start_matches("W/") == etag));
+          headers.insert(header::ETAG, HeaderValue::from_str(etag).map_err(|e| ApiError::Validation(format!("asset hash: {}", e)))?);
+          if matched { return Ok::<_, ApiError>((StatusCode::NOT_MODIFIED, headers).into_response()); }
+        }
+        let (bytes, sniffed) = state.store.assets().read(&path)?;
+        let ct = assets::servable(asset.as_ref().map_or(sniffed, |(_, mime)| mime.as_str()));
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(ct).map_err(|e| ApiError::Validation(format!("asset type: {}", e)))?);
+        // Only images are shown inline; PDFs and anything unknown are downloaded.
+        if !ct.starts_with("image/") { headers.insert(header::CONTENT_DISPOSITION, HeaderValue::from_static("attachment")); }
+        Ok((StatusCode::OK, headers, bytes).into_response())
+      }
+    }))
+
+    // A multipart body with one `file` field, written to disk as it arrives. Clips then name the
+    // file by the returned hash instead of sending it inline.
+    .route("/assets", post({
+      let state = state.clone();
+      move |multipart: Result<Multipart, MultipartRejection>| async move {
+        let mut multipart = multipart?;
+        let mut asset = None;
+        while let Some(mut field) = multipart.next_field().await? {
+          if field.name() != Some("file") { continue; }
+          if asset.is_some() { return Err(ApiError::Validation("send one `file` per upload".into())); }
+          // Its declared content type is ignored; the store types it by its bytes.
+          let mut staged = state.store.assets().stage(upload_limit)?;
+          while let Some(chunk) = field.chunk().await? { staged.write(&chunk)?; }
+          asset = Some(state.store.add_staged(staged)?);
+        }
+        let asset = asset.ok_or_else(|| ApiError::Validation("multipart body has no `file` field".into()))?;
+        state.thumbnailer.wake();
+        Ok::<_, ApiError>(Json(UploadResponse{ok:true,asset}))
+      }
+    }).layer(DefaultBodyLimit::max(usize::try_from(upload_limit.saturating_add(MULTIPART_OVERHEAD)).unwrap_or(usize::MAX))))
+
+    // A PDF as a `file` field, or the `assetId` of one already uploaded; `filename` overrides the
+    // upload's. Clips then name the document with `source.documentId`.
+    .route("/documents", post({
+      let state = state.clone();
+      move |multipart: Result<Multipart, MultipartRejection>| async move {
+        let mut multipart = multipart?;
+        let (mut hash, mut filename) = (None, None);
+        while let Some(mut field) = multipart.next_field().await? {
+          match field.name() {
+            Some("file") => {
+              if hash.is_some() { return Err(ApiError::Validation("send one `file` or `assetId` per document".into())); }
+              filename = filename.or_else(|| field.file_name().map(str::to_string));
+              let mut staged = state.store.assets().stage(upload_limit)?;
+              while let Some(chunk) = field.chunk().await? { staged.write(&chunk)?; }
+              hash = Some(state.store.add_staged(staged)?.hash);
+            }
+            Some("assetId") => {
+              if hash.is_some() { return Err(ApiError::Validation("send one `file` or `assetId` per document".into())); }
+              hash = Some(field.text().await?);
+            }
+            Some("filename") => filename = Some(field.text().await?),
+            _ => {}
+          }
+        }
+        let hash = hash.ok_or_else(|| ApiError::Validation("multipart body has no `file` or `assetId` field".into()))?;
+        let document = state.store.add_document(&hash, filename.as_deref())?;
+        Ok::<_, ApiError>(Json(DocumentResponse{ok:true,document}))
+      }
+    }).layer(DefaultBodyLimit::max(usize::try_from(upload_limit.saturating_add(MULTIPART_OVERHEAD)).unwrap_or(usize::MAX)))
+    .get({
+      let state = state.clone();
+      move || async move { Ok::<_, ApiError>(Json(state.store.documents()?)) }
+    }))
+
+    .route("/document/:id", get({
+      let state = state.clone();
+      move |AxPath(id): AxPath<String>| async move { Ok::<_, ApiError>(Json(state.store.document(&id)?)) }
+    }))
+
+    .route("/clip", post({
+      let state = state.clone();
+      move |payload: Result<AxJson<ClipPayload>, JsonRejection>| async move {
+        let AxJson(payload) = payload?;
+        let note_id = state.store.create(&payload)?;
+        state.enricher.wake();
+        state.thumbnailer.wake();
+        Ok::<_, ApiError>(Json(ClipResponse{ok:true,note_id}))
+      }
+    }))
+
+    .route("/update/:id", post({
+      let state = state.clone();
+      move |AxPath(id): AxPath<String>, headers: HeaderMap, payload: Result<AxJson<UpdatePayload>, JsonRejection>| async move {
+        let AxJson(payload) = payload?;
+        let saved = state.store.update(&id, &payload, &Precondition::from_headers(&headers)?)?;
+        Ok::<_, ApiError>(saved_response(saved))
+      }
+    }))
+
+    .route("/notes", get({
+      let state = state.clone();
+      move |query: Result<AxQuery<ListQuery>, QueryRejection>| async move {
+        let AxQuery(query) = query?;
+        Ok::<_, ApiError>(Json(state.store.list(&query)?))
+      }
+    }))
+
+    .route("/search", get({
+      let state = state.clone();
+      move |query: Result<AxQuery<ListQuery>, QueryRejection>| async move {
+        let AxQuery(query) = query?;
+        Ok::<_, ApiError>(Json(state.store.list(&query)?))
+      }
+    }))
+
+    .route("/note/:id", get({
+      let state = state.clone();
+      move |AxPath(id): AxPath<String>| async move {
+        let note = state.store.get(&id)?;
+        Ok::<_, ApiError>((conditional::validators(&note.updated_at), Json(note)))
+      }
+    }))
+
+    .route("/open/:id", post({
+      let state = state.clone();
+      move |AxPath(id): AxPath<String>| async move {
+        state.store.mark_opened(&id)?;
+        let note = state.store.get(&id)?;
+        Ok::<_, ApiError>((conditional::validators(&note.updated_at), Json(note)))
+      }
+    }))
+
+    .route("/note/:id/preview", post({
+      let state = state.clone();
+      move |AxPath(id): AxPath<String>, headers: HeaderMap, payload: Result<AxJson<PreviewPayload>, JsonRejection>| async move {
+        let AxJson(payload) = payload?;
+        let saved = state.store.set_preview(&id, payload.hash.trim(), &Precondition::from_headers(&headers)?)?;
+        Ok::<_, ApiError>(saved_response(saved))
+      }
+    }))
+
+    .route("/note/:id/revisions", get({
+      let state = state.clone();
+      move |AxPath(id): AxPath<String>| async move {
+        Ok::<_, ApiError>(Json(state.store.revisions(&id)?))
+      }
+    }))
+
+    .route("/note/:id/revisions/:rev", get({
+      let state = state.clone();
+      move |AxPath((id, rev)): AxPath<(String, i64)>| async move {
+        Ok::<_, ApiError>(Json(state.store.revision(&id, rev)?))
+      }
+    }))
+
+    .route("/note/:id/diff", get({
+      let state = state.clone();
+      move |AxPath(id): AxPath<String>, query: Result<AxQuery<DiffQuery>, QueryRejection>| async move {
+        let AxQuery(query) = query?;
+        Ok::<_, ApiError>(Json(state.store.diff_revisions(&id, query.from, query.to)?))
+      }
+    }))
+
+    .route("/note/:id/restore/:rev", post({
+      let state = state.clone();
+      move |AxPath((id, rev)): AxPath<(String, i64)>| async move {
+        let revision = state.store.restore_revision(&id, rev)?;
+        Ok::<_, ApiError>(Json(RestoreResponse{ok:true,revision}))
+      }
+    }))
+
+    .route("/delete/:id", post({
+      let state = state.clone();
+      move |AxPath(id): AxPath<String>| async move {
+        let changed = state.store.delete(&id)?;
+        Ok::<_, ApiError>(Json(OkResponse{ok:true,changed,updated_at:None,version:None}))
+      }
+    }))
+
+    .route("/trash", get({
+      let state = state.clone();
+      move |query: Result<AxQuery<ListQuery>, QueryRejection>| async move {
+        let AxQuery(query) = query?;
+        Ok::<_, ApiError>(Json(state.store.trash(query)?))
+      }
+    }))
+
+    .route("/restore/:id", post({
+      let state = state.clone();
+      move |AxPath(id): AxPath<String>| async move {
+        let changed = state.store.restore(&id)?;
+        Ok::<_, ApiError>(Json(OkResponse{ok:true,changed,updated_at:None,version:None}))
+      }
+    }))
+
+    .route("/purge", post({
+      let state = state.clone();
+      move |query: Result<AxQuery<PurgeQuery>, QueryRejection>| async move {
+        let AxQuery(query) = query?;
+        let ids: Option<Vec<String>> = query.ids.as_deref()
+          .map(|s| s.split(',').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect());
+        Ok::<_, ApiError>(Json(state.store.purge(ids.as_deref(), query.older_than_days)?))
+      }
+    }))
+
+    .route("/append/:id", post({
+      let state = state.clone();
+      move |AxPath(id): AxPath<String>, headers: HeaderMap, payload: Result<AxJson<ClipPayload>, JsonRejection>| async move {
+        let AxJson(payload) = payload?;
+        let saved = state.store.append(&id, &payload, &Precondition::from_headers(&headers)?)?;
+        state.enricher.wake();
+        state.thumbnailer.wake();
+        Ok::<_, ApiError>(saved_response(saved))
+      }
+    }))
+
+    // Looks the source up again now, e.g. after adding its Crossref record or once it `failed`.
+    .route("/source/:id/resolve", post({
+      let state = state.clone();
+      move |AxPath(id): AxPath<String>| async move {
+        if !state.store.requeue_resolution(&id)? { return Err(ApiError::NotFound(format!("source {} not found", id))); }
+        state.enricher.wake();
+        Ok::<_, ApiError>(Json(OkResponse{ok:true,changed:1,updated_at:None,version:None}))
+      }
+    }))
+
+    // matchit can't split a segment, so `:id.md` would capture the extension too; strip it here.
+    // `.bib`, `.ris` and `.csl.json` cite the note's source instead of dumping it.
+    .route("/export/:file", get({
+      let state = state.clone();
+      move |AxPath(file): AxPath<String>| async move {
+        let (id, format) = export::Format::split_file(&file).ok_or_else(|| ApiError::NotFound(format!("no exporter for {}", file)))?;
+        let note = state.store.note_for_export(id)?;
+        if format != export::Format::Markdown && note.source.is_none() {
+          return Err(ApiError::Validation(format!("note {} has no source to cite", id)));
+        }
+        let name = format!("{}-{}", export::sanitize_filename(&note.title), export::sanitize_filename(id));
+        download(format, &name, export::render_note(&note, format))
+      }
+    }))
+
+    .route("/citations", get({
+      let state = state.clone();
+      move |query: Result<AxQuery<CitationQuery>, QueryRejection>| async move {
+        let AxQuery(query) = query?;
+        let format = export::Format::citation(&query.format)?;
+        let notes = state.store.notes_for_export(&query)?;
+        let name = query.tag.as_deref().map(export::sanitize_filename).unwrap_or_else(|| "citations".into());
+        download(format, &name, export::render(&notes, format))
+      }
+    }))
+    .layer(middleware::from_fn_with_state(state, auth::require_token))
+    .layer(cors)
+}
@@ -0,0 +1,194 @@
+//! Where clips came from. One `sources` row stands for one work and is shared by every note clipped
+//! from it: clips are matched to an existing row by DOI, ISBN, canonical URL, then kind and raw URL.
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+use crate::{error::{ApiError, ApiResult}, model::{Resolution, Source, SourceRecord}};
+
+/// The `source.kind` values the clip payload may carry.
+pub const KINDS: &[&str] = &["web", "pdf", "image"];
+
+// Query parameters that only track how a visitor arrived; two URLs differing in these are one page.
+const TRACKING_PARAMS: &[&str] = &["fbclid", "gclid", "dclid", "msclkid", "mc_cid", "mc_eid", "igshid", "ref_src"];
+
+/// A DOI in its bare, lowercased form (`10.1000/xyz`), accepting `doi:` and doi.org URL prefixes.
+/// DOIs are case-insensitive, so this is also the form they are compared in.
+pub fn normalize_doi(s: &str) -> Option<String> {
+  let lower = s.trim().to_ascii_lowercase();
+  let bare = ["https://doi.org/", "http://doi.org/", "https://dx.doi.org/", "http://dx.doi.org/", "doi:"]
+    .iter().find_map(|p| lower.strip_prefix(p)).unwrap_or(&lower).trim();
+  let (prefix, suffix) = bare.split_once('/')?;
+  if prefix.starts_with("10.") && prefix.len() > 3 && !suffix.is_empty() && !bare.contains(char::is_whitespace) { Some(bare.to_string()) } else { None }
+}
+
+/// An ISBN as its 13 digits, from either form with or without hyphens; `None` if the check digit is wrong.
+pub fn normalize_isbn(s: &str) -> Option<String> {
+  let s = s.trim();
+  let s = s.strip_prefix("ISBN").or_else(|| s.strip_prefix("isbn")).unwrap_or(s).trim_start_matches([':', ' ']);
+  let chars: Vec<char> = s.chars().filter(|c| !matches!(c, '-' | ' ')).map(|c| c.to_ascii_uppercase()).collect();
+  let digit = |c: char| c.to_digit(10);
+  match chars.len() {
+    10 => {
+      let mut sum = 0;
+      for (i, c) in chars.iter().enumerate() {
+        let v = match (i, c) { (9, 'X') => 10, _ => digit(*c)? };
+        sum += v * (10 - i as u32);
+      }
+      if !sum.is_multiple_of(11) { return None; }
+      let mut isbn13: String = "978".chars().chain(chars[..9].iter().copied()).collect();
+      let sum: u32 = isbn13.chars().enumerate().map(|(i, c)| digit(c).unwrap() * if i % 2 == 0 { 1 } else { 3 }).sum();
+      isbn13.push(char::from_digit((10 - sum % 10) % 10, 10).unwrap());
+      Some(isbn13)
+    }
+    13 => {
+      let digits: Vec<u32> = chars.iter().map(|c| digit(*c)).collect::<Option<_>>()?;
+      let sum: u32 = digits.iter().enumerate().map(|(i, d)| d * if i % 2 == 0 { 1 } else { 3 }).sum();
+      sum.is_multiple_of(10).then(|| chars.iter().collect())
+    }
+    _ => None,
+  }
+}
+
+/// An http(s) URL without its fragment and tracking parameters (`utm_*`, `fbclid`, ...). Other URLs,
+/// like the file names PDF clips send, have no canonical form.
+pub fn canonical_url(url: &str) -> Option<String> {
+  let url = url.trim();
+  let (scheme, _) = url.split_once("://")?;
+  if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") { return None; }
+  let url = url.split('#').next().unwrap_or(url);
+  let Some((base, query)) = url.split_once('?') else { return Some(url.to_string()) };
+  let kept: Vec<&str> = query.split('&').filter(|kv| {
+    let key = kv.split('=').next().unwrap_or("").to_ascii_lowercase();
+    !kv.is_empty() && !key.starts_with("utm_") && !TRACKING_PARAMS.contains(&key.as_str())
+  }).collect();
+  Some(if kept.is_empty() { base.to_string() } else { format!("{}?{}", base, kept.join("&")) })
+}
+
+// The first of `keys` holding a non-empty string; a list of strings (several authors) is joined with "; ".
+fn meta_str(meta: &Map<String, Value>, keys: &[&str]) -> Option<String> {
+  keys.iter().filter_map(|k| meta.get(*k)).find_map(|v| match v {
+    Value::String(s) => Some(s.trim().to_string()),
+    Value::Array(items) => Some(items.iter().filter_map(Value::as_str).map(str::trim).filter(|s| !s.is_empty()).collect::<Vec<_>>().join("; ")),
+    _ => None,
+  }).filter(|s| !s.is_empty())
+}
+
+/// The columns of a `sources` row, before it has an id.
+#[derive(Default, Debug)]
+pub(crate) struct SourceFields {
+  pub kind: String,
+  pub url: Option<String>,
+  pub canonical_url: Option<String>,
+  pub doi: Option<String>,
+  pub isbn: Option<String>,
+  pub title: Option<String>,
+  pub author: Option<String>,
+  pub site_name: Option<String>,
+  pub metadata: Map<String, Value>,
+}
+
+impl SourceFields {
+  /// Validates a clip's `source`. `None` when it names nothing to link to: no URL, DOI or metadata.
+  /// Recognised metadata keys (`title`, `author`/`authors`, `siteName`, `canonicalUrl`, `doi`, `isbn`)
+  /// fill the matching columns; the whole map is kept as sent.
+  pub fn from_payload(source: &Source) -> ApiResult<Option<SourceFields>> {
+    if !KINDS.contains(&source.kind.as_str()) {
+      return Err(ApiError::Validation(format!("source.kind must be one of {}, not {:?}", KINDS.join(", "), source.kind)));
+    }
+    let doi = match source.doi.as_deref().map(str::trim).filter(|d| !d.is_empty()) {
+      Some(d) => Some(normalize_doi(d).ok_or_else(|| ApiError::Validation(format!("source.doi {:?} is not a DOI (expected 10.prefix/suffix)", d)))?),
+      None => None,
+    };
+    let metadata = source.metadata.clone().unwrap_or_default();
+    let url = source.url.as_deref().map(str::trim).filter(|u| !u.is_empty()).map(str::to_string);
+    if url.is_none() && doi.is_none() && metadata.is_empty() { return Ok(None); }
+    Ok(Some(SourceFields {
+      kind: source.kind.clone(),
+      canonical_url: meta_str(&metadata, &["canonicalUrl", "canonical_url"]).as_deref().and_then(canonical_url)
+        .or_else(|| url.as_deref().and_then(canonical_url)),
+      doi: doi.or_else(|| meta_str(&metadata, &["doi", "DOI"]).as_deref().and_then(normalize_doi)),
+      isbn: meta_str(&metadata, &["isbn", "ISBN"]).as_deref().and_then(normalize_isbn),
+      title: meta_str(&metadata, &["title"]),
+      author: meta_str(&metadata, &["author", "authors"]),
+      site_name: meta_str(&metadata, &["siteName", "site_name"]),
+      url,
+      metadata,
+    }))
+  }
+}
+
+// An existing row for the same work, if any.
+fn find(db: &Connection, f: &SourceFields) -> rusqlite::Result<Option<String>> {
+  if let Some(doi) = &f.doi {
+    if let Some(id) = db.query_row("SELECT id FROM sources WHERE doi=?1", params![doi], |r| r.get(0)).optional()? { return Ok(Some(id)); }
+  }
+  if let Some(isbn) = &f.isbn {
+    if let Some(id) = db.query_row("SELECT id FROM sources WHERE isbn=?1", params![isbn], |r| r.get(0)).optional()? { return Ok(Some(id)); }
+  }
+  if let Some(canonical) = &f.canonical_url {
+    return db.query_row("SELECT id FROM sources WHERE canonical_url=?1 ORDER BY accessed_at DESC LIMIT 1", params![canonical], |r| r.get(0)).optional();
+  }
+  match &f.url {
+    Some(url) => db.query_row("SELECT id FROM sources WHERE kind=?1 AND url=?2 AND canonical_url IS NULL ORDER BY accessed_at DESC LIMIT 1",
+      params![f.kind, url], |r| r.get(0)).optional(),
+    None => Ok(None),
+  }
+}
+
+/// Links `f` to the row for the same work, refreshing it with whatever this clip adds, or creates
+/// one. Queues a metadata lookup if the source has something to look up. Returns the source id.
+pub(crate) fn upsert(db: &Connection, f: &SourceFields, accessed_at: &str) -> rusqlite::Result<String> {
+  let metadata = serde_json::to_string(&f.metadata).unwrap();
+  let id = match find(db, f)? {
+    Some(id) => {
+      db.execute(
+        "UPDATE sources SET url=COALESCE(?1,url), canonical_url=COALESCE(canonical_url,?2), doi=COALESCE(doi,?3), isbn=COALESCE(isbn,?4),
+           title=COALESCE(?5,title), author=COALESCE(?6,author), site_name=COALESCE(?7,site_name),
+           accessed_at=MAX(accessed_at,?8), metadata_json=json_patch(COALESCE(metadata_json,'{}'),?9) WHERE id=?10",
+        params![f.url, f.canonical_url, f.doi, f.isbn, f.title, f.author, f.site_name, accessed_at, metadata, id])?;
+      id
+    }
+    None => {
+      let id = Uuid::new_v4().to_string();
+      db.execute(
+        "INSERT INTO sources (id, kind, url, canonical_url, doi, isbn, title, author, site_name, accessed_at, metadata_json)
+         VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11)",
+        params![id, f.kind, f.url, f.canonical_url, f.doi, f.isbn, f.title, f.author, f.site_name, accessed_at, metadata])?;
+      id
+    }
+  };
+  if f.doi.is_some() || f.isbn.is_some() || f.kind == "pdf" {
+    // A source that was looked up before without an identifier gets another go now that it has one.
+    db.execute(
+      "INSERT INTO metadata_jobs (source_id, status, next_attempt_at, updated_at) VALUES (?1, 'pending', ?2, ?2)
+       ON CONFLICT(source_id) DO UPDATE SET status='pending', attempts=0, next_attempt_at=excluded.next_attempt_at, updated_at=excluded.updated_at
+       WHERE status='not_found' AND (?3 IS NOT NULL OR ?4 IS NOT NULL)",
+      params![id, accessed_at, f.doi, f.isbn])?;
+  }
+  Ok(id)
+}
+
+pub(crate) fn load(db: &Connection, id: &str) -> rusqlite::Result<Option<SourceRecord>> {
+  db.query_row(
+    "SELECT s.id, s.kind, s.url, s.canonical_url, s.doi, s.isbn, s.title, s.author, s.site_name, s.year, s.venue, s.accessed_at, s.metadata_json,
+       j.status, j.attempts, j.next_attempt_at, j.last_error, j.resolved_by
+     FROM sources s LEFT JOIN metadata_jobs j ON j.source_id = s.id WHERE s.id=?1",
+    params![id], |r| {
+      let metadata: Option<String> = r.get(12)?;
+      let status: Option<String> = r.get(13)?;
+      let resolution = match status {
+        Some(status) => Some(Resolution {
+          next_attempt_at: if status == "pending" { r.get(15)? } else { None },
+          status, attempts: r.get(14)?, last_error: r.get(16)?, resolved_by: r.get(17)?,
+        }),
+        None => None,
+      };
+      Ok(SourceRecord {
+        id: r.get(0)?, kind: r.get(1)?, url: r.get(2)?, canonical_url: r.get(3)?, doi: r.get(4)?, isbn: r.get(5)?,
+        title: r.get(6)?, author: r.get(7)?, site_name: r.get(8)?, year: r.get(9)?, venue: r.get(10)?, accessed_at: r.get(11)?,
+        metadata: metadata.and_then(|j| serde_json::from_str(&j).ok()).unwrap_or_default(),
+        resolution,
+      })
+    }).optional()
+}
@@ -0,0 +1,494 @@
+use std::{path::Path as FsPath, sync::{Arc, Mutex, MutexGuard}};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+use crate::{assets::{self, AssetStore}, conditional::Precondition, error::{ApiError, ApiResult}, documents, migrations, model::*, revisions, sources::{self, SourceFields}, text::html_to_text};
+
+pub(crate) fn open_db(path: &FsPath) -> Result<Connection, migrations::MigrateError> {
+  if let Some(parent) = path.parent() { std::fs::create_dir_all(parent).map_err(migrations::MigrateError::Io)?; }
+  let mut db = Connection::open(path)?;
+  db.execute_batch("PRAGMA journal_mode=WAL;")?;
+  if let Some(backup) = migrations::migrate(&mut db, path)? {
+    println!("LevelNotes DB  backup before migrating: {}", backup.display());
+  }
+  Ok(db)
+}
+
+pub(crate) fn merge_tags(old_json: Option<String>, add: &[String]) -> String {
+  let mut set: std::collections::BTreeSet<String> = old_json
+    .and_then(|j| serde_json::from_str::<Vec<String>>(&j).ok())
+    .unwrap_or_default().into_iter().collect();
+  for t in add { let t=t.trim(); if !t.is_empty() { set.insert(t.to_string()); } }
+  serde_json::to_string::<Vec<String>>(&set.into_iter().collect()).unwrap()
+}
+
+pub(crate) fn parse_tags(tags_json: Option<String>) -> Vec<String> {
+  tags_json.and_then(|j| serde_json::from_str::<Vec<String>>(&j).ok()).unwrap_or_default()
+}
+
+// A file clipped with a note: decoded before the store is locked, or already uploaded to `/assets`.
+enum UploadData { Inline(Vec<u8>), Stored(String) }
+struct Upload { data: UploadData, role: &'static str, name: Option<String> }
+
+// The file in a data URL or an asset id, named `fields` in errors; at most one of them may be set.
+fn upload_data(fields: (&str, &str), data_url: Option<&str>, asset_id: Option<&str>) -> ApiResult<Option<UploadData>> {
+  match (data_url, asset_id.map(str::trim)) {
+    (Some(_), Some(_)) => Err(ApiError::Validation(format!("send {} or {}, not both", fields.0, fields.1))),
+    (Some(url), None) => Ok(Some(UploadData::Inline(assets::decode_data_url(fields.0, url)?))),
+    (None, Some(hash)) => Ok(Some(UploadData::Stored(hash.to_ascii_lowercase()))),
+    (None, None) => Ok(None),
+  }
+}
+
+// A clip's screenshot, then its attachments.
+fn clip_uploads(payload: &ClipPayload) -> ApiResult<Vec<Upload>> {
+  let Some(media) = payload.media.as_ref() else { return Ok(Vec::new()) };
+  let mut uploads = Vec::new();
+  if let Some(data) = upload_data(("screenshotDataUrl", "screenshotAssetId"), media.screenshot_data_url.as_deref(), media.screenshot_asset_id.as_deref())? {
+    uploads.push(Upload { data, role: "screenshot", name: None });
+  }
+  for (i, a) in media.attachments.iter().enumerate() {
+    let fields = (format!("attachments[{}].dataUrl", i), format!("attachments[{}].assetId", i));
+    let data = upload_data((&fields.0, &fields.1), a.data_url.as_deref(), a.asset_id.as_deref())?
+      .ok_or_else(|| ApiError::Validation(format!("attachments[{}] needs a dataUrl or an assetId", i)))?;
+    let name = a.name.as_deref().map(str::trim).filter(|n| !n.is_empty()).map(str::to_string);
+    uploads.push(Upload { data, role: "attachment", name });
+  }
+  Ok(uploads)
+}
+
+fn not_found(id: &str) -> ApiError { ApiError::NotFound(format!("note {} not found", id)) }
+
+/// Ok if note `id` exists and is not in the trash, which only takes `/restore` and `/purge`.
+pub(crate) fn ensure_live(db: &Connection, id: &str) -> ApiResult<()> {
+  let in_trash: bool = db.query_row("SELECT deleted_at IS NOT NULL FROM notes WHERE id=?1", params![id], |r| r.get(0))
+    .optional()?.ok_or_else(|| not_found(id))?;
+  if in_trash { Err(ApiError::Conflict(format!("note {} is in the trash; restore it first", id))) } else { Ok(()) }
+}
+
+// `ensure_live`, then `expect` and `expected_version` against the note as stored. Returns the
+// note's version and `updated_at`.
+fn ensure_writable(db: &Connection, id: &str, expect: &Precondition, expected_version: Option<i64>) -> ApiResult<(i64, String)> {
+  ensure_live(db, id)?;
+  let (updated_at, version): (String, i64) =
+    db.query_row("SELECT COALESCE(updated_at, created_at), version FROM notes WHERE id=?1", params![id], |r| Ok((r.get(0)?, r.get(1)?)))?;
+  expect.check(id, &updated_at)?;
+  if let Some(expected) = expected_version.filter(|&v| v != version) {
+    return Err(ApiError::VersionConflict(
+      format!("note {} is at version {}, not {}; merge with the current note and save again", id, version, expected),
+      Box::new(load_note(db, id)?)));
+  }
+  Ok((version, updated_at))
+}
+
+/// Host of an http(s) URL, lowercased and without `www.` or a port; what `source_domain` filters on.
+pub(crate) fn url_domain(url: &str) -> Option<String> {
+  let rest = url.split_once("://").filter(|(scheme, _)| scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https"))?.1;
+  let authority = rest.split(['/', '?', '#']).next()?;
+  let host = authority.rsplit('@').next()?.split(':').next()?.to_ascii_lowercase();
+  let host = host.strip_prefix("www.").map(str::to_string).unwrap_or(host);
+  if host.is_empty() { None } else { Some(host) }
+}
+
+/// What `list_item_from_row` reads, in order, from `notes n`; a query may select more after these.
+pub(crate) const LIST_COLUMNS: &str = "n.id, n.title, n.created_at, n.source_url, n.tags_json, n.plaintext, n.preview_path, n.html, n.deleted_at,
+  COALESCE(n.updated_at, n.created_at), n.last_opened_at, n.version";
+pub(crate) const LIST_COLUMN_COUNT: usize = 12;
+
+pub(crate) fn list_item_from_row(row: &rusqlite::Row) -> rusqlite::Result<NoteListItem> {
+  let plaintext: Option<String> = row.get(5)?;
+  let snippet = plaintext.as_ref().map(|s| { let s=s.trim(); let mut out=s.chars().take(160).collect::<String>(); if s.len()>out.len(){out.push('…');} out });
+  Ok(NoteListItem {
+    id: row.get(0)?,
+    title: row.get::<_, Option<String>>(1)?.unwrap_or_else(|| "Untitled clip".to_string()),
+    created_at: row.get(2)?,
+    updated_at: row.get(9)?,
+    last_opened_at: row.get(10)?,
+    version: row.get(11)?,
+    source_url: row.get(3)?,
+    tags: parse_tags(row.get(4)?),
+    snippet,
+    preview_path: row.get(6)?,
+    html: row.get(7)?,
+    deleted_at: row.get(8)?,
+    score: None, highlighted_title: None, highlighted_snippet: None, matches: Vec::new(),
+  })
+}
+
+// Anchors of the clip as a JSON selector list, or NULL when it came without usable ones.
+fn selectors_json(payload: &ClipPayload) -> Option<String> {
+  let selectors = payload.selection.as_ref().and_then(|s| s.anchors.as_ref()).map(Anchors::selectors).unwrap_or_default();
+  if selectors.is_empty() { None } else { Some(serde_json::to_string(&selectors).unwrap()) }
+}
+
+fn highlights_json(payload: &ClipPayload) -> String {
+  payload.ops.as_ref().and_then(|o| o.highlights.as_ref()).map(|v| serde_json::to_string(v).unwrap()).unwrap_or_else(|| "[]".to_string())
+}
+
+// The `/documents` PDF the clip says it came from.
+fn document_id(payload: &ClipPayload) -> Option<String> {
+  payload.source.as_ref().and_then(|s| s.document_id.as_deref()).map(str::trim).filter(|d| !d.is_empty()).map(str::to_string)
+}
+
+fn append_block(prev: Option<String>, add: String) -> String {
+  match prev {
+    Some(prev) if !add.is_empty() && !prev.is_empty() => format!("{}\n\n{}", prev, add),
+    Some(prev) if !prev.is_empty() => prev,
+    _ => add,
+  }
+}
+
+/// Note `id` with its source, trashed or not.
+pub(crate) fn load_note(db: &Connection, id: &str) -> ApiResult<NoteDetail> {
+  let (mut note, source_id, document_id) = db.query_row(
+    "SELECT id,created_at,title,plaintext,html,source_url,text_quote,preview_path,tags_json,page_number,highlights_json,selectors_json,source_id,deleted_at,
+       COALESCE(updated_at,created_at),last_opened_at,version,document_id
+     FROM notes WHERE id=?1", params![id], |row| {
+    let highlights_json: Option<String> = row.get(10)?;
+    let selectors_json: Option<String> = row.get(11)?;
+    Ok((NoteDetail{
+      id: row.get(0)?, created_at: row.get(1)?,
+      title: row.get::<_, Option<String>>(2)?.unwrap_or_else(|| "Untitled clip".into()),
+      updated_at: row.get(14)?, last_opened_at: row.get(15)?, version: row.get(16)?,
+      plaintext: row.get(3)?, html: row.get(4)?,
+      source_url: row.get(5)?, text_quote: row.get(6)?,
+      preview_path: row.get(7)?, tags: parse_tags(row.get(8)?),
+      page_number: row.get(9)?,
+      highlights: highlights_json.and_then(|j|serde_json::from_str::<Vec<Rect>>(&j).ok()).unwrap_or_default(),
+      selectors: selectors_json.and_then(|j|serde_json::from_str::<Vec<Selector>>(&j).ok()).unwrap_or_default(),
+      source: None, document: None, clips: Vec::new(), assets: Vec::new(),
+      deleted_at: row.get(13)?,
+    }, row.get::<_, Option<String>>(12)?, row.get::<_, Option<String>>(17)?))
+  }).optional()?.ok_or_else(|| not_found(id))?;
+  if let Some(sid) = source_id { note.source = sources::load(db, &sid)?; }
+  if let Some(did) = document_id { note.document = documents::load(db, &did)?; }
+  note.clips = documents::clips_for_note(db, id)?;
+  note.assets = assets::for_note(db, id)?;
+  Ok(note)
+}
+
+/// Notes in the SQLite database plus the assets they reference. Cheap to clone; all clones share one connection.
+#[derive(Clone)]
+pub struct NoteStore { db: Arc<Mutex<Connection>>, assets: AssetStore }
+
+impl NoteStore {
+  /// Opens (and migrates) the database at `db_path`; assets live in the same directory.
+  pub fn open(db_path: &FsPath) -> Result<Self, migrations::MigrateError> {
+    let data_dir = db_path.parent().map(|p| p.to_path_buf()).unwrap_or_else(|| ".".into());
+    Ok(NoteStore { db: Arc::new(Mutex::new(open_db(db_path)?)), assets: AssetStore::new(data_dir) })
+  }
+
+  pub fn assets(&self) -> &AssetStore { &self.assets }
+
+  // Stores `uploads` and links them to note `id`, inside the caller's transaction. Returns the
+  // screenshot's path, for a card image.
+  fn attach(&self, tx: &Connection, id: &str, uploads: Vec<Upload>, now: &str) -> ApiResult<Option<String>> {
+    let mut screenshot = None;
+    for upload in uploads {
+      let stored = match upload.data {
+        UploadData::Inline(bytes) => {
+          let asset = self.assets.put(&bytes)?;
+          let stored = assets::register(tx, &asset, now)?;
+          // Already kept at a legacy path: the copy just written under the hash isn't needed.
+          if stored.path != asset.path { self.assets.remove(&asset.path)?; }
+          stored
+        }
+        UploadData::Stored(hash) => assets::find(tx, &hash)?
+          .ok_or_else(|| ApiError::NotFound(format!("asset {} not found; upload it to /assets first", hash)))?,
+      };
+      assets::link(tx, id, &stored.hash, upload.role, upload.name.as_deref(), now)?;
+      if upload.role == "screenshot" && stored.mime.starts_with("image/") { screenshot = Some(stored.path); }
+    }
+    Ok(screenshot)
+  }
+
+  pub(crate) fn conn(&self) -> ApiResult<MutexGuard<'_, Connection>> { Ok(self.db.lock()?) }
+
+  /// Stores a new clip and returns its id.
+  pub fn create(&self, payload: &ClipPayload) -> ApiResult<String> {
+    let id = Uuid::new_v4().to_string();
+    let now = Utc::now();
+    let created_at = now.to_rfc3339();
+    let title = payload.selection.as_ref()
+      .and_then(|s| s.text.as_ref()).map(|t| t.trim()).filter(|s| !s.is_empty())
+      .map(|t| t.chars().take(80).collect::<String>())
+      .unwrap_or_else(|| "Untitled clip".to_string());
+    let plaintext = payload.selection.as_ref().and_then(|s| s.text.clone());
+    let html = payload.selection.as_ref().and_then(|s| s.html.clone());
+    let html_text = html.as_deref().map(html_to_text);
+    let source_url = payload.source.as_ref().and_then(|s| s.url.clone());
+    let source_kind = payload.source.as_ref().map(|s| s.kind.clone());
+    let source_domain = source_url.as_deref().and_then(url_domain);
+    let text_quote = plaintext.clone();
+    let tags_vec: Vec<String> = payload.ops.as_ref().and_then(|o| o.tags.clone()).unwrap_or_default();
+    let tags_json = serde_json::to_string(&tags_vec).unwrap();
+    let page_number: Option<i32> = payload.ops.as_ref().and_then(|o| o.page);
+    let highlights_json = highlights_json(payload);
+    let selectors_json = selectors_json(payload);
+    let source = match payload.source.as_ref() { Some(s) => SourceFields::from_payload(s)?, None => None };
+    let document_id = document_id(payload);
+
+    let uploads = clip_uploads(payload)?;
+
+    let mut db = self.conn()?;
+    let tx = db.transaction()?;
+    if let Some(did) = &document_id { documents::ensure_clip(&tx, did, page_number)?; }
+    let source_id = source.map(|f| sources::upsert(&tx, &f, &created_at)).transpose()?;
+    let preview_rel = self.attach(&tx, &id, uploads, &created_at)?;
+    tx.execute(
+      "INSERT INTO notes (id, created_at, updated_at, title, plaintext, html, html_text, source_url, text_quote, preview_path, tags_json, page_number, highlights_json, source_kind, source_domain, selectors_json, source_id, document_id)
+       VALUES (?1,?2,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12,?13,?14,?15,?16,?17)",
+      params![id,created_at,title,plaintext,html,html_text,source_url,text_quote,preview_rel,tags_json,page_number,highlights_json,source_kind,source_domain,selectors_json,source_id,document_id]
+    )?;
+    if let Some(did) = &document_id { documents::record_clip(&tx, &id, did, page_number, &highlights_json, &created_at)?; }
+    revisions::record(&tx, &id, "create", now, None)?;
+    tx.commit()?;
+    Ok(id)
+  }
+
+  pub fn get(&self, id: &str) -> ApiResult<NoteDetail> { load_note(&*self.conn()?, id) }
+
+  /// Applies the fields that are set; tags are merged into the existing set. Refused if the note
+  /// has changed since what `expect` describes or since `payload.expected_version`. An update that
+  /// changes nothing writes nothing, and reports `changed: 0`.
+  pub fn update(&self, id: &str, payload: &UpdatePayload, expect: &Precondition) -> ApiResult<Saved> {
+    let mut db = self.conn()?;
+    let tx = db.transaction()?;
+    let (version, updated_at) = ensure_writable(&tx, id, expect, payload.expected_version)?;
+    let (old_title, old_tags_json, old_html, old_plaintext): (String, Option<String>, Option<String>, Option<String>) =
+      tx.query_row("SELECT title, tags_json, html, plaintext FROM notes WHERE id=?1", params![id], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))?;
+    // A save that changes nothing keeps the version, so edits other windows base on it still apply.
+    let old_tags = parse_tags(old_tags_json.clone());
+    let unchanged = payload.title.as_ref().is_none_or(|t| *t == old_title)
+      && payload.tags.as_ref().is_none_or(|v| v.iter().map(|t| t.trim()).all(|t| t.is_empty() || old_tags.iter().any(|o| o == t)))
+      && payload.html.as_ref().is_none_or(|h| old_html.as_ref() == Some(h)) && payload.plaintext.as_ref().is_none_or(|p| old_plaintext.as_ref() == Some(p));
+    if unchanged { return Ok(Saved { changed: 0, updated_at, version }); }
+    let merged = match &payload.tags { Some(v)=> merge_tags(old_tags_json, v), None=> old_tags_json.unwrap_or_else(|| "[]".to_string()) };
+    let now = Utc::now();
+    let changed = tx.execute(
+      "UPDATE notes SET title=COALESCE(?1,title), tags_json=?2, html=COALESCE(?3,html), html_text=COALESCE(?4,html_text), plaintext=COALESCE(?5,plaintext), updated_at=?6,
+         version=version+1 WHERE id=?7",
+      params![payload.title, merged, payload.html, payload.html.as_deref().map(html_to_text), payload.plaintext, now.to_rfc3339(), id]
+    )?;
+    revisions::record(&tx, id, "update", now, None)?;
+    tx.commit()?;
+    Ok(Saved { changed, updated_at: now.to_rfc3339(), version: version + 1 })
+  }
+
+  /// Adds a clip's text, HTML and tags to the end of an existing note, unless it has changed since
+  /// what `expect` describes or since `payload.expected_version`.
+  pub fn append(&self, id: &str, payload: &ClipPayload, expect: &Precondition) -> ApiResult<Saved> {
+    let uploads = clip_uploads(payload)?;
+    let source = match payload.source.as_ref() { Some(s) => SourceFields::from_payload(s)?, None => None };
+
+    let now = Utc::now();
+    let mut db = self.conn()?;
+    let tx = db.transaction()?;
+    let (version, _) = ensure_writable(&tx, id, expect, payload.expected_version)?;
+    // Every clip's place in its document goes to `document_clips`; the note's own document, page and
+    // highlights are its first document clip's.
+    let document_id = document_id(payload);
+    let page_number = payload.ops.as_ref().and_then(|o| o.page);
+    if let Some(did) = &document_id {
+      documents::ensure_clip(&tx, did, page_number)?;
+      documents::record_clip(&tx, id, did, page_number, &highlights_json(payload), &now.to_rfc3339())?;
+    }
+    // Kept after the note's other assets; the screenshot only becomes the card image if the note
+    // had none, and `set_preview` can pick it later.
+    let preview_rel = self.attach(&tx, id, uploads, &now.to_rfc3339())?;
+    // Read inside the transaction, so an edit saved meanwhile is appended to rather than overwritten.
+    let (old_pt, old_html, old_tags_json, has_source): (Option<String>, Option<String>, Option<String>, bool) =
+      tx.query_row("SELECT plaintext, html, tags_json, source_id IS NOT NULL FROM notes WHERE id=?1", params![id],
+        |row| Ok((row.get(0)?,row.get(1)?,row.get(2)?,row.get(3)?)))?;
+    let add_text = payload.selection.as_ref().and_then(|s| s.text.clone()).unwrap_or_default();
+    let add_html = payload.selection.as_ref().and_then(|s| s.html.clone()).unwrap_or_default();
+    let new_pt = append_block(old_pt, add_text);
+    let new_html = append_block(old_html, add_html);
+    let add_tags: Vec<String> = payload.ops.as_ref().and_then(|o| o.tags.clone()).unwrap_or_default();
+    let tags_json = merge_tags(old_tags_json, &add_tags);
+    // A note keeps the source it was created from; an appended clip only supplies one if it had none.
+    let source_id = if has_source { None } else { source.map(|f| sources::upsert(&tx, &f, &now.to_rfc3339())).transpose()? };
+    let changed = tx.execute(
+      "UPDATE notes SET plaintext=?1, html=?2, html_text=?3, tags_json=?4, preview_path=COALESCE(preview_path, ?5), updated_at=?6,
+         selectors_json=COALESCE(selectors_json, ?7), source_id=COALESCE(source_id, ?8), document_id=COALESCE(document_id, ?9),
+         page_number=CASE WHEN document_id IS NULL AND ?9 IS NOT NULL THEN ?10 ELSE page_number END,
+         highlights_json=CASE WHEN document_id IS NULL AND ?9 IS NOT NULL THEN ?11 ELSE highlights_json END, version=version+1 WHERE id=?12",
+      params![new_pt, new_html, html_to_text(&new_html), tags_json, preview_rel, now.to_rfc3339(), selectors_json(payload), source_id, document_id,
+        page_number, highlights_json(payload), id])?;
+    revisions::record(&tx, id, "append", now, None)?;
+    tx.commit()?;
+    Ok(Saved { changed, updated_at: now.to_rfc3339(), version: version + 1 })
+  }
+
+  /// Records that the note was just opened, for `sort=opened`. Doesn't count as a change. Refused
+  /// for notes in the trash, like other writes.
+  pub fn mark_opened(&self, id: &str) -> ApiResult<()> {
+    let db = self.conn()?;
+    ensure_live(&db, id)?;
+    db.execute("UPDATE notes SET last_opened_at=?1 WHERE id=?2", params![Utc::now().to_rfc3339(), id])?;
+    Ok(())
+  }
+
+  /// Shows the note's image `hash` on its card, unless the note has changed since what `expect` describes.
+  pub fn set_preview(&self, id: &str, hash: &str, expect: &Precondition) -> ApiResult<Saved> {
+    let mut db = self.conn()?;
+    let tx = db.transaction()?;
+    let (version, _) = ensure_writable(&tx, id, expect, None)?;
+    let asset = assets::for_note(&tx, id)?.into_iter().find(|a| a.asset.hash == hash)
+      .ok_or_else(|| ApiError::NotFound(format!("note {} has no asset {}", id, hash)))?.asset;
+    if !asset.mime.starts_with("image/") {
+      return Err(ApiError::Validation(format!("asset {} is {}, not an image", hash, asset.mime)));
+    }
+    let now = Utc::now().to_rfc3339();
+    let changed = tx.execute("UPDATE notes SET preview_path=?1, updated_at=?2, version=version+1 WHERE id=?3", params![asset.path, now, id])?;
+    tx.commit()?;
+    Ok(Saved { changed, updated_at: now, version: version + 1 })
+  }
+
+  /// Moves a note to the trash. It stays there, out of listings and search, until restored or purged.
+  pub fn delete(&self, id: &str) -> ApiResult<usize> {
+    let db = self.conn()?;
+    let changed = db.execute("UPDATE notes SET deleted_at=?1 WHERE id=?2 AND deleted_at IS NULL", params![Utc::now().to_rfc3339(), id])?;
+    if changed == 0 {
+      let in_trash: Option<bool> = db.query_row("SELECT deleted_at IS NOT NULL FROM notes WHERE id=?1", params![id], |r| r.get(0)).optional()?;
+      return Err(if in_trash == Some(true) { ApiError::Conflict(format!("note {} is already in the trash", id)) } else { not_found(id) });
+    }
+    Ok(changed)
+  }
+
+  /// Browser origins that completed pairing; the CORS layer admits these next to the app itself.
+  pub fn paired_clients(&self) -> ApiResult<Vec<PairedClient>> {
+    let db = self.conn()?;
+    let mut stmt = db.prepare("SELECT origin, name, paired_at FROM clients ORDER BY paired_at")?;
+    let rows = stmt.query_map([], |r| Ok(PairedClient { origin: r.get(0)?, name: r.get(1)?, paired_at: r.get(2)? }))?
+      .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+  }
+
+  pub fn add_paired_client(&self, origin: &str, name: Option<&str>) -> ApiResult<()> {
+    self.conn()?.execute(
+      "INSERT INTO clients (origin, name, paired_at) VALUES (?1, ?2, ?3)
+       ON CONFLICT(origin) DO UPDATE SET name=excluded.name, paired_at=excluded.paired_at",
+      params![origin, name, Utc::now().to_rfc3339()])?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use std::path::PathBuf;
+  use super::*;
+
+  /// A store in a fresh directory under the system temp dir; the caller removes the directory.
+  pub(crate) fn temp_store() -> (PathBuf, NoteStore) {
+    let dir = std::env::temp_dir().join(format!("levelnotes-test-{}", Uuid::new_v4()));
+    let store = NoteStore::open(&dir.join("levelnotes.db")).unwrap();
+    (dir, store)
+  }
+
+  /// A clip payload from its JSON form, as the extension sends it.
+  pub(crate) fn clip(json: serde_json::Value) -> ClipPayload { serde_json::from_value(json).unwrap() }
+
+  /// A data URL of bytes that type as PNG; different `seed`s give different files.
+  pub(crate) fn png_data_url(seed: &str) -> String {
+    use base64::Engine as _;
+    let bytes = [b"\x89PNG\r\n\x1a\n".as_slice(), seed.as_bytes()].concat();
+    format!("data:image/png;base64,{}", base64::engine::general_purpose::STANDARD.encode(bytes))
+  }
+
+  #[test]
+  fn append_stores_the_clip_screenshot() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(serde_json::json!({ "selection": { "text": "first" } }))).unwrap();
+    store.append(&id, &clip(serde_json::json!({
+      "selection": { "text": "second" }, "media": { "screenshotDataUrl": png_data_url("page 2") },
+    })), &Precondition::default()).unwrap();
+    let note = store.get(&id).unwrap();
+    assert_eq!(note.plaintext.as_deref(), Some("first\n\nsecond"));
+    assert_eq!(note.assets.len(), 1);
+    let asset = &note.assets[0];
+    assert_eq!((asset.role.as_str(), asset.asset.mime.as_str()), ("screenshot", "image/png"));
+    assert!(dir.join(&asset.asset.path).is_file());
+    // The note had no card image, so the appended screenshot becomes it.
+    assert_eq!(note.preview_path.as_deref(), Some(asset.asset.path.as_str()));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn keeps_the_anchors_a_clip_was_sent_with() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(serde_json::json!({
+      "source": { "kind": "web", "url": "https://example.com/a" },
+      "selection": { "text": "quick brown", "anchors": {
+        "xpath": "/html[1]/body[1]/p[2]", "startOffset": 4, "endOffset": 15,
+        "textQuote": { "exact": "quick brown", "prefix": "The ", "suffix": " fox" },
+      } },
+    }))).unwrap();
+    let selectors = serde_json::to_value(store.get(&id).unwrap().selectors).unwrap();
+    assert_eq!(selectors, serde_json::json!([
+      { "type": "TextQuoteSelector", "exact": "quick brown", "prefix": "The ", "suffix": " fox" },
+      { "type": "XPathSelector", "value": "/html[1]/body[1]/p[2]", "refinedBy": { "type": "TextPositionSelector", "start": 4, "end": 15 } },
+    ]));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn opening_a_trashed_note_is_a_conflict() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(serde_json::json!({ "selection": { "text": "body" } }))).unwrap();
+    store.mark_opened(&id).unwrap();
+    let opened = store.get(&id).unwrap().last_opened_at;
+    assert!(opened.is_some());
+    store.delete(&id).unwrap();
+    assert!(matches!(store.mark_opened(&id), Err(ApiError::Conflict(_))));
+    assert_eq!(store.get(&id).unwrap().last_opened_at, opened);
+    assert!(matches!(store.mark_opened("nope"), Err(ApiError::NotFound(_))));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  fn revision_count(store: &NoteStore, id: &str) -> i64 {
+    store.conn().unwrap().query_row("SELECT COUNT(*) FROM note_revisions WHERE note_id=?1", params![id], |r| r.get(0)).unwrap()
+  }
+
+  fn update(json: serde_json::Value) -> UpdatePayload { serde_json::from_value(json).unwrap() }
+
+  #[test]
+  fn an_update_that_changes_nothing_keeps_the_version() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(serde_json::json!({ "selection": { "text": "body" }, "ops": { "tags": ["a"] } }))).unwrap();
+    let before = store.get(&id).unwrap();
+    let revisions = revision_count(&store, &id);
+    for payload in [serde_json::json!({}), serde_json::json!({ "title": before.title, "tags": ["a", " "], "plaintext": "body" })] {
+      let saved = store.update(&id, &update(payload), &Precondition::default()).unwrap();
+      assert_eq!((saved.changed, saved.version), (0, before.version));
+    }
+    assert_eq!(store.get(&id).unwrap().version, before.version);
+    assert_eq!(revision_count(&store, &id), revisions);
+
+    let saved = store.update(&id, &update(serde_json::json!({ "tags": ["b"] })), &Precondition::default()).unwrap();
+    assert_eq!((saved.changed, saved.version), (1, before.version + 1));
+    assert_eq!(revision_count(&store, &id), revisions + 1);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn writes_against_an_old_version_are_refused_with_the_current_note() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(serde_json::json!({ "selection": { "text": "body" } }))).unwrap();
+    let base = store.get(&id).unwrap().version;
+    store.update(&id, &update(serde_json::json!({ "title": "Mine", "expectedVersion": base })), &Precondition::default()).unwrap();
+
+    let stale = store.update(&id, &update(serde_json::json!({ "title": "Theirs", "expectedVersion": base })), &Precondition::default());
+    match stale {
+      Err(ApiError::VersionConflict(_, current)) => assert_eq!((current.title.as_str(), current.version), ("Mine", base + 1)),
+      other => panic!("expected a version conflict, got {:?}", other.map(|s| s.version)),
+    }
+    let stale = store.append(&id, &clip(serde_json::json!({ "selection": { "text": "more" }, "expectedVersion": base })), &Precondition::default());
+    assert!(matches!(stale, Err(ApiError::VersionConflict(..))));
+    assert_eq!(store.get(&id).unwrap().plaintext.as_deref(), Some("body"));
+
+    let saved = store.append(&id, &clip(serde_json::json!({ "selection": { "text": "more" }, "expectedVersion": base + 1 })), &Precondition::default()).unwrap();
+    assert_eq!(saved.version, base + 2);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
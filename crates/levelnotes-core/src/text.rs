@@ -0,0 +1,125 @@
+//! Plain text from clip HTML, for the search index: markup never reaches `notes_fts`.
+
+// Elements whose content isn't readable text.
+const SKIPPED: &[&str] = &["script", "style", "noscript", "template", "head", "svg", "math", "iframe", "object"];
+// Blocks that read as separate paragraphs, and ones that only start a new line.
+const PARAGRAPHS: &[&str] = &["p", "h1", "h2", "h3", "h4", "h5", "h6", "blockquote", "pre", "table", "ul", "ol", "dl", "figure", "hr"];
+const LINES: &[&str] = &[
+  "div", "section", "article", "aside", "header", "footer", "nav", "main", "li", "dt", "dd", "tr",
+  "caption", "figcaption", "address", "details", "summary", "form", "fieldset", "legend",
+];
+// Cells sit side by side; keep their words apart.
+const CELLS: &[&str] = &["td", "th"];
+
+// HTML's Latin-1 entities, U+00A0 to U+00FF in order.
+const LATIN1: [&str; 96] = [
+  "nbsp", "iexcl", "cent", "pound", "curren", "yen", "brvbar", "sect", "uml", "copy", "ordf", "laquo", "not",
+  "shy", "reg", "macr", "deg", "plusmn", "sup2", "sup3", "acute", "micro", "para", "middot", "cedil", "sup1",
+  "ordm", "raquo", "frac14", "frac12", "frac34", "iquest", "Agrave", "Aacute", "Acirc", "Atilde", "Auml",
+  "Aring", "AElig", "Ccedil", "Egrave", "Eacute", "Ecirc", "Euml", "Igrave", "Iacute", "Icirc", "Iuml",
+  "ETH", "Ntilde", "Ograve", "Oacute", "Ocirc", "Otilde", "Ouml", "times", "Oslash", "Ugrave", "Uacute",
+  "Ucirc", "Uuml", "Yacute", "THORN", "szlig", "agrave", "aacute", "acirc", "atilde", "auml", "aring",
+  "aelig", "ccedil", "egrave", "eacute", "ecirc", "euml", "igrave", "iacute", "icirc", "iuml", "eth",
+  "ntilde", "ograve", "oacute", "ocirc", "otilde", "ouml", "divide", "oslash", "ugrave", "uacute", "ucirc",
+  "uuml", "yacute", "thorn", "yuml",
+];
+
+fn entity(name: &str) -> Option<char> {
+  if let Some(num) = name.strip_prefix('#') {
+    let code = match num.strip_prefix(['x', 'X']) { Some(hex) => u32::from_str_radix(hex, 16).ok()?, None => num.parse().ok()? };
+    return char::from_u32(code).filter(|c| *c != '\0');
+  }
+  if let Some(i) = LATIN1.iter().position(|n| *n == name) { return char::from_u32(0xa0 + i as u32); }
+  Some(match name {
+    "amp" => '&', "lt" => '<', "gt" => '>', "quot" => '"', "apos" => '\'',
+    "ndash" => '–', "mdash" => '—', "hellip" => '…', "lsquo" => '‘', "rsquo" => '’', "ldquo" => '“', "rdquo" => '”',
+    "bull" => '•', "trade" => '™', "euro" => '€',
+    _ => return None,
+  })
+}
+
+struct Text { out: String, space: bool, breaks: usize, pre: usize }
+
+impl Text {
+  fn push(&mut self, c: char) {
+    if c == '\u{ad}' { return; }
+    if self.pre > 0 && c == '\n' { self.breaks += 1; return; }
+    if c.is_whitespace() { self.space = true; return; }
+    if !self.out.is_empty() {
+      if self.breaks > 0 { for _ in 0..self.breaks.min(2) { self.out.push('\n'); } }
+      else if self.space { self.out.push(' '); }
+    }
+    self.space = false; self.breaks = 0;
+    self.out.push(c);
+  }
+}
+
+/// Readable text of `html`: tags removed, entities decoded, `<script>`/`<style>` and the like
+/// dropped, and block elements turned into line breaks so words on either side don't run together.
+pub fn html_to_text(html: &str) -> String {
+  let mut t = Text { out: String::with_capacity(html.len() / 2), space: false, breaks: 0, pre: 0 };
+  let lower = html.to_ascii_lowercase();
+  let mut i = 0;
+  while i < html.len() {
+    let rest = &html[i..];
+    if rest.starts_with("<!--") {
+      i += rest.find("-->").map(|e| e + 3).unwrap_or(rest.len());
+      continue;
+    }
+    if rest.starts_with('<') {
+      let Some(end) = rest.find('>') else { rest.chars().for_each(|c| t.push(c)); break };
+      let tag = &lower[i + 1..i + end];
+      let closing = tag.starts_with('/');
+      let name: String = tag.trim_start_matches('/').chars().take_while(|c| c.is_ascii_alphanumeric()).collect();
+      // `<` not followed by a tag name (`a < b`, `<!DOCTYPE>`) is text or a declaration.
+      if name.is_empty() && !tag.starts_with('!') && !tag.starts_with('?') { t.push('<'); i += 1; continue; }
+      i += end + 1;
+      if !closing && !tag.ends_with('/') && SKIPPED.contains(&name.as_str()) {
+        let close = format!("</{}", name);
+        i = lower[i..].find(&close).map(|p| i + p).unwrap_or(html.len());
+        i += html[i..].find('>').map(|e| e + 1).unwrap_or(html.len() - i);
+        continue;
+      }
+      if name == "pre" { if closing { t.pre = t.pre.saturating_sub(1) } else { t.pre += 1 } }
+      if name == "br" { t.breaks += 1; }
+      else if PARAGRAPHS.contains(&name.as_str()) { t.breaks = t.breaks.max(2); }
+      else if LINES.contains(&name.as_str()) { t.breaks = t.breaks.max(1); }
+      else if CELLS.contains(&name.as_str()) { t.space = true; }
+      continue;
+    }
+    if let Some(after) = rest.strip_prefix('&') {
+      let decoded = after.find(';').filter(|&e| e <= 10).and_then(|e| entity(&after[..e]).map(|c| (c, e + 2)));
+      if let Some((c, len)) = decoded { t.push(c); i += len; continue; }
+    }
+    let c = rest.chars().next().unwrap_or_default();
+    t.push(c);
+    i += c.len_utf8();
+  }
+  t.out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keeps_only_readable_text() {
+    let html = r#"<head><title>t</title></head><p class="x">Hello <b>there</b>,<br>world</p><SCRIPT>var a = "<p>no</p>";</SCRIPT>
+      <style>p { color: red }</style><!-- a <b>comment</b> --><svg><text>icon</text></svg><div>after</div>"#;
+    assert_eq!(html_to_text(html), "Hello there,\nworld\n\nafter");
+  }
+
+  #[test]
+  fn decodes_entities() {
+    assert_eq!(html_to_text("caf&eacute; &amp; cr&egrave;me &lt;b&gt; &#x263A;&#9731; &hellip;&nbsp;done"), "café & crème <b> ☺☃ … done");
+    // Unknown, unterminated and NUL entities stay as written; soft hyphens disappear.
+    assert_eq!(html_to_text("&bogus; &amp &#0; co&shy;op"), "&bogus; &amp &#0; coop");
+  }
+
+  #[test]
+  fn keeps_words_in_separate_blocks_apart() {
+    assert_eq!(html_to_text("<ul><li>one</li><li>two</li></ul><table><tr><td>a</td><td>b</td></tr></table>"), "one\ntwo\n\na b");
+    assert_eq!(html_to_text("<pre>line 1\nline 2</pre>"), "line 1\nline 2");
+    assert_eq!(html_to_text("1 < 2 and <!DOCTYPE html>3 > 2"), "1 < 2 and 3 > 2");
+  }
+}
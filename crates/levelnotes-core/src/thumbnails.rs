@@ -0,0 +1,193 @@
+//! Downscaled copies of image assets, so lists of notes don't load every screenshot at full size.
+//! `Thumbnailer` decodes each image asset once, soon after it is clipped (or, for images clipped
+//! before thumbnails existed, when the API starts), and stores one copy per size in `SIZES` that
+//! is smaller than the image. `/file/*path?size=N` serves the smallest copy of at least `N` pixels.
+
+use std::{io::Cursor, sync::Arc, time::Duration};
+use chrono::Utc;
+use image::{codecs::{jpeg::JpegEncoder, webp::WebPEncoder}, imageops::FilterType, DynamicImage, ImageError};
+use rusqlite::{params, OptionalExtension};
+use tokio::sync::Notify;
+use crate::{assets, error::{ApiError, ApiResult}, store::NoteStore};
+
+/// Longest side of each thumbnail, in pixels.
+pub const SIZES: [u32; 2] = [320, 1024];
+const JPEG_QUALITY: u8 = 82;
+// Images decoded per pass; the connection is free between them.
+const BATCH: usize = 16;
+// How often the worker looks for images without a `wake`, e.g. ones an older build clipped meanwhile.
+const IDLE_POLL: Duration = Duration::from_secs(10 * 60);
+// The formats `image` is built to decode here.
+const DECODABLE: &str = "'image/png', 'image/jpeg', 'image/webp', 'image/gif'";
+
+/// A stored thumbnail of asset `asset_hash`, `size` pixels on its longer side at most.
+#[derive(Debug, Clone)]
+pub struct Thumbnail { pub asset_hash: String, pub size: u32, pub mime: String, pub path: String }
+
+/// One size of an image, encoded: lossless WebP if any of its pixels is see-through, JPEG otherwise.
+pub struct Rendered { pub size: u32, pub mime: &'static str, pub bytes: Vec<u8>, pub width: u32, pub height: u32 }
+
+/// Decodes `bytes` and renders each of `SIZES` smaller than the image. Returns the image's own
+/// width and height too.
+pub fn render(bytes: &[u8]) -> Result<((u32, u32), Vec<Rendered>), ImageError> {
+  let img = image::load_from_memory(bytes)?;
+  let (w, h) = (img.width(), img.height());
+  let mut rendered = Vec::new();
+  for size in SIZES.into_iter().filter(|&s| w.max(h) > s) {
+    let small = img.resize(size, size, FilterType::CatmullRom);
+    let mut out = Cursor::new(Vec::new());
+    // Canvas PNGs are RGBA even when fully opaque, so it's the pixels that decide, not the colour type.
+    let rgba = small.color().has_alpha().then(|| small.to_rgba8()).filter(|px| px.pixels().any(|p| p[3] < 255));
+    let mime = if let Some(rgba) = rgba {
+      DynamicImage::ImageRgba8(rgba).write_with_encoder(WebPEncoder::new_lossless(&mut out))?;
+      "image/webp"
+    } else {
+      DynamicImage::ImageRgb8(small.to_rgb8()).write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))?;
+      "image/jpeg"
+    };
+    rendered.push(Rendered { size, mime, bytes: out.into_inner(), width: small.width(), height: small.height() });
+  }
+  Ok(((w, h), rendered))
+}
+
+/// Makes thumbnails for image assets that have none yet. Cheap to clone; `serve` runs one per API.
+#[derive(Clone)]
+pub struct Thumbnailer { store: NoteStore, wake: Arc<Notify> }
+
+impl Thumbnailer {
+  pub fn new(store: NoteStore) -> Self { Thumbnailer { store, wake: Arc::new(Notify::new()) } }
+
+  /// Asks the worker to look for new images now rather than at its next poll.
+  pub fn wake(&self) { self.wake.notify_one(); }
+
+  /// Makes the thumbnails of up to `BATCH` images that have none yet. Returns how many it decoded.
+  pub fn run_pending(&self) -> ApiResult<usize> {
+    let pending: Vec<(String, String)> = {
+      let db = self.store.conn()?;
+      let mut stmt = db.prepare(&format!(
+        "SELECT hash, path FROM assets WHERE mime IN ({DECODABLE}) AND width IS NULL AND thumbnail_error IS NULL ORDER BY created_at LIMIT ?1"))?;
+      let rows = stmt.query_map(params![BATCH], |r| Ok((r.get(0)?, r.get(1)?)))?.collect::<rusqlite::Result<Vec<_>>>()?;
+      rows
+    };
+    for (hash, path) in &pending {
+      // Decoded and written without the lock; only recording them needs it.
+      let outcome = self.store.assets().read(path).and_then(|(bytes, _)| render(&bytes).map_err(|e| ApiError::Validation(e.to_string())));
+      let mut written = Vec::new();
+      let outcome = outcome.and_then(|(dims, rendered)| {
+        for r in &rendered {
+          let rel = assets::stored_path(hash, &format!("{}.{}", hash, r.size), r.mime);
+          self.store.assets().write(&rel, &r.bytes)?;
+          written.push(rel);
+        }
+        Ok((dims, rendered))
+      });
+      let now = Utc::now().to_rfc3339();
+      let mut db = self.store.conn()?;
+      let tx = db.transaction()?;
+      // Purged while it was being decoded: its thumbnails go too.
+      let exists = tx.query_row("SELECT 1 FROM assets WHERE hash=?1", params![hash], |_| Ok(())).optional()?.is_some();
+      match outcome {
+        Ok(((w, h), rendered)) if exists => {
+          tx.execute("UPDATE assets SET width=?1, height=?2, thumbnail_error=NULL WHERE hash=?3", params![w, h, hash])?;
+          for (r, rel) in rendered.iter().zip(&written) {
+            tx.execute(
+              "INSERT OR REPLACE INTO asset_thumbnails (asset_hash, size, mime, path, width, height, created_at) VALUES (?1,?2,?3,?4,?5,?6,?7)",
+              params![hash, r.size, r.mime, rel, r.width, r.height, now])?;
+          }
+        }
+        Ok(_) => { for rel in &written { self.store.assets().remove(rel)?; } }
+        Err(e) => {
+          for rel in &written { self.store.assets().remove(rel)?; }
+          if exists { tx.execute("UPDATE assets SET thumbnail_error=?1 WHERE hash=?2", params![e.to_string(), hash])?; }
+          eprintln!("LevelNotes IMG  no thumbnails for {}: {}", path, e);
+        }
+      }
+      tx.commit()?;
+    }
+    Ok(pending.len())
+  }
+
+  /// Works through new images until the task is dropped, sleeping until a `wake` or the idle poll.
+  pub async fn run(self) {
+    loop {
+      let this = self.clone();
+      let ran = match tokio::task::spawn_blocking(move || this.run_pending()).await {
+        Ok(Ok(n)) => n,
+        Ok(Err(e)) => { eprintln!("LevelNotes IMG  {}", e); 0 }
+        Err(e) => { eprintln!("LevelNotes IMG  worker panicked: {}", e); 0 }
+      };
+      // A full batch means there are probably more waiting.
+      if ran >= BATCH { continue; }
+      tokio::select! { _ = self.wake.notified() => {}, _ = tokio::time::sleep(IDLE_POLL) => {} }
+    }
+  }
+}
+
+impl NoteStore {
+  /// The smallest thumbnail of the image stored at `path` that is at least `size` pixels on its
+  /// longer side. `None` if the image itself is the best fit, or has no thumbnails yet.
+  pub fn thumbnail(&self, path: &str, size: u32) -> ApiResult<Option<Thumbnail>> {
+    Ok(self.conn()?.query_row(
+      "SELECT t.asset_hash, t.size, t.mime, t.path FROM asset_thumbnails t JOIN assets a ON a.hash = t.asset_hash
+       WHERE a.path=?1 AND t.size >= ?2 ORDER BY t.size LIMIT 1",
+      params![path, size], |r| Ok(Thumbnail { asset_hash: r.get(0)?, size: r.get(1)?, mime: r.get(2)?, path: r.get(3)? })).optional()?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use base64::Engine as _;
+  use image::{ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+  use crate::store::tests::{clip, png_data_url, temp_store};
+
+  fn png(img: DynamicImage) -> Vec<u8> {
+    let mut out = Cursor::new(Vec::new());
+    img.write_to(&mut out, ImageFormat::Png).unwrap();
+    out.into_inner()
+  }
+
+  #[test]
+  fn renders_each_smaller_size() {
+    let ((w, h), rendered) = render(&png(RgbImage::from_pixel(1200, 600, Rgb([200, 10, 10])).into())).unwrap();
+    assert_eq!((w, h), (1200, 600));
+    let sizes: Vec<_> = rendered.iter().map(|r| (r.size, r.mime, r.width, r.height)).collect();
+    assert_eq!(sizes, [(320, "image/jpeg", 320, 160), (1024, "image/jpeg", 1024, 512)]);
+    assert!(rendered.iter().all(|r| assets::detect_mime(&r.bytes) == Some("image/jpeg")));
+
+    // Transparency survives as WebP; an image already smaller than a size isn't scaled up to it.
+    let (_, rendered) = render(&png(RgbaImage::from_pixel(600, 400, Rgba([0, 0, 0, 0])).into())).unwrap();
+    assert_eq!(rendered.iter().map(|r| (r.size, r.mime, r.width, r.height)).collect::<Vec<_>>(), [(320, "image/webp", 320, 213)]);
+    assert!(render(&png(RgbImage::new(300, 200).into())).unwrap().1.is_empty());
+    // An RGBA image with nothing see-through, as a canvas gives, is still a JPEG.
+    let (_, rendered) = render(&png(RgbaImage::from_pixel(600, 400, Rgba([30, 60, 90, 255])).into())).unwrap();
+    assert_eq!(rendered.iter().map(|r| (r.size, r.mime)).collect::<Vec<_>>(), [(320, "image/jpeg")]);
+    assert_eq!(assets::detect_mime(&rendered[0].bytes), Some("image/jpeg"));
+    assert!(render(b"\x89PNG\r\n\x1a\nbroken").is_err());
+  }
+
+  #[test]
+  fn serves_the_smallest_thumbnail_big_enough() {
+    let (dir, store) = temp_store();
+    let screenshot = format!("data:image/png;base64,{}", base64::engine::general_purpose::STANDARD.encode(png(RgbImage::new(1100, 600).into())));
+    let id = store.create(&clip(serde_json::json!({ "selection": { "text": "shot" }, "media": { "screenshotDataUrl": screenshot } }))).unwrap();
+    let broken = store.create(&clip(serde_json::json!({ "selection": { "text": "broken" }, "media": { "screenshotDataUrl": png_data_url("broken") } }))).unwrap();
+    let thumbnailer = Thumbnailer::new(store.clone());
+    assert_eq!(thumbnailer.run_pending().unwrap(), 2);
+    // Each image is decoded once, whether that worked or not.
+    assert_eq!(thumbnailer.run_pending().unwrap(), 0);
+
+    let path = store.get(&id).unwrap().assets[0].asset.path.clone();
+    let small = store.thumbnail(&path, 200).unwrap().unwrap();
+    assert_eq!((small.size, small.mime.as_str()), (320, "image/jpeg"));
+    assert!(dir.join(&small.path).is_file());
+    assert_eq!(store.thumbnail(&path, 321).unwrap().unwrap().size, 1024);
+    assert!(store.thumbnail(&path, 1025).unwrap().is_none());
+
+    let broken = store.get(&broken).unwrap().assets[0].asset.path.clone();
+    assert!(store.thumbnail(&broken, 1).unwrap().is_none());
+    let error: Option<String> = store.conn().unwrap().query_row("SELECT thumbnail_error FROM assets WHERE path=?1", params![broken], |r| r.get(0)).unwrap();
+    assert!(error.is_some());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,172 @@
+//! Deleting a note only stamps `deleted_at`. Trashed notes drop out of listings and the search
+//! index but can still be opened and restored, until a purge removes them with the assets only they used.
+
+use std::time::Duration;
+use chrono::Utc;
+use rusqlite::{params, params_from_iter, types::Value};
+use crate::{assets, error::{ApiError, ApiResult}, model::*, store::NoteStore};
+
+// How often `purge_expired` looks for notes past the retention period.
+const PURGE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+impl NoteStore {
+  /// One page of trashed notes, most recently deleted first by default.
+  pub fn trash(&self, mut query: ListQuery) -> ApiResult<NotePage> {
+    query.trashed = true;
+    self.list(&query)
+  }
+
+  /// Takes a note back out of the trash.
+  pub fn restore(&self, id: &str) -> ApiResult<usize> {
+    let changed = self.conn()?.execute("UPDATE notes SET deleted_at=NULL WHERE id=?1 AND deleted_at IS NOT NULL", params![id])?;
+    if changed == 0 { return Err(ApiError::NotFound(format!("note {} is not in the trash", id))); }
+    Ok(changed)
+  }
+
+  /// Removes trashed notes for good, with their revisions, their legacy `previews/{id}.png` and any
+  /// asset no other note uses:
+  /// the notes in `ids`, or the whole trash, limited to those deleted `older_than_days` or more ago.
+  pub fn purge(&self, ids: Option<&[String]>, older_than_days: Option<u32>) -> ApiResult<PurgeResponse> {
+    let mut wheres = vec!["deleted_at IS NOT NULL".to_string()];
+    let mut args: Vec<Value> = Vec::new();
+    if let Some(days) = older_than_days {
+      wheres.push("deleted_at <= ?".into());
+      args.push(Value::Text((Utc::now() - chrono::Duration::days(days.into())).to_rfc3339()));
+    }
+    if let Some(ids) = ids {
+      wheres.push(format!("id IN ({})", vec!["?"; ids.len()].join(",")));
+      args.extend(ids.iter().cloned().map(Value::Text));
+    }
+    let mut db = self.conn()?;
+    let tx = db.transaction()?;
+    let doomed: Vec<String> = {
+      let mut stmt = tx.prepare(&format!("SELECT id FROM notes WHERE {}", wheres.join(" AND ")))?;
+      let rows = stmt.query_map(params_from_iter(args), |r| r.get(0))?.collect::<rusqlite::Result<Vec<_>>>()?;
+      rows
+    };
+    let mut released = Vec::new();
+    for id in &doomed {
+      let mut stmt = tx.prepare_cached("SELECT asset_hash FROM note_assets WHERE note_id=?1")?;
+      released.extend(stmt.query_map(params![id], |r| r.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?);
+      tx.execute("DELETE FROM note_revisions WHERE note_id=?1", params![id])?;
+      tx.execute("DELETE FROM note_assets WHERE note_id=?1", params![id])?;
+      tx.execute("DELETE FROM document_clips WHERE note_id=?1", params![id])?;
+      tx.execute("DELETE FROM notes WHERE id=?1", params![id])?;
+    }
+    let mut files = assets::drop_orphans(&tx, &released)?;
+    // A legacy screenshot identical to an older note's was never registered: that note's file
+    // stands in for it. Its own copy goes with the note unless something still refers to it.
+    for id in &doomed {
+      let legacy = format!("previews/{}.png", id);
+      let used: bool = tx.query_row("SELECT EXISTS(SELECT 1 FROM assets WHERE path=?1) OR EXISTS(SELECT 1 FROM notes WHERE preview_path=?1)",
+        params![legacy], |r| r.get(0))?;
+      if !used && self.assets().resolve(&legacy)?.is_file() { files.push(legacy); }
+    }
+    tx.commit()?;
+    // Still locked, so no clip can pick one of these files up again before it is gone. The rows are
+    // gone by now; a file that won't go is logged and left behind.
+    let mut files_removed = 0;
+    for rel in files {
+      match self.assets().remove(&rel) {
+        Ok(()) => files_removed += 1,
+        Err(e) => eprintln!("LevelNotes DB  could not remove {}: {}", rel, e),
+      }
+    }
+    drop(db);
+    let purged = doomed.len();
+    Ok(PurgeResponse { ok: true, purged, files_removed })
+  }
+}
+
+/// Purges notes that have been in the trash for `retention_days`, now and every few hours after.
+/// Runs until the task is aborted; a retention of 0 days keeps trashed notes until purged by hand.
+pub async fn purge_expired(store: NoteStore, retention_days: u32) {
+  if retention_days == 0 { return; }
+  loop {
+    let s = store.clone();
+    match tokio::task::spawn_blocking(move || s.purge(None, Some(retention_days))).await {
+      Ok(Ok(r)) if r.purged > 0 => println!("LevelNotes DB  purged {} notes from the trash", r.purged),
+      Ok(Ok(_)) => {}
+      Ok(Err(e)) => eprintln!("LevelNotes DB  trash purge failed: {}", e),
+      Err(e) => eprintln!("LevelNotes DB  trash purge failed: {}", e),
+    }
+    tokio::time::sleep(PURGE_INTERVAL).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+  use super::*;
+  use crate::{conditional::Precondition, store::tests::{clip, png_data_url, temp_store}};
+
+  fn count(store: &NoteStore, sql: &str, id: &str) -> i64 { store.conn().unwrap().query_row(sql, params![id], |r| r.get(0)).unwrap() }
+
+  #[test]
+  fn deleting_twice_is_a_conflict() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(json!({ "selection": { "text": "note" } }))).unwrap();
+    assert_eq!(store.delete(&id).unwrap(), 1);
+    assert!(matches!(store.delete(&id), Err(ApiError::Conflict(_))));
+    assert!(matches!(store.delete("missing"), Err(ApiError::NotFound(_))));
+    assert_eq!(store.restore(&id).unwrap(), 1);
+    assert!(matches!(store.restore(&id), Err(ApiError::NotFound(_))));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn trashed_notes_cannot_be_exported() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(json!({ "selection": { "text": "note" } }))).unwrap();
+    store.delete(&id).unwrap();
+    assert!(matches!(store.note_for_export(&id), Err(ApiError::Conflict(_))));
+    assert!(matches!(store.note_for_export("missing"), Err(ApiError::NotFound(_))));
+    store.restore(&id).unwrap();
+    assert_eq!(store.note_for_export(&id).unwrap().id, id);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn purge_removes_the_note_everywhere() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(json!({
+      "selection": { "text": "purgeable words", "html": "<p>purgeable words</p>" },
+      "media": { "screenshotDataUrl": png_data_url("only mine") }, "ops": { "tags": ["gone"] },
+    }))).unwrap();
+    let shared = png_data_url("shared");
+    store.append(&id, &clip(json!({ "selection": { "text": "more" }, "media": { "screenshotDataUrl": shared } })), &Precondition::default()).unwrap();
+    let other = store.create(&clip(json!({ "selection": { "text": "other" }, "media": { "screenshotDataUrl": shared } }))).unwrap();
+    // A duplicate screenshot from before the asset store, which the migration left unregistered.
+    std::fs::create_dir_all(dir.join("previews")).unwrap();
+    std::fs::write(dir.join(format!("previews/{}.png", id)), b"\x89PNG\r\n\x1a\nold").unwrap();
+    let paths: Vec<String> = store.get(&id).unwrap().assets.into_iter().map(|a| a.asset.path).collect();
+
+    store.delete(&id).unwrap();
+    // Only trashed notes are purged.
+    assert_eq!(store.purge(Some(std::slice::from_ref(&other)), None).unwrap().purged, 0);
+    let purged = store.purge(Some(std::slice::from_ref(&id)), None).unwrap();
+    assert_eq!((purged.purged, purged.files_removed), (1, 2));
+
+    assert_eq!(count(&store, "SELECT COUNT(*) FROM notes WHERE id=?1", &id), 0);
+    assert_eq!(count(&store, "SELECT COUNT(*) FROM note_revisions WHERE note_id=?1", &id), 0);
+    assert_eq!(count(&store, "SELECT COUNT(*) FROM note_assets WHERE note_id=?1", &id), 0);
+    assert_eq!(count(&store, "SELECT COUNT(*) FROM notes_fts WHERE notes_fts MATCH ?1", "purgeable"), 0);
+    assert!(!dir.join(&paths[0]).exists());
+    assert!(!dir.join(format!("previews/{}.png", id)).exists());
+    // The screenshot the other note has too stays.
+    assert!(dir.join(&paths[1]).is_file());
+    assert_eq!(store.get(&other).unwrap().assets[0].asset.path, paths[1]);
+    assert!(matches!(store.get(&id), Err(ApiError::NotFound(_))));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn purge_keeps_notes_deleted_more_recently_than_asked() {
+    let (dir, store) = temp_store();
+    let id = store.create(&clip(json!({ "selection": { "text": "note" } }))).unwrap();
+    store.delete(&id).unwrap();
+    assert_eq!(store.purge(None, Some(1)).unwrap().purged, 0);
+    assert_eq!(store.purge(None, Some(0)).unwrap().purged, 1);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
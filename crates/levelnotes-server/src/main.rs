@@ -0,0 +1,129 @@
+//! Runs the LevelNotes HTTP API without the desktop window, e.g. for browser capture on a
+//! machine without the GUI or as a fixture for integration tests.
+
+use std::{net::IpAddr, path::PathBuf};
+use clap::{Parser, Subcommand};
+use levelnotes_core::{resolve, serve, AppState, Auth, Config, ConfigLayer, Enricher, NoteStore, Thumbnailer, Tokenizer};
+use tokio::net::TcpListener;
+
+/// Flags override `LEVELNOTES_*` environment variables, which override `levelnotes.toml`.
+#[derive(Parser)]
+#[command(name = "levelnotes-server", version, about = "Headless LevelNotes HTTP API")]
+struct Args {
+  /// Address to bind. Anything other than loopback exposes your notes to the network.
+  #[arg(long)]
+  bind: Option<IpAddr>,
+  /// Port to listen on (default 3030). If it is busy the next free one is used; 0 lets the OS pick.
+  #[arg(long)]
+  port: Option<u16>,
+  /// Directory holding levelnotes.db and clip assets (defaults to the desktop app's).
+  #[arg(long)]
+  data_dir: Option<PathBuf>,
+  /// Read settings from this file instead of the platform `levelnotes.toml`.
+  #[arg(long)]
+  config: Option<PathBuf>,
+  /// Search tokenizer: unicode61, porter (English stemming) or trigram (substrings). Changing it rebuilds the index.
+  #[arg(long)]
+  tokenizer: Option<Tokenizer>,
+  /// Whether search ignores accents (true or false).
+  #[arg(long)]
+  remove_diacritics: Option<bool>,
+  /// Directory of offline source metadata: Crossref JSON in `crossref/`, PDFs in `pdf/` (default: data dir's `metadata`).
+  #[arg(long)]
+  metadata_dir: Option<PathBuf>,
+  /// JSON file of canned DOI/ISBN answers, tried before anything else; for tests.
+  #[arg(long)]
+  metadata_fixtures: Option<PathBuf>,
+  /// Days deleted notes stay in the trash before they are purged (default 30; 0 keeps them until purged by hand).
+  #[arg(long)]
+  trash_retention_days: Option<u32>,
+  /// Largest file POST /assets accepts, in MiB (default 100).
+  #[arg(long)]
+  max_upload_mb: Option<u64>,
+  #[command(subcommand)]
+  command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Rebuild the search index from the stored notes, then exit.
+  Reindex,
+  /// Run the source metadata lookups that are due, then exit.
+  Resolve,
+  /// Make thumbnails for every image that has none yet, then exit.
+  Thumbnails,
+}
+
+async fn shutdown_signal() {
+  let ctrl_c = async { let _ = tokio::signal::ctrl_c().await; };
+  #[cfg(unix)]
+  let term = async {
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+      Ok(mut s) => { s.recv().await; }
+      Err(_) => std::future::pending::<()>().await,
+    }
+  };
+  #[cfg(not(unix))]
+  let term = std::future::pending::<()>();
+  tokio::select! { _ = ctrl_c => {}, _ = term => {} }
+  println!("LevelNotes HTTP shutting down");
+}
+
+fn fail(msg: String) -> ! { eprintln!("{}", msg); std::process::exit(1) }
+
+#[tokio::main]
+async fn main() {
+  let args = Args::parse();
+  let cli = ConfigLayer {
+    data_dir: args.data_dir, bind: args.bind, port: args.port,
+    tokenizer: args.tokenizer, remove_diacritics: args.remove_diacritics,
+    metadata_dir: args.metadata_dir, metadata_fixtures: args.metadata_fixtures,
+    trash_retention_days: args.trash_retention_days, max_upload_mb: args.max_upload_mb,
+  };
+  let mut config = Config::load(cli, args.config).unwrap_or_else(|e| fail(format!("LevelNotes {}", e)));
+  println!("LevelNotes DB  {}", config.db_path.display());
+  config.adopt_legacy_data().unwrap_or_else(|e| fail(format!("LevelNotes DB  {}", e)));
+  let store = NoteStore::open(&config.db_path)
+    .unwrap_or_else(|e| fail(format!("LevelNotes DB  cannot open {}: {}", config.db_path.display(), e)));
+  match store.use_tokenizer(config.tokenizer, config.remove_diacritics) {
+    Ok(true) => println!("LevelNotes DB  rebuilt the search index for tokenizer {:?}", config.tokenizer),
+    Ok(false) => {}
+    Err(e) => fail(format!("LevelNotes DB  cannot set up the search index: {}", e)),
+  }
+  let resolvers = resolve::resolvers_for(&config).unwrap_or_else(|e| fail(format!("LevelNotes META {}", e)));
+  match args.command {
+    Some(Command::Reindex) => {
+      let n = store.reindex().unwrap_or_else(|e| fail(format!("LevelNotes DB  reindex failed: {}", e)));
+      println!("LevelNotes DB  reindexed {} notes", n);
+      return;
+    }
+    Some(Command::Resolve) => {
+      let n = Enricher::new(store, resolvers).run_due().unwrap_or_else(|e| fail(format!("LevelNotes META {}", e)));
+      println!("LevelNotes META ran {} lookups", n);
+      return;
+    }
+    Some(Command::Thumbnails) => {
+      let thumbnailer = Thumbnailer::new(store);
+      let mut total = 0;
+      loop {
+        match thumbnailer.run_pending().unwrap_or_else(|e| fail(format!("LevelNotes IMG  {}", e))) { 0 => break, n => total += n }
+      }
+      println!("LevelNotes IMG  decoded {} images", total);
+      return;
+    }
+    None => {}
+  }
+  let paired = store.paired_clients().unwrap_or_else(|e| fail(format!("LevelNotes DB  {}", e)));
+  let auth = Auth::load_or_create(&config.data_dir, paired.into_iter().map(|c| c.origin))
+    .unwrap_or_else(|e| fail(format!("LevelNotes cannot read or create the API token: {}", e)));
+  println!("LevelNotes HTTP API token is in {}", config.data_dir.join("auth_token").display());
+
+  let listener = config.bind_listener().and_then(TcpListener::from_std)
+    .unwrap_or_else(|e| fail(format!("LevelNotes HTTP cannot bind {}:{}: {}", config.bind, config.requested_port, e)));
+  println!("LevelNotes HTTP listening on {}", config.base_url());
+  if let Err(e) = config.advertise() { eprintln!("LevelNotes HTTP cannot write server.json: {}", e); }
+
+  let result = serve(listener, AppState::new(store, config.clone(), auth, resolvers), shutdown_signal()).await;
+  config.withdraw_advert();
+  if let Err(e) = result { fail(format!("LevelNotes HTTP {}", e)); }
+}
@@ -11,7 +11,10 @@ export interface ClipPayload {
     kind: SourceKind;
     url?: string;
     doi?: string;
+    // Kept as sent; title, author/authors, siteName, canonicalUrl and doi also fill the source record.
     metadata?: Record<string, unknown>;
+    // The id POST /documents returned for the PDF; ops.page (1-based) and ops.highlights place the clip in it.
+    documentId?: string;
   };
   selection?: {
     text?: string;
@@ -23,8 +26,17 @@ export interface ClipPayload {
       textQuote?: TextQuoteSelector;
     };
   };
-  media?: { screenshotDataUrl?: string };
-  ops?: { summarize?: boolean; tags?: string[] };
+  // Attachments are kept with the note after the screenshot, appended clips' ones included. Each file
+  // is inline as a data URL, or the hash POST /assets returned for it as an asset id.
+  media?: {
+    screenshotDataUrl?: string;
+    screenshotAssetId?: string;
+    attachments?: { dataUrl?: string; assetId?: string; name?: string }[];
+  };
+  // With source.documentId, each clip keeps its page and highlight rectangles (PDF units from the page's top-left).
+  ops?: { summarize?: boolean; tags?: string[]; page?: number; highlights?: { x: number; y: number; w: number; h: number }[] };
+  // /append/:id only: the note version the clip is meant for; a note that has changed since answers 409.
+  expectedVersion?: number;
 }
 
 export interface NoteRecord {
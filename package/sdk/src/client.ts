@@ -1,9 +1,32 @@
 ﻿import type { ClipPayload } from "@levelnotes/core";
 
-export async function sendClipToDesktop(payload: ClipPayload) {
-  const res = await fetch("http://127.0.0.1:3030/clip", {
+// The desktop API listens on 3030, or on one of the next 10 ports when that one is busy.
+const DEFAULT_PORT = 3030;
+const PORT_FALLBACK_TRIES = 10;
+
+async function isLevelNotes(base: string): Promise<boolean> {
+  try {
+    const res = await fetch(`${base}/health`, { signal: AbortSignal.timeout(1000) });
+    return res.ok && (await res.json()).service === "levelnotes";
+  } catch {
+    return false;
+  }
+}
+
+// The base URL of the desktop API: the first port of its range where it answers.
+export async function findDesktop(): Promise<string> {
+  const bases = Array.from({ length: PORT_FALLBACK_TRIES + 1 }, (_, i) => `http://127.0.0.1:${DEFAULT_PORT + i}`);
+  const found = bases[(await Promise.all(bases.map(isLevelNotes))).indexOf(true)];
+  if (!found) throw new Error(`LevelNotes is not running (looked on ports ${DEFAULT_PORT}-${DEFAULT_PORT + PORT_FALLBACK_TRIES})`);
+  return found;
+}
+
+// `token` is the per-install API token (see `auth_token` in the LevelNotes data directory, or pair via /pair/complete).
+// `baseUrl` skips the lookup, e.g. with the `url` from `server.json` in the data directory.
+export async function sendClipToDesktop(payload: ClipPayload, token: string, baseUrl?: string) {
+  const res = await fetch(`${baseUrl ?? await findDesktop()}/clip`, {
     method: "POST",
-    headers: { "Content-Type": "application/json" },
+    headers: { "Content-Type": "application/json", "Authorization": `Bearer ${token}` },
     body: JSON.stringify(payload)
   });
   if (!res.ok) throw new Error(`Desktop refused: ${res.status}`);